@@ -0,0 +1,91 @@
+use std::ops::Range;
+use crate::ray::Ray;
+use crate::vector::Vec3;
+
+/// Axis aligned bounding box.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(a: Vec3, b: Vec3) -> Self {
+        Self {
+            min: Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z())),
+            max: Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z())),
+        }
+    }
+
+    /// Smallest box containing all the points. Flat boxes are padded a little so rays
+    /// can still hit them.
+    pub fn from_points(points: &[Vec3]) -> Self {
+        let mut min = [f64::INFINITY; 3];
+        let mut max = [f64::NEG_INFINITY; 3];
+        for point in points {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(point.axis(axis));
+                max[axis] = max[axis].max(point.axis(axis));
+            }
+        }
+        for axis in 0..3 {
+            if max[axis] - min[axis] < 1e-4 {
+                min[axis] -= 1e-4;
+                max[axis] += 1e-4;
+            }
+        }
+        Self {
+            min: Vec3::new(min[0], min[1], min[2]),
+            max: Vec3::new(max[0], max[1], max[2]),
+        }
+    }
+
+    /// Smallest box containing both boxes.
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self::from_points(&[self.min, self.max, other.min, other.max])
+    }
+
+    pub fn min(&self) -> Vec3 { self.min }
+    pub fn max(&self) -> Vec3 { self.max }
+    pub fn size(&self) -> Vec3 { self.max - self.min }
+    pub fn center(&self) -> Vec3 { 0.5 * (self.min + self.max) }
+
+    /// Position relative to the box, (0, 0, 0) at min and (1, 1, 1) at max.
+    pub fn relative(&self, point: Vec3) -> Vec3 {
+        let size = self.size();
+        let local = point - self.min;
+        Vec3::new(local.x() / size.x(), local.y() / size.y(), local.z() / size.z())
+    }
+
+    /// The range of t where the ray is inside the box, clipped to `t_range`, and the
+    /// axis of the face where the ray enters and leaves.
+    pub fn hit_range(&self, ray: Ray, t_range: Range<f64>) -> Option<(f64, usize, f64, usize)> {
+        // Slab method
+        let mut t_min = t_range.start;
+        let mut t_max = t_range.end;
+        let mut enter_axis = 0;
+        let mut exit_axis = 0;
+        let origin = ray.origin();
+        let dir = ray.dir();
+        for axis in 0..3 {
+            let inv_dir = 1.0 / dir.axis(axis);
+            let mut t0 = (self.min.axis(axis) - origin.axis(axis)) * inv_dir;
+            let mut t1 = (self.max.axis(axis) - origin.axis(axis)) * inv_dir;
+            if inv_dir < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            if t0 > t_min {
+                t_min = t0;
+                enter_axis = axis;
+            }
+            if t1 < t_max {
+                t_max = t1;
+                exit_axis = axis;
+            }
+            if t_max <= t_min {
+                return None;
+            }
+        }
+        Some((t_min, enter_axis, t_max, exit_axis))
+    }
+}
@@ -0,0 +1,660 @@
+use std::f64::consts::PI;
+use crate::camera::Camera;
+use crate::light::Light;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::renderer::power_heuristic;
+use crate::scene::Scene;
+use crate::shapes::{HitResult, Hittable};
+use crate::util::{random, AliasTable};
+use crate::vector::Vec3;
+
+/// Something lights subpaths can start from.
+enum Source<'a> {
+    // Point and spot lights
+    Light(&'a Light),
+    // Slot of an object giving off light
+    Emitter(usize),
+}
+
+enum Kind<'a> {
+    Camera,
+    Light(&'a Light),
+    // Where a light subpath leaves an object giving off light
+    Emitter(HitResult<'a>),
+    Surface(HitResult<'a>),
+}
+
+/// A point along a subpath from the camera or from a light.
+struct Vertex<'a> {
+    kind: Kind<'a>,
+    point: Vec3,
+    // Of surfaces, facing where the subpath came from
+    normal: Option<Vec3>,
+    // What the subpath carries up to here, divided by the chance of picking it
+    beta: Vec3,
+    // Scatters into single directions only, so it can't be connected to
+    delta: bool,
+    // Density per area of this vertex being picked by its own subpath, and by a
+    // subpath from the other end
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl<'a> Vertex<'a> {
+    fn new(kind: Kind<'a>, point: Vec3, normal: Option<Vec3>, beta: Vec3, pdf_fwd: f64) -> Self {
+        Self { kind, point, normal, beta, delta: false, pdf_fwd, pdf_rev: 0.0 }
+    }
+
+    fn surface(hit: HitResult<'a>, beta: Vec3) -> Self {
+        let (point, normal) = (hit.hit_point(), hit.normal());
+        Self::new(Kind::Surface(hit), point, Some(normal), beta, 0.0)
+    }
+
+    fn hit(&self) -> Option<&HitResult<'a>> {
+        match &self.kind {
+            Kind::Emitter(hit) | Kind::Surface(hit) => Some(hit),
+            _ => None,
+        }
+    }
+
+    fn is_delta_light(&self) -> bool {
+        matches!(self.kind, Kind::Light(_))
+    }
+
+    /// Light given off when the vertex is on an object giving off light.
+    fn emitted(&self) -> Vec3 {
+        match &self.kind {
+            Kind::Surface(hit) => hit.material().emitted(hit),
+            _ => Vec3::zero(),
+        }
+    }
+
+    /// Normalized direction to another vertex, and the squared distance to it.
+    fn towards(&self, other: &Vertex) -> (Vec3, f64) {
+        let offset = other.point - self.point;
+        (offset.normalize(), offset.norm_sq())
+    }
+
+    fn cos(&self, dir: Vec3) -> f64 {
+        self.normal.map_or(1.0, |normal| normal.dot(dir).abs())
+    }
+
+    /// How much of what arrives from `prev` goes on along `dir`. For lights that's
+    /// the light sent out that way, and for the camera how much it sees of it.
+    fn f(&self, camera: &Camera, prev: Option<&Vertex>, dir: Vec3) -> Vec3 {
+        match &self.kind {
+            Kind::Camera => {
+                let importance = camera.pdf(dir);
+                Vec3::new(importance, importance, importance)
+            }
+            Kind::Light(_) | Kind::Emitter(_) => self.light_sent(dir),
+            Kind::Surface(hit) => match prev {
+                Some(prev) => hit.material().eval(prev.towards(self).0, hit.normal(), dir),
+                None => Vec3::zero(),
+            },
+        }
+    }
+
+    /// Light sent out along `dir` by the light the subpath starts on.
+    fn light_sent(&self, dir: Vec3) -> Vec3 {
+        match &self.kind {
+            // Irradiance one unit away is the intensity
+            Kind::Light(light) => light.sample(self.point + dir).map_or(Vec3::zero(), |sample| sample.irradiance),
+            Kind::Emitter(hit) => emitted_towards(hit, dir),
+            _ => Vec3::zero(),
+        }
+    }
+
+    /// Density per area of `next` being picked by a subpath at this vertex, which
+    /// came from `prev`.
+    fn pdf(&self, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let (dir, distance_sq) = self.towards(next);
+        let pdf = match &self.kind {
+            Kind::Camera => camera.pdf(dir),
+            Kind::Light(_) | Kind::Emitter(_) => self.pdf_light_dir(dir),
+            Kind::Surface(hit) => match prev {
+                Some(prev) => hit.material().pdf(prev.towards(self).0, hit.normal(), dir),
+                None => 0.0,
+            },
+        };
+        pdf * next.cos(dir) / distance_sq
+    }
+
+    /// Density per area of `next` being picked by a light subpath starting here.
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let (dir, distance_sq) = self.towards(next);
+        self.pdf_light_dir(dir) * next.cos(dir) / distance_sq
+    }
+
+    /// Density per solid angle of light leaving the light this vertex is on along
+    /// `dir`.
+    fn pdf_light_dir(&self, dir: Vec3) -> f64 {
+        match &self.kind {
+            Kind::Light(light) => light_dir_pdf(light, dir),
+            Kind::Emitter(hit) | Kind::Surface(hit) => emission_pdf(hit, dir),
+            Kind::Camera => 0.0,
+        }
+    }
+}
+
+/// Light an object gives off along `dir`, from either side of it.
+fn emitted_towards(hit: &HitResult, dir: Vec3) -> Vec3 {
+    let material = hit.material();
+    let front = (dir.dot(hit.normal()) >= 0.0) == hit.front_face();
+    if !front && matches!(material, Material::Emissive { one_sided: true, .. }) {
+        return Vec3::zero();
+    }
+    material.emitted(hit)
+}
+
+/// Density per solid angle of a light subpath leaving an object along `dir`. Light
+/// goes out like the cosine, on a random side for lights shining both ways.
+fn emission_pdf(hit: &HitResult, dir: Vec3) -> f64 {
+    let outward = if hit.front_face() { hit.normal() } else { -hit.normal() };
+    let cos = outward.dot(dir);
+    if matches!(hit.material(), Material::Emissive { one_sided: true, .. }) {
+        cos.max(0.0) / PI
+    } else {
+        cos.abs() / (2.0 * PI)
+    }
+}
+
+fn light_dir_pdf(light: &Light, dir: Vec3) -> f64 {
+    match light {
+        Light::Spot { direction, outer_angle, .. } => {
+            let cos_max = outer_angle.to_radians().cos();
+            if dir.dot(direction.normalize()) < cos_max { 0.0 } else { 1.0 / (2.0 * PI * (1.0 - cos_max)) }
+        }
+        _ => 1.0 / (4.0 * PI),
+    }
+}
+
+/// Random direction within `cos_max` of `axis`, uniformly.
+fn sample_cone(axis: Vec3, cos_max: f64) -> Vec3 {
+    let cos = 1.0 - random::<f64>() * (1.0 - cos_max);
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * random::<f64>();
+    let (u, v) = axis.orthonormal_basis();
+    (sin * phi.cos()) * u + (sin * phi.sin()) * v + cos * axis
+}
+
+/// Turn a density per solid angle at `from` into a density per area at `to`.
+fn to_area(pdf: f64, from: Vec3, to: &Vertex) -> f64 {
+    let offset = to.point - from;
+    pdf * to.cos(offset.normalize()) / offset.norm_sq()
+}
+
+fn remap0(pdf: f64) -> f64 {
+    if pdf != 0.0 { pdf } else { 1.0 }
+}
+
+/// Bidirectional path tracer, from "Robust Monte Carlo Methods for Light Transport
+/// Simulation" by Veach. Every sample traces a subpath from the camera and one from
+/// a light, and connects every vertex of one to every vertex of the other. Each way
+/// of making a path is weighed with the power heuristic against all the other ways,
+/// so paths that are hard to find from the camera, like caustics, come from the
+/// light instead.
+///
+/// Lights are picked by how much light they give off. Light from the sky and
+/// directional lights can't be sent out from the light, so it's only found from
+/// the camera like the path tracer does. Fog and volumes are left out, rays pass
+/// straight through them.
+pub(crate) struct Bdpt<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    max_depth: u32,
+    lights: LightSources<'a>,
+}
+
+/// Lights that light can be sent out from, picked by how much light they give off.
+/// Directional lights and the sky are too far away for that.
+pub(crate) struct LightSources<'a> {
+    scene: &'a Scene,
+    sources: Vec<Source<'a>>,
+    table: Option<AliasTable>,
+    // Where each slot of the scene is in `sources`
+    source_of_slot: Vec<Option<usize>>,
+}
+
+impl<'a> LightSources<'a> {
+    pub(crate) fn new(scene: &'a Scene) -> Self {
+        let mut sources = Vec::new();
+        let mut powers = Vec::new();
+        for light in scene.lights() {
+            let (color, intensity, solid_angle) = match light {
+                Light::Point { color, intensity, .. } => (color, intensity, 4.0 * PI),
+                Light::Spot { color, intensity, outer_angle, .. } => {
+                    (color, intensity, 2.0 * PI * (1.0 - outer_angle.to_radians().cos()))
+                }
+                Light::Directional { .. } => continue,
+            };
+            sources.push(Source::Light(light));
+            powers.push(solid_angle * intensity * color.luminance().max(0.0));
+        }
+        let mut source_of_slot = Vec::new();
+        for &slot in scene.emitters() {
+            let object = scene.object(slot);
+            if source_of_slot.len() <= slot {
+                source_of_slot.resize(slot + 1, None);
+            }
+            source_of_slot[slot] = Some(sources.len());
+            sources.push(Source::Emitter(slot));
+            powers.push(object.material().map_or(0.0, |material| material.power(object.area())));
+        }
+        Self { scene, sources, table: AliasTable::new(&powers), source_of_slot }
+    }
+
+    /// Pick a light to start a subpath from, and the chance of picking it.
+    fn pick(&self) -> Option<(&Source<'a>, f64)> {
+        let table = self.table.as_ref()?;
+        let picked = table.sample();
+        Some((&self.sources[picked], table.probability(picked)))
+    }
+
+    /// A vertex on a light picked at random, with the chance of picking it per area
+    /// in `pdf_fwd`.
+    fn sample(&self) -> Option<Vertex<'a>> {
+        let (source, probability) = self.pick()?;
+        let (kind, point, normal, pdf) = match *source {
+            Source::Light(light) => {
+                let position = match light {
+                    Light::Point { position, .. } | Light::Spot { position, .. } => *position,
+                    Light::Directional { .. } => return None,
+                };
+                (Kind::Light(light), position, None, probability)
+            }
+            Source::Emitter(slot) => {
+                let object = self.scene.object(slot);
+                let (point, normal) = object.sample_area()?;
+                // Hit it from outside to find its uv and such
+                let hit = object.hit(Ray::new(point + normal, -normal), 0.0..f64::INFINITY)?.with_object(slot);
+                let (point, normal) = (hit.hit_point(), hit.normal());
+                (Kind::Emitter(hit), point, Some(normal), probability / object.area())
+            }
+        };
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(Vertex::new(kind, point, normal, Vec3::new(1.0, 1.0, 1.0) / pdf, pdf))
+    }
+
+    /// Density per area of a light subpath starting at the light `vertex` is on.
+    fn pdf_origin(&self, vertex: &Vertex) -> f64 {
+        let table = match &self.table {
+            Some(table) => table,
+            None => return 0.0,
+        };
+        match &vertex.kind {
+            Kind::Light(light) => self.sources.iter()
+                .position(|source| matches!(source, Source::Light(other) if std::ptr::eq(*other, *light)))
+                .map_or(0.0, |picked| table.probability(picked)),
+            Kind::Emitter(hit) | Kind::Surface(hit) => {
+                let slot = match hit.object() {
+                    Some(slot) => slot,
+                    None => return 0.0,
+                };
+                match self.source_of_slot.get(slot).copied().flatten() {
+                    Some(picked) => table.probability(picked) / self.scene.object(slot).area(),
+                    None => 0.0,
+                }
+            }
+            Kind::Camera => 0.0,
+        }
+    }
+
+    /// Pick a direction for light to leave the light `start` is on in, and the ray
+    /// going that way. Also gives the density per solid angle of picking it.
+    fn leave(&self, start: &Vertex<'a>) -> Option<(Ray, f64)> {
+        let (dir, ray) = match &start.kind {
+            Kind::Light(light) => {
+                let dir = match light {
+                    Light::Spot { direction, outer_angle, .. } => sample_cone(direction.normalize(), outer_angle.to_radians().cos()),
+                    _ => Vec3::random_unit(),
+                };
+                (dir, Ray::new(start.point, dir))
+            }
+            Kind::Emitter(hit) => {
+                let outward = if hit.front_face() { hit.normal() } else { -hit.normal() };
+                let mut dir = (outward + Vec3::random_unit()).normalize();
+                let one_sided = matches!(hit.material(), Material::Emissive { one_sided: true, .. });
+                if !one_sided && random::<f64>() < 0.5 {
+                    dir = -dir;
+                }
+                (dir, hit.spawn_ray(dir))
+            }
+            _ => return None,
+        };
+        let pdf = start.pdf_light_dir(dir);
+        (pdf > 0.0).then_some((ray, pdf))
+    }
+
+    /// Light leaving a light picked at random, as a ray and what it carries, divided
+    /// by the chance of picking it. Like photons sent out for photon mapping.
+    pub(crate) fn emit(&self) -> Option<(Ray, Vec3)> {
+        let start = self.sample()?;
+        let (ray, pdf) = self.leave(&start)?;
+        let dir = ray.dir().normalize();
+        Some((ray, start.cos(dir) / pdf * start.beta * start.light_sent(dir)))
+    }
+}
+
+impl<'a> Bdpt<'a> {
+    pub(crate) fn new(scene: &'a Scene, camera: &'a Camera, max_depth: u32) -> Self {
+        Self { scene, camera, max_depth, lights: LightSources::new(scene) }
+    }
+
+    /// Light arriving through pixel (x, y) from one sample. Paths that go through
+    /// other pixels, from light subpaths hitting the camera, are added to `splats`,
+    /// which has a color for every pixel.
+    pub(crate) fn sample(&self, x: u32, y: u32, splats: &mut [Vec3]) -> Vec3 {
+        let (camera_path, mut color) = self.camera_subpath(x, y);
+        let light_path = self.light_subpath();
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = (s + t) as i64 - 2;
+                if depth < 0 || depth > self.max_depth as i64 {
+                    continue;
+                }
+                if let Some((light, pixel)) = self.connect(&light_path, &camera_path, s, t) {
+                    match pixel {
+                        Some((x, y)) => {
+                            let index = (y * self.camera.width() + x) as usize;
+                            splats[index] = splats[index] + light;
+                        }
+                        None => color = color + light,
+                    }
+                }
+            }
+        }
+        color
+    }
+
+    /// Subpath from the camera through pixel (x, y). Also gives the light from the sky
+    /// and directional lights along it, which only the camera finds.
+    fn camera_subpath(&self, x: u32, y: u32) -> (Vec<Vertex<'a>>, Vec3) {
+        let ray = self.camera.ray_rand(x, y);
+        let pdf = self.camera.pdf(ray.dir());
+        let start = Vertex::new(Kind::Camera, self.camera.center(), None, Vec3::new(1.0, 1.0, 1.0), 1.0);
+        let mut vertices = vec![start];
+        let escaped = self.random_walk(ray, Vec3::new(1.0, 1.0, 1.0), pdf, self.max_depth + 1, &mut vertices);
+
+        let mut color = Vec3::zero();
+        for i in 1..vertices.len().min(self.max_depth as usize + 1) {
+            if !vertices[i].delta {
+                color = color + vertices[i].beta * self.infinite_lights(&vertices[i], &vertices[i - 1]);
+            }
+        }
+        if let (Some((ray, beta)), Some(sky)) = (escaped, self.scene.sky()) {
+            let radiance = sky.radiance(ray.dir());
+            let last = &vertices[vertices.len() - 1];
+            // The sun could have been sampled from surfaces that aren't mirrors
+            let weight = match (last.hit(), vertices.len() > 1 && !last.delta) {
+                (Some(hit), true) => {
+                    let prev = &vertices[vertices.len() - 2];
+                    let pdf = hit.material().pdf(prev.towards(last).0, hit.normal(), ray.dir().normalize());
+                    power_heuristic(pdf, sky.sun_pdf(ray.dir()))
+                }
+                _ => 1.0,
+            };
+            color = color + weight * beta * radiance;
+        }
+        (vertices, color)
+    }
+
+    /// Light from the sun and directional lights reflected by `vertex` towards `prev`.
+    fn infinite_lights(&self, vertex: &Vertex, prev: &Vertex) -> Vec3 {
+        let hit = match &vertex.kind {
+            Kind::Surface(hit) => hit,
+            _ => return Vec3::zero(),
+        };
+        let mut total = Vec3::zero();
+        let scattering = |dir: Vec3| vertex.cos(dir) * vertex.f(self.camera, Some(prev), dir);
+        for light in self.scene.lights() {
+            if let Light::Directional { .. } = light {
+                if let Some(sample) = light.sample(vertex.point) {
+                    if self.unblocked(hit.spawn_ray(sample.dir), f64::INFINITY) {
+                        total = total + scattering(sample.dir) * sample.irradiance;
+                    }
+                }
+            }
+        }
+        if let Some(sky) = self.scene.sky() {
+            if let Some(dir) = sky.sample_sun() {
+                let scattered = scattering(dir);
+                if !scattered.is_near_zero() && self.unblocked(hit.spawn_ray(dir), f64::INFINITY) {
+                    let sun_pdf = sky.sun_pdf(dir);
+                    let pdf = hit.material().pdf(prev.towards(vertex).0, hit.normal(), dir);
+                    total = total + power_heuristic(sun_pdf, pdf) / sun_pdf * scattered * sky.radiance(dir);
+                }
+            }
+        }
+        total
+    }
+
+    fn light_subpath(&self) -> Vec<Vertex<'a>> {
+        let start = match self.lights.sample() {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+        let (ray, pdf) = match self.lights.leave(&start) {
+            Some(leaving) => leaving,
+            None => return Vec::new(),
+        };
+        let dir = ray.dir().normalize();
+        let beta = start.cos(dir) / pdf * start.beta * start.light_sent(dir);
+        let mut vertices = vec![start];
+        if !beta.is_near_zero() {
+            self.random_walk(ray, beta, pdf, self.max_depth, &mut vertices);
+        }
+        vertices
+    }
+
+    /// Carry on a subpath from its last vertex along `ray`, which was picked with
+    /// density `pdf` per solid angle, until it has `max_bounces` more vertices. Gives
+    /// the ray that left the scene, and what it carried, if one did.
+    fn random_walk(&self, mut ray: Ray, mut beta: Vec3, mut pdf: f64, max_bounces: u32, vertices: &mut Vec<Vertex<'a>>) -> Option<(Ray, Vec3)> {
+        let mut bounces = 0;
+        loop {
+            let hit = match self.scene.hit_surface(ray) {
+                Some(hit) => hit,
+                None => return Some((ray, beta)),
+            };
+            let prev = vertices.len() - 1;
+            let mut vertex = Vertex::surface(hit, beta);
+            vertex.pdf_fwd = to_area(pdf, vertices[prev].point, &vertex);
+            vertices.push(vertex);
+            bounces += 1;
+            if bounces >= max_bounces {
+                return None;
+            }
+
+            let vertex = &mut vertices[prev + 1];
+            let hit = match &vertex.kind {
+                Kind::Surface(hit) => hit,
+                _ => unreachable!(),
+            };
+            let material = hit.material();
+            let scatter = material.scatter(ray, hit)?;
+            let (dir_in, dir_out) = (ray.dir().normalize(), scatter.ray.dir().normalize());
+            let pdf_rev = if material.is_specular() {
+                vertex.delta = true;
+                pdf = 0.0;
+                0.0
+            } else {
+                pdf = material.pdf(dir_in, hit.normal(), dir_out);
+                if pdf <= 0.0 {
+                    return None;
+                }
+                material.pdf(-dir_out, hit.normal(), -dir_in)
+            };
+            beta = beta * scatter.attenuation;
+            if beta.is_near_zero() {
+                return None;
+            }
+            let point = vertex.point;
+            vertices[prev].pdf_rev = to_area(pdf_rev, point, &vertices[prev]);
+            ray = scatter.ray;
+        }
+    }
+
+    /// Whether nothing but the boundaries of volumes is in the way of `ray` within
+    /// `distance`, for a normalized direction.
+    fn unblocked(&self, mut ray: Ray, mut distance: f64) -> bool {
+        loop {
+            match self.scene.hit(ray, 0.0..distance) {
+                None => return true,
+                Some(hit) if matches!(hit.material(), Material::Volume { .. }) => {
+                    distance -= hit.t();
+                    ray = hit.spawn_ray(ray.dir());
+                }
+                Some(_) => return false,
+            }
+        }
+    }
+
+    fn visible(&self, a: &Vertex, b: &Vertex) -> bool {
+        let (dir, _) = a.towards(b);
+        let ray = match a.hit() {
+            Some(hit) => hit.spawn_ray(dir),
+            None => Ray::new(a.point, dir),
+        };
+        // Stop just short of surfaces so they don't block themselves
+        let distance = (b.point - ray.origin()).norm();
+        let distance = if b.normal.is_some() { distance * (1.0 - 1e-4) } else { distance };
+        self.unblocked(ray, distance)
+    }
+
+    /// Light along the path made of the first `s` vertices of the light subpath and
+    /// the first `t` of the camera subpath, weighed against the other ways of making
+    /// it. Paths reaching the camera directly from the light subpath come with the
+    /// pixel they go through.
+    fn connect(&self, light_path: &[Vertex<'a>], camera_path: &[Vertex<'a>], s: usize, t: usize) -> Option<(Vec3, Option<(u32, u32)>)> {
+        let camera = self.camera;
+        let mut sampled = None;
+        let mut pixel = None;
+        let light = if s == 0 {
+            // The camera subpath hit a light by itself
+            let pt = &camera_path[t - 1];
+            pt.beta * pt.emitted()
+        } else if t == 1 {
+            let qs = &light_path[s - 1];
+            if qs.delta {
+                return None;
+            }
+            pixel = Some(camera.project(qs.point)?);
+            let eye = Vertex::new(Kind::Camera, camera.center(), None, Vec3::new(1.0, 1.0, 1.0), 1.0);
+            let (dir, distance_sq) = qs.towards(&eye);
+            let qs_prev = s.checked_sub(2).map(|i| &light_path[i]);
+            let light = qs.cos(dir) / distance_sq * qs.beta * qs.f(camera, qs_prev, dir) * eye.f(camera, None, -dir);
+            if light.is_near_zero() || !self.visible(qs, &eye) {
+                return None;
+            }
+            sampled = Some(eye);
+            light
+        } else if s == 1 {
+            let pt = &camera_path[t - 1];
+            if pt.delta {
+                return None;
+            }
+            let start = self.lights.sample()?;
+            let light = self.connection(&start, None, pt, Some(&camera_path[t - 2]));
+            if light.is_near_zero() || !self.visible(pt, &start) {
+                return None;
+            }
+            sampled = Some(start);
+            light
+        } else {
+            let (qs, pt) = (&light_path[s - 1], &camera_path[t - 1]);
+            if qs.delta || pt.delta {
+                return None;
+            }
+            let light = self.connection(qs, Some(&light_path[s - 2]), pt, Some(&camera_path[t - 2]));
+            if light.is_near_zero() || !self.visible(pt, qs) {
+                return None;
+            }
+            light
+        };
+        if light.is_near_zero() {
+            return None;
+        }
+        Some((self.mis_weight(light_path, camera_path, sampled.as_ref(), s, t) * light, pixel))
+    }
+
+    /// What the light subpath ending in `qs` brings along the edge to the camera
+    /// subpath ending in `pt`, if nothing is in the way.
+    fn connection(&self, qs: &Vertex, qs_prev: Option<&Vertex>, pt: &Vertex, pt_prev: Option<&Vertex>) -> Vec3 {
+        let (dir, distance_sq) = pt.towards(qs);
+        let geometry = qs.cos(dir) * pt.cos(dir) / distance_sq;
+        geometry * qs.beta * qs.f(self.camera, qs_prev, -dir) * pt.f(self.camera, pt_prev, dir) * pt.beta
+    }
+
+    /// Power heuristic weight of the path connecting subpaths at `s` and `t` against
+    /// every other `s` and `t` that could have made it. A vertex picked just for the
+    /// connection, on a light for `s` = 1 or the camera for `t` = 1, is `sampled`.
+    fn mis_weight(&self, light_path: &[Vertex], camera_path: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+        let camera = self.camera;
+        let (sampled_light, sampled_camera) = if t == 1 { (None, sampled) } else { (sampled, None) };
+        let qs = match s {
+            0 => None,
+            1 => sampled_light.or(light_path.first()),
+            _ => Some(&light_path[s - 1]),
+        };
+        let pt = sampled_camera.unwrap_or_else(|| &camera_path[t - 1]);
+        let qs_prev = s.checked_sub(2).map(|i| &light_path[i]);
+        let pt_prev = t.checked_sub(2).map(|i| &camera_path[i]);
+
+        // (pdf_fwd, pdf_rev, delta) of each vertex, as if the path was made this way
+        let copy = |vertex: &Vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta);
+        let mut lights: Vec<_> = light_path[..s].iter().map(copy).collect();
+        let mut cameras: Vec<_> = camera_path[..t].iter().map(copy).collect();
+        if s == 1 {
+            lights[0] = copy(qs.unwrap());
+        }
+        if t == 1 {
+            cameras[0] = copy(pt);
+        }
+        cameras[t - 1].2 = false;
+        cameras[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(camera, qs_prev, pt),
+            None => self.lights.pdf_origin(pt),
+        };
+        if let Some(pt_prev) = pt_prev {
+            cameras[t - 2].1 = match qs {
+                Some(qs) => pt.pdf(camera, Some(qs), pt_prev),
+                None => pt.pdf_light(pt_prev),
+            };
+        }
+        if let Some(qs) = qs {
+            lights[s - 1].2 = false;
+            lights[s - 1].1 = pt.pdf(camera, pt_prev, qs);
+            if let Some(qs_prev) = qs_prev {
+                lights[s - 2].1 = qs.pdf(camera, Some(pt), qs_prev);
+            }
+        }
+
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap0(cameras[i].1) / remap0(cameras[i].0);
+            if !cameras[i].2 && !cameras[i - 1].2 {
+                sum += ratio * ratio;
+            }
+        }
+        let delta_light = match s {
+            0 => false,
+            1 => qs.is_some_and(|qs| qs.is_delta_light()),
+            _ => light_path[0].is_delta_light(),
+        };
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap0(lights[i].1) / remap0(lights[i].0);
+            let delta_before = if i > 0 { lights[i - 1].2 } else { delta_light };
+            if !lights[i].2 && !delta_before {
+                sum += ratio * ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt::Write;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use crate::csg::Csg;
+use crate::material::Material;
+use crate::packet::{RayPacket, WIDTH};
+use crate::ray::Ray;
+use crate::renderer::Renderer;
+use crate::scenes::{self, many_objects};
+use crate::sdf::{DistanceField, Sdf};
+use crate::shapes::{AxisAlignedBox, Cone, Cylinder, Disk, Hittable, Quad, Sphere, Torus, Triangle};
+use crate::util::seed;
+use crate::vector::Vec3;
+
+// Everything random is seeded with this, so every run renders the same images
+const SEED: u64 = 1;
+// How long to repeat the quick benchmarks for
+const BUDGET: Duration = Duration::from_secs(1);
+
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+/// Run all benchmarks, printing one line of JSON per result so runs can be compared
+/// by scripts. Only benchmarks with a name containing `filter` are run.
+pub fn run(filter: &str) {
+    let benchmarks: [(&str, fn()); 5] = [
+        ("vec3", vec3_ops),
+        ("intersect", intersections),
+        ("bvh_build", bvh_build),
+        ("traversal", traversal),
+        ("render", renders),
+    ];
+    for (name, benchmark) in benchmarks {
+        if name.contains(filter) {
+            benchmark();
+        }
+    }
+}
+
+fn backend() -> &'static str {
+    if cfg!(all(feature = "sse", target_arch = "x86_64")) {
+        "sse"
+    } else if cfg!(feature = "f32") {
+        "f32"
+    } else {
+        "f64"
+    }
+}
+
+fn report(benchmark: &str, name: &str, values: &[(&str, Value)]) {
+    let mut line = format!("{{\"benchmark\":\"{}\",\"name\":\"{}\",\"backend\":\"{}\"", benchmark, name, backend());
+    for (key, value) in values {
+        match value {
+            Value::Number(number) => write!(line, ",\"{}\":{}", key, number),
+            Value::Text(text) => write!(line, ",\"{}\":\"{}\"", key, text),
+        }.unwrap();
+    }
+    line.push('}');
+    println!("{}", line);
+}
+
+/// Average time of running `f`, repeated for about `BUDGET`.
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    // Warm up caches first
+    black_box(f());
+    let start = Instant::now();
+    let mut runs = 0;
+    while runs == 0 || start.elapsed() < BUDGET {
+        black_box(f());
+        runs += 1;
+    }
+    start.elapsed() / runs
+}
+
+fn nanos_per(duration: Duration, count: usize) -> Value {
+    Value::Number(duration.as_nanos() as f64 / count as f64)
+}
+
+fn vec3_ops() {
+    seed(SEED);
+    let count = 1000;
+    let a: Vec<Vec3> = (0..count).map(|_| Vec3::random()).collect();
+    let b: Vec<Vec3> = (0..count).map(|_| Vec3::random()).collect();
+    let pairs = || a.iter().zip(&b).map(|(a, b)| (black_box(*a), black_box(*b)));
+
+    let ops: [(&str, Duration); 6] = [
+        ("add", time(|| pairs().map(|(a, b)| a + b).fold(Vec3::zero(), |sum, v| sum + v))),
+        ("mul", time(|| pairs().map(|(a, b)| a * b).fold(Vec3::zero(), |sum, v| sum + v))),
+        ("scale", time(|| pairs().map(|(a, b)| a.x() * b).fold(Vec3::zero(), |sum, v| sum + v))),
+        ("dot", time(|| pairs().map(|(a, b)| a.dot(b)).sum::<f64>())),
+        ("cross", time(|| pairs().map(|(a, b)| a.cross(b)).fold(Vec3::zero(), |sum, v| sum + v))),
+        ("normalize", time(|| pairs().map(|(a, _)| a.normalize()).fold(Vec3::zero(), |sum, v| sum + v))),
+    ];
+    for (name, duration) in ops {
+        report("vec3", name, &[("ns_per_op", nanos_per(duration, count))]);
+    }
+}
+
+fn intersections() {
+    let material = || Material::Diffuse { color: Vec3::new(0.5, 0.5, 0.5) };
+    let shapes: Vec<(&str, Box<dyn Hittable>)> = vec![
+        ("sphere", Box::new(Sphere::new(Vec3::zero(), 1.0, material()))),
+        ("triangle", Box::new(Triangle::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material()))),
+        ("quad", Box::new(Quad::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0), material()))),
+        ("disk", Box::new(Disk::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 1.0, material()))),
+        ("box", Box::new(AxisAlignedBox::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0), material()))),
+        ("cylinder", Box::new(Cylinder::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 1.0, material()))),
+        ("cone", Box::new(Cone::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 1.0, material()))),
+        ("torus", Box::new(Torus::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 0.75, 0.25, material()))),
+        ("csg", Box::new(Csg::difference(
+            AxisAlignedBox::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0), material()),
+            Sphere::new(Vec3::zero(), 1.25, material()),
+        ))),
+        ("sdf", Box::new(Sdf::new(DistanceField::Sphere { center: Vec3::zero(), radius: 1.0 }, material()))),
+    ];
+
+    // From all around towards points near the shapes, so about half of them hit
+    seed(SEED);
+    let count = 1000;
+    let rays: Vec<Ray> = (0..count)
+        .map(|_| {
+            let origin = 4.0 * Vec3::random_unit();
+            Ray::new(origin, 1.5 * Vec3::random() - origin)
+        })
+        .collect();
+
+    for (name, shape) in shapes {
+        let hits = rays.iter().filter(|ray| shape.hit(**ray, 0.0..f64::INFINITY).is_some()).count();
+        let duration = time(|| rays.iter().filter(|ray| shape.hit(**ray, 0.0..f64::INFINITY).is_some()).count());
+        report("intersect", name, &[
+            ("ns_per_ray", nanos_per(duration, count)),
+            ("hit_fraction", Value::Number(hits as f64 / count as f64)),
+        ]);
+    }
+}
+
+fn bvh_build() {
+    for count in [1_000, 10_000, 100_000] {
+        seed(SEED);
+        let scene = many_objects(count);
+        let start = Instant::now();
+        scene.build_accelerator();
+        report("bvh_build", &count.to_string(), &[
+            ("objects", Value::Number(count as f64)),
+            ("ms", Value::Number(start.elapsed().as_secs_f64() * 1000.0)),
+        ]);
+    }
+}
+
+/// Tracing rays one by one compared with tracing them in packets.
+fn traversal() {
+    seed(SEED);
+    let scene = many_objects(10_000);
+    scene.build_accelerator();
+    // Like the samples of a pixel
+    let origin = Vec3::new(0.0, 0.0, 20.0);
+    let packets: Vec<[Ray; WIDTH]> = (0..1000)
+        .map(|_| {
+            let target = 10.0 * Vec3::random();
+            [(); WIDTH].map(|_| Ray::new(origin, target + 0.01 * Vec3::random() - origin))
+        })
+        .collect();
+    let count = packets.len() * WIDTH;
+
+    let scalar_primary = time(|| {
+        packets.iter().flat_map(|rays| rays.map(|ray| scene.hit(ray, 0.0..f64::INFINITY).is_some())).filter(|&hit| hit).count()
+    });
+    let packet_primary = time(|| {
+        packets.iter().flat_map(|rays| scene.hit_packet(&RayPacket::new(*rays), 0.0..f64::INFINITY).map(|hit| hit.is_some())).filter(|&hit| hit).count()
+    });
+    let scalar_shadow = time(|| {
+        packets.iter().flat_map(|rays| rays.map(|ray| scene.occluded(ray, 0.0..1.0))).filter(|&hit| hit).count()
+    });
+    let packet_shadow = time(|| {
+        packets.iter().flat_map(|rays| scene.occluded_packet(&RayPacket::new(*rays), 0.0..1.0)).filter(|&hit| hit).count()
+    });
+    report("traversal", "scalar_primary", &[("ns_per_ray", nanos_per(scalar_primary, count))]);
+    report("traversal", "packet_primary", &[("ns_per_ray", nanos_per(packet_primary, count))]);
+    report("traversal", "scalar_shadow", &[("ns_per_ray", nanos_per(scalar_shadow, count))]);
+    report("traversal", "packet_shadow", &[("ns_per_ray", nanos_per(packet_shadow, count))]);
+}
+
+/// FNV-1a, to tell if a change makes the images come out different.
+fn checksum(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+fn renders() {
+    let scenes: [(&str, scenes::Setup, u32, u32); 6] = [
+        ("spheres", scenes::spheres, 160, 90),
+        ("cornell", scenes::cornell_box, 100, 100),
+        ("glass", scenes::glass_sphere, 100, 100),
+        ("furnace", scenes::furnace, 100, 100),
+        ("many", scenes::many, 160, 90),
+        ("many_lights", scenes::many_lights, 160, 90),
+    ];
+    let samples_per_pixel = 16;
+    for (name, setup, width, height) in scenes {
+        // Some scenes place objects randomly, so seed before setting them up
+        seed(SEED);
+        let (scene, camera) = setup(width, height);
+        scene.build_accelerator();
+
+        let start = Instant::now();
+        let image = Renderer::new().samples_per_pixel(samples_per_pixel).render(&scene, &camera);
+        let seconds = start.elapsed().as_secs_f64();
+        let rays = scene.rays_traced() as f64;
+        let samples = (image.width() * image.height() * samples_per_pixel) as f64;
+        report("render", name, &[
+            ("width", Value::Number(image.width() as f64)),
+            ("height", Value::Number(image.height() as f64)),
+            ("samples_per_pixel", Value::Number(samples_per_pixel as f64)),
+            ("seconds", Value::Number(seconds)),
+            ("rays_per_second", Value::Number(rays / seconds)),
+            ("samples_per_second", Value::Number(samples / seconds)),
+            ("checksum", Value::Text(checksum(image.as_raw()))),
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv_checksum() {
+        assert_eq!(checksum(b""), "cbf29ce484222325");
+        assert_eq!(checksum(b"a"), "af63dc4c8601ec8c");
+    }
+}
@@ -0,0 +1,244 @@
+use std::ops::Range;
+use crate::aabb::Aabb;
+use crate::packet::{hit_boxes, splat_ray, Lanes, RayPacket, WIDTH};
+use crate::ray::Ray;
+
+#[derive(Debug, Copy, Clone)]
+enum Child {
+    Empty,
+    Node(usize),
+    Leaf(usize),
+}
+
+/// The boxes of the children are stored one coordinate per array, so a ray can be
+/// tested against all of them at once.
+struct Node {
+    min: [Lanes; 3],
+    max: [Lanes; 3],
+    children: [Child; WIDTH],
+}
+
+/// Bounding volume hierarchy with `WIDTH` children per node. Leaves hold up to
+/// `WIDTH` objects, referred to by whatever index they were built with.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    leaves: Vec<Vec<usize>>,
+}
+
+impl Bvh {
+    pub fn new(boxes: &[(usize, Aabb)]) -> Self {
+        let mut bvh = Self { nodes: Vec::new(), leaves: Vec::new() };
+        if !boxes.is_empty() {
+            bvh.build(boxes.to_vec());
+        }
+        bvh
+    }
+
+    /// The objects in each leaf.
+    pub fn leaves(&self) -> &[Vec<usize>] {
+        &self.leaves
+    }
+
+    fn build(&mut self, items: Vec<(usize, Aabb)>) -> usize {
+        let groups = if items.len() <= WIDTH {
+            vec![items]
+        } else {
+            // Halve at the median twice. Not as good as a surface area heuristic, but
+            // quick to build and good enough for scenes that aren't huge.
+            split(items).into_iter().flat_map(split).collect()
+        };
+
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            min: [[f64::INFINITY; WIDTH]; 3],
+            max: [[f64::NEG_INFINITY; WIDTH]; 3],
+            children: [Child::Empty; WIDTH],
+        });
+        for (lane, group) in groups.into_iter().enumerate() {
+            let bounds = group.iter().map(|(_, bounds)| *bounds).reduce(|a, b| a.union(&b)).unwrap();
+            let child = if group.len() <= WIDTH {
+                self.leaves.push(group.into_iter().map(|(object, _)| object).collect());
+                Child::Leaf(self.leaves.len() - 1)
+            } else {
+                Child::Node(self.build(group))
+            };
+            let node = &mut self.nodes[index];
+            for axis in 0..3 {
+                node.min[axis][lane] = bounds.min().axis(axis);
+                node.max[axis][lane] = bounds.max().axis(axis);
+            }
+            node.children[lane] = child;
+        }
+        index
+    }
+
+    /// Visit the leaves the ray might hit, closest first. `visit` gets the range left
+    /// to search and returns the t of the closest hit so far. Returning at most the
+    /// start of the range stops the search, like when any hit is enough.
+    pub fn traverse(&self, ray: Ray, t_range: Range<f64>, mut visit: impl FnMut(usize, Range<f64>) -> f64) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let (origin, inv_dir) = splat_ray(ray);
+        let t_min = [t_range.start; WIDTH];
+        let mut closest = t_range.end;
+        let mut stack = Vec::with_capacity(64);
+        stack.push((Child::Node(0), t_range.start));
+
+        while let Some((child, enter)) = stack.pop() {
+            if enter > closest {
+                continue;
+            }
+            match child {
+                Child::Node(index) => {
+                    let node = &self.nodes[index];
+                    let (near, hit) = hit_boxes(&origin, &inv_dir, &node.min, &node.max, &t_min, &[closest; WIDTH]);
+                    // Closest last, so it's next off the stack
+                    let start = stack.len();
+                    for lane in (0..WIDTH).filter(|&lane| hit[lane]) {
+                        stack.push((node.children[lane], near[lane]));
+                    }
+                    stack[start..].sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+                }
+                Child::Leaf(leaf) => {
+                    closest = visit(leaf, t_range.start..closest);
+                    if closest <= t_range.start {
+                        return;
+                    }
+                }
+                Child::Empty => {}
+            }
+        }
+    }
+
+    /// Visit the leaves any ray of the packet might hit, roughly closest first.
+    /// `visit` gets which rays reach the leaf and lowers `t_max` of the ones it finds
+    /// hits for. Rays are done once their `t_max` is at most `t_min`.
+    pub fn traverse_packet(&self, packet: &RayPacket, t_min: f64, t_max: &mut Lanes, mut visit: impl FnMut(usize, [bool; WIDTH], &mut Lanes)) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let t_mins = [t_min; WIDTH];
+        let mut stack = Vec::with_capacity(64);
+        stack.push((Child::Node(0), t_mins, [true; WIDTH]));
+
+        while let Some((child, enter, hit)) = stack.pop() {
+            // Hits found since the box was tested can rule it out
+            let hit: [bool; WIDTH] = std::array::from_fn(|lane| hit[lane] && enter[lane] <= t_max[lane]);
+            if !hit.iter().any(|&hit| hit) {
+                continue;
+            }
+            match child {
+                Child::Node(index) => {
+                    let node = &self.nodes[index];
+                    let start = stack.len();
+                    for lane in 0..WIDTH {
+                        let min = node.min.map(|axis| axis[lane]);
+                        let max = node.max.map(|axis| axis[lane]);
+                        let (near, child_hit) = packet.hit_box(min, max, &t_mins, t_max);
+                        if child_hit.iter().any(|&hit| hit) {
+                            stack.push((node.children[lane], near, child_hit));
+                        }
+                    }
+                    // Closest for the first ray that hits last, so it's next off the stack
+                    let first_near = |(_, near, hit): &(Child, Lanes, [bool; WIDTH])| {
+                        (0..WIDTH).find(|&lane| hit[lane]).map_or(f64::INFINITY, |lane| near[lane])
+                    };
+                    stack[start..].sort_unstable_by(|a, b| first_near(b).total_cmp(&first_near(a)));
+                }
+                Child::Leaf(leaf) => {
+                    visit(leaf, hit, t_max);
+                    if t_max.iter().all(|&t| t <= t_min) {
+                        return;
+                    }
+                }
+                Child::Empty => {}
+            }
+        }
+    }
+}
+
+/// Halve at the median of the centers along the axis they are most spread out on.
+fn split(mut items: Vec<(usize, Aabb)>) -> Vec<Vec<(usize, Aabb)>> {
+    if items.len() <= 1 {
+        return vec![items];
+    }
+    let centers: Vec<_> = items.iter().map(|(_, bounds)| bounds.center()).collect();
+    let spread = Aabb::from_points(&centers).size();
+    let axis = (0..3).max_by(|&a, &b| spread.axis(a).total_cmp(&spread.axis(b))).unwrap();
+    items.sort_by(|(_, a), (_, b)| a.center().axis(axis).total_cmp(&b.center().axis(axis)));
+    let upper = items.split_off(items.len() / 2);
+    vec![items, upper]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vec3;
+
+    fn random_boxes(count: usize) -> Vec<(usize, Aabb)> {
+        (0..count)
+            .map(|i| {
+                let center = 10.0 * Vec3::random();
+                (i, Aabb::new(center - 0.2 * Vec3::new(1.0, 1.0, 1.0), center + 0.3 * Vec3::new(1.0, 1.0, 1.0)))
+            })
+            .collect()
+    }
+
+    fn ray_hits_box(ray: Ray, bounds: &Aabb) -> bool {
+        bounds.hit_range(ray, 0.0..f64::INFINITY).is_some()
+    }
+
+    #[test]
+    fn every_object_in_one_leaf() {
+        let boxes = random_boxes(1000);
+        let bvh = Bvh::new(&boxes);
+        let mut objects: Vec<usize> = bvh.leaves().iter().flatten().copied().collect();
+        objects.sort();
+        assert_eq!(objects, (0..1000).collect::<Vec<_>>());
+        assert!(bvh.leaves().iter().all(|leaf| !leaf.is_empty() && leaf.len() <= WIDTH));
+    }
+
+    #[test]
+    fn visits_leaves_of_hit_boxes() {
+        let boxes = random_boxes(300);
+        let bvh = Bvh::new(&boxes);
+        for _ in 0..100 {
+            let rays: [Ray; WIDTH] = std::array::from_fn(|_| Ray::new(15.0 * Vec3::random(), Vec3::random()));
+
+            let mut packet_visited = vec![Vec::new(); WIDTH];
+            let mut t_max = [f64::INFINITY; WIDTH];
+            bvh.traverse_packet(&RayPacket::new(rays), 0.0, &mut t_max, |leaf, hit, _| {
+                for lane in (0..WIDTH).filter(|&lane| hit[lane]) {
+                    packet_visited[lane].extend(&bvh.leaves()[leaf]);
+                }
+            });
+
+            for (lane, ray) in rays.into_iter().enumerate() {
+                let mut visited = Vec::new();
+                bvh.traverse(ray, 0.0..f64::INFINITY, |leaf, range| {
+                    visited.extend(&bvh.leaves()[leaf]);
+                    range.end
+                });
+                for (object, bounds) in &boxes {
+                    if ray_hits_box(ray, bounds) {
+                        assert!(visited.contains(object) && packet_visited[lane].contains(object));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stops_when_done() {
+        let boxes = random_boxes(300);
+        let bvh = Bvh::new(&boxes);
+        let ray = Ray::new(Vec3::new(20.0, 20.0, 20.0), boxes[0].1.center() - Vec3::new(20.0, 20.0, 20.0));
+        let mut visits = 0;
+        bvh.traverse(ray, 0.0..f64::INFINITY, |_, range| {
+            visits += 1;
+            range.start
+        });
+        assert_eq!(visits, 1);
+    }
+}
@@ -1,24 +1,20 @@
-use std::time::Instant;
-use image::{ImageBuffer, Rgb, RgbImage};
-use rand::random;
 use crate::ray::Ray;
-use crate::scene::Scene;
+use crate::util::random;
 use crate::vector::Vec3;
 
+/// Where the image is seen from, and how many pixels it has. `fov` is the vertical
+/// field of view in degrees.
+#[derive(Debug, Clone)]
 pub struct Camera {
     image_width: u32,
     image_height: u32,
     center: Vec3,
-    viewport_width: f64,
-    viewport_height: f64,
     pixel_delta_u: Vec3,
     pixel_delta_v: Vec3,
     top_left_pixel_pos: Vec3,
-    max_depth: u32,
-    fov: f64, // (vertical)
-    look_from: Vec3,
-    look_at: Vec3,
-    view_up: Vec3,
+    // Straight ahead, and how far away the viewport is
+    forward: Vec3,
+    focal_length: f64,
 }
 
 impl Camera {
@@ -50,50 +46,24 @@ impl Camera {
             image_width,
             image_height,
             center: camera_center,
-            viewport_width,
-            viewport_height,
             pixel_delta_u,
             pixel_delta_v,
             top_left_pixel_pos,
-            max_depth: 5,
-            fov,
-            look_from,
-            look_at,
-            view_up,
+            forward: -w,
+            focal_length,
         }
     }
 
-    pub fn render_image(&self, scene: &Scene) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-        let start = Instant::now();
-
-        let mut img = RgbImage::new(self.image_width, self.image_height);
-        for y in 0..self.image_height {
-            println!("{} / {}", y, self.image_height);
-            for x in 0..self.image_width {
-                // Average colors (anti-aliasing)
-                let mut color = Vec3::zero();
-                let sample_count = 1000;
-                for i in 0..sample_count {
-                    let ray = self.ray_rand(x, y);
-                    let color_i = self.ray_color(ray, &scene, self.max_depth);
-                    // color += color_i;
-                    color = color + color_i;
-                }
-                // color /= sample_count as f64;
-                color = color / (sample_count as f64);
-
-                let rgb = to_rgb(color.x(), color.y(), color.z());
-                img.put_pixel(x, y, rgb);
-            }
-        }
-
-        let elapsed = start.elapsed();
-        println!("\nDone in {:.2?}", elapsed);
+    pub fn width(&self) -> u32 {
+        self.image_width
+    }
 
-        img
+    pub fn height(&self) -> u32 {
+        self.image_height
     }
 
-    fn ray_rand(&self, x: u32, y: u32) -> Ray {
+    /// Ray through a random point in pixel (x, y), counting from the top left.
+    pub fn ray_rand(&self, x: u32, y: u32) -> Ray {
         let viewport_pixel = self.top_left_pixel_pos + (x as f64 * self.pixel_delta_u) + (y as f64 * self.pixel_delta_v);
         let delta_x = random::<f64>() - 0.5;
         let delta_y = random::<f64>() - 0.5;
@@ -103,40 +73,61 @@ impl Camera {
         Ray::new(self.center, ray_dir)
     }
 
-    fn ray_color(&self, ray: Ray, scene: &Scene, depth: u32) -> Vec3 {
-        if depth < 1 {
-            return Vec3::zero();
-        }
-        let hit_result = scene.hit(ray, 0.001..f64::INFINITY);
-        if let Some(hit_result) = hit_result {
-            let light = hit_result.material().get_light();
-
-            if let Some(scatter) = hit_result.material().scatter(ray, &hit_result) {
-                return scatter.attenuation * self.ray_color(scatter.ray, scene, depth - 1) + light;
-            }
+    pub(crate) fn center(&self) -> Vec3 {
+        self.center
+    }
 
-            return light;
+    /// The pixel a point is seen in, or None if it's outside of the image.
+    pub(crate) fn project(&self, point: Vec3) -> Option<(u32, u32)> {
+        let dir = point - self.center;
+        let ahead = dir.dot(self.forward);
+        if ahead <= 0.0 {
+            return None;
         }
-
-        // let dir_n = ray.dir().normalize();
-        // let a = 0.5 * (dir_n.y() + 1.0);
-        // Vec3::new(
-        //     (1.0-a) * 1.0 + 0.7 * a,
-        //     (1.0-a) * 1.0 + 1.0 * a,
-        //     (1.0-a) * 1.0 + 1.0 * a,
-        // )
-        Vec3::zero()
+        let on_viewport = self.center + (self.focal_length / ahead) * dir;
+        let top_left = self.top_left_pixel_pos - 0.5 * (self.pixel_delta_u + self.pixel_delta_v);
+        let offset = on_viewport - top_left;
+        let x = offset.dot(self.pixel_delta_u) / self.pixel_delta_u.norm_sq();
+        let y = offset.dot(self.pixel_delta_v) / self.pixel_delta_v.norm_sq();
+        if x < 0.0 || y < 0.0 || x >= self.image_width as f64 || y >= self.image_height as f64 {
+            return None;
+        }
+        Some((x as u32, y as u32))
     }
-}
 
-fn to_rgb(r: f64, g: f64, b: f64) -> Rgb<u8> {
-    return Rgb([
-        (gamma_correction(r) * 255.0) as u8,
-        (gamma_correction(g) * 255.0) as u8,
-        (gamma_correction(b) * 255.0) as u8,
-    ])
+    /// Density per solid angle of `ray_rand` picking `dir`, for a pixel picked at
+    /// random. Rays from lights reaching the camera along `dir` count this much
+    /// towards the image, which makes both ways of finding light agree.
+    pub(crate) fn pdf(&self, dir: Vec3) -> f64 {
+        if self.project(self.center + dir).is_none() {
+            return 0.0;
+        }
+        let cos = dir.normalize().dot(self.forward);
+        let area = self.image_width as f64 * self.pixel_delta_u.norm() * self.image_height as f64 * self.pixel_delta_v.norm();
+        self.focal_length * self.focal_length / (area * cos * cos * cos)
+    }
 }
 
-fn gamma_correction(value: f64) -> f64 {
-    return value.sqrt();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn projects_rays_back_to_their_pixel() {
+        let camera = Camera::new(Vec3::new(1.0, 2.0, 3.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 8, 6, 40.0);
+        for (x, y) in [(0, 0), (7, 5), (3, 2)] {
+            let ray = camera.ray_rand(x, y);
+            assert_eq!(camera.project(ray.at(2.5)), Some((x, y)));
+            assert!(camera.pdf(ray.dir()) > 0.0);
+        }
+        assert_eq!(camera.project(Vec3::new(2.0, 4.0, 6.0)), None);
+        assert_eq!(camera.pdf(Vec3::new(1.0, 2.0, 3.0)), 0.0);
+
+        // Adds up to 1 over all directions
+        util::seed(1);
+        let count = 200_000;
+        let total: f64 = (0..count).map(|_| camera.pdf(Vec3::random_unit())).sum();
+        assert!((total / count as f64 * 4.0 * std::f64::consts::PI - 1.0).abs() < 0.05);
+    }
 }
@@ -0,0 +1,207 @@
+use std::ops::Range;
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::shapes::{HitResult, Hittable, Solid};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CsgOperation {
+    /// Inside either shape.
+    Union,
+    /// Inside both shapes.
+    Intersection,
+    /// Inside the first shape but not the second.
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(&self, inside_a: bool, inside_b: bool) -> bool {
+        match self {
+            CsgOperation::Union => inside_a || inside_b,
+            CsgOperation::Intersection => inside_a && inside_b,
+            CsgOperation::Difference => inside_a && !inside_b,
+        }
+    }
+}
+
+/// Two solids combined into one, like a lens made from the intersection of two
+/// spheres. Every surface keeps the material of the shape it came from, including
+/// surfaces cut out by a difference.
+pub struct Csg {
+    operation: CsgOperation,
+    a: Box<dyn Solid>,
+    b: Box<dyn Solid>,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, a: impl Solid + 'static, b: impl Solid + 'static) -> Self {
+        Self { operation, a: Box::new(a), b: Box::new(b) }
+    }
+
+    pub fn union(a: impl Solid + 'static, b: impl Solid + 'static) -> Self {
+        Self::new(CsgOperation::Union, a, b)
+    }
+
+    pub fn intersection(a: impl Solid + 'static, b: impl Solid + 'static) -> Self {
+        Self::new(CsgOperation::Intersection, a, b)
+    }
+
+    pub fn difference(a: impl Solid + 'static, b: impl Solid + 'static) -> Self {
+        Self::new(CsgOperation::Difference, a, b)
+    }
+}
+
+impl Hittable for Csg {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        self.crossings(ray).into_iter().find(|hit| t_range.contains(&hit.t()))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        match self.operation {
+            CsgOperation::Union => self.a.bounding_box().union(&self.b.bounding_box()),
+            // Never reaches outside of the first shape
+            CsgOperation::Intersection | CsgOperation::Difference => self.a.bounding_box(),
+        }
+    }
+}
+
+impl Solid for Csg {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        let mut a = self.a.crossings(ray).into_iter().peekable();
+        let mut b = self.b.crossings(ray).into_iter().peekable();
+        let mut inside_a = false;
+        let mut inside_b = false;
+        let mut inside = false;
+        let mut crossings = Vec::new();
+
+        // Walk along both lists of crossings, keeping the ones where the result changes
+        loop {
+            let from_b = match (a.peek(), b.peek()) {
+                (Some(hit_a), Some(hit_b)) => hit_b.t() < hit_a.t(),
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (None, None) => break,
+            };
+            let hit = if from_b { b.next() } else { a.next() }.unwrap();
+            // The line starts outside and goes in and out with each crossing. Faces can't
+            // tell where a ray only touches the surface, it would seem to enter twice
+            if from_b {
+                inside_b = !inside_b;
+            } else {
+                inside_a = !inside_a;
+            }
+
+            let now_inside = self.operation.contains(inside_a, inside_b);
+            if now_inside != inside {
+                inside = now_inside;
+                // Going in and out at the same t, like a ray grazing a sphere, leaves an
+                // empty interval that isn't really a hit
+                if crossings.last().is_some_and(|last: &HitResult| last.t() == hit.t()) {
+                    crossings.pop();
+                    continue;
+                }
+                // Entering the second shape leaves the result, and the other way around
+                if from_b && self.operation == CsgOperation::Difference {
+                    crossings.push(hit.flip_face());
+                } else {
+                    crossings.push(hit);
+                }
+            }
+        }
+        crossings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::shapes::{AxisAlignedBox, Sphere};
+    use crate::vector::Vec3;
+
+    fn material(gray: f64) -> Material {
+        Material::Diffuse { color: Vec3::new(gray, gray, gray) }
+    }
+
+    fn color(hit: &HitResult) -> Vec3 {
+        match hit.material() {
+            Material::Diffuse { color } => *color,
+            _ => unreachable!(),
+        }
+    }
+
+    fn ray_along_x() -> Ray {
+        Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0))
+    }
+
+    fn overlapping_spheres() -> (Sphere, Sphere) {
+        // Spanning x from -2 to 0 and from -1 to 1
+        (
+            Sphere::new(Vec3::new(-1.0, 0.0, 0.0), 1.0, material(0.2)),
+            Sphere::new(Vec3::zero(), 1.0, material(0.8)),
+        )
+    }
+
+    #[test]
+    fn union() {
+        let (a, b) = overlapping_spheres();
+        let csg = Csg::union(a, b);
+        let ts: Vec<f64> = csg.crossings(ray_along_x()).iter().map(|hit| hit.t()).collect();
+        assert_eq!(ts, [3.0, 6.0]);
+    }
+
+    #[test]
+    fn intersection() {
+        let (a, b) = overlapping_spheres();
+        let csg = Csg::intersection(a, b);
+        let hits = csg.crossings(ray_along_x());
+        assert_eq!(hits.len(), 2);
+        assert_eq!((hits[0].t(), hits[1].t()), (4.0, 5.0));
+        assert_eq!(color(&hits[0]), Vec3::new(0.8, 0.8, 0.8));
+        assert_eq!(color(&hits[1]), Vec3::new(0.2, 0.2, 0.2));
+        assert!(hits[0].front_face() && !hits[1].front_face());
+    }
+
+    #[test]
+    fn difference_flips_cut_surface() {
+        // A box with a sphere bitten out of its right side
+        let cube = AxisAlignedBox::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0), material(0.2));
+        let sphere = Sphere::new(Vec3::new(1.0, 0.0, 0.0), 0.5, material(0.8));
+        let csg = Csg::difference(cube, sphere);
+
+        let hits = csg.crossings(ray_along_x());
+        assert_eq!(hits.len(), 2);
+        assert_eq!((hits[0].t(), hits[1].t()), (4.0, 5.5));
+        // Leaving the box through the inside of the sphere
+        let cut = &hits[1];
+        assert!(!cut.front_face());
+        assert_eq!(cut.normal(), Vec3::new(-1.0, 0.0, 0.0));
+        assert_eq!(color(cut), Vec3::new(0.8, 0.8, 0.8));
+
+        // From the other side the cut surface is where the ray enters
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        let hit = csg.hit(ray, 0.001..f64::INFINITY).unwrap();
+        assert_eq!(hit.t(), 4.5);
+        assert!(hit.front_face());
+        assert_eq!(hit.normal(), Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn grazing_ray_misses() {
+        // Just touching the top of both spheres
+        let (a, b) = overlapping_spheres();
+        let csg = Csg::union(a, b);
+        let ray = Ray::new(Vec3::new(-5.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(csg.crossings(ray).is_empty());
+        assert!(csg.hit(ray, 0.001..f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn nested() {
+        // A lens with a hole drilled through it
+        let (a, b) = overlapping_spheres();
+        let hole = Sphere::new(Vec3::new(-0.5, 0.0, 0.0), 0.25, material(0.5));
+        let csg = Csg::difference(Csg::intersection(a, b), hole);
+        let ts: Vec<f64> = csg.crossings(ray_along_x()).iter().map(|hit| hit.t()).collect();
+        assert_eq!(ts, [4.0, 4.25, 4.75, 5.0]);
+    }
+}
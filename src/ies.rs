@@ -0,0 +1,211 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use crate::vector::Vec3;
+
+// Real files have a few hundred angles at most
+const MAX_COUNT: usize = 100_000;
+
+/// How bright a light fixture is in each direction, from an IES LM-63 photometric
+/// file. Only type C photometry is read, which is what almost every fixture uses:
+/// vertical angles go from 0 straight down to 180 straight up, and horizontal
+/// angles go around the fixture.
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    // For each horizontal angle, the candela at every vertical angle
+    candela: Vec<Vec<f64>>,
+    max: f64,
+}
+
+impl IesProfile {
+    pub fn load(file_path: &str) -> Result<Self> {
+        Self::parse(&fs::read_to_string(file_path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+        // Keywords come before the TILT line, then it's all numbers
+        let mut lines = text.lines();
+        let tilt = lines
+            .find_map(|line| line.trim().strip_prefix("TILT="))
+            .ok_or_else(|| invalid("No TILT line"))?;
+        let mut numbers = lines.flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|word| !word.is_empty())
+            .map(|word| word.parse::<f64>().map_err(|_| invalid("Not a number")));
+        let mut next = || numbers.next().unwrap_or_else(|| Err(invalid("File ends too early")));
+
+        if tilt.trim() == "INCLUDE" {
+            // How the light changes as the lamp tilts, which we don't use
+            next()?;
+            let pairs = count(next()?)?;
+            for _ in 0..pairs.checked_mul(2).ok_or_else(|| invalid("Too many tilt angles"))? {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens = next()?;
+        let multiplier = next()?;
+        let vertical_count = count(next()?)?;
+        let horizontal_count = count(next()?)?;
+        let photometric_type = next()?;
+        for _ in 0..4 {
+            // Units and the size of the fixture
+            next()?;
+        }
+        let ballast_factor = next()?;
+        next()?;
+        next()?;
+        if photometric_type != 1.0 {
+            return Err(invalid("Only type C photometry is supported"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid("No angles"));
+        }
+
+        let vertical = (0..vertical_count).map(|_| next()).collect::<Result<Vec<_>>>()?;
+        let horizontal = (0..horizontal_count).map(|_| next()).collect::<Result<Vec<_>>>()?;
+        let increasing = |angles: &[f64]| angles.windows(2).all(|pair| pair[0] < pair[1]);
+        if !increasing(&vertical) || !increasing(&horizontal) {
+            return Err(invalid("Angles have to go up"));
+        }
+        let scale = multiplier * ballast_factor;
+        let candela = (0..horizontal_count)
+            .map(|_| (0..vertical_count).map(|_| Ok(scale * next()?)).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        let max = candela.iter().flatten().copied().fold(0.0, f64::max);
+        Ok(Self { vertical, horizontal, candela, max })
+    }
+
+    /// The brightest the fixture gets, in candela.
+    pub fn max_candela(&self) -> f64 {
+        self.max
+    }
+
+    /// Brightness at the angles in degrees, relative to the brightest direction, so
+    /// it goes from 0 to 1. Interpolated between the angles in the file like a
+    /// texture.
+    pub fn value(&self, vertical: f64, horizontal: f64) -> f64 {
+        if self.max <= 0.0 {
+            return 0.0;
+        }
+        let first = self.vertical[0];
+        let last = self.vertical[self.vertical.len() - 1];
+        if vertical < first || vertical > last {
+            return 0.0;
+        }
+
+        // Files only store the part of the fixture that isn't a mirror image of the
+        // rest, which the last horizontal angle tells
+        let horizontal = horizontal.rem_euclid(360.0);
+        let horizontal = match self.horizontal[self.horizontal.len() - 1] {
+            last if last <= 0.0 => 0.0,
+            last if last <= 90.0 => {
+                let half = if horizontal > 180.0 { 360.0 - horizontal } else { horizontal };
+                if half > 90.0 { 180.0 - half } else { half }
+            }
+            last if last <= 180.0 => if horizontal > 180.0 { 360.0 - horizontal } else { horizontal },
+            _ => horizontal,
+        };
+
+        let (h0, h1, th) = bracket(&self.horizontal, horizontal);
+        let (v0, v1, tv) = bracket(&self.vertical, vertical);
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let row = |h: usize| lerp(self.candela[h][v0], self.candela[h][v1], tv);
+        lerp(row(h0), row(h1), th) / self.max
+    }
+
+    /// Brightness towards `dir` of a fixture pointing along `down`, with horizontal
+    /// angle 0 towards `across`.
+    pub fn value_towards(&self, dir: Vec3, down: Vec3, across: Vec3) -> f64 {
+        let dir = dir.normalize();
+        let down = down.normalize();
+        let vertical = dir.dot(down).clamp(-1.0, 1.0).acos().to_degrees();
+        let across = (across - across.dot(down) * down).normalize();
+        let side = down.cross(across);
+        let horizontal = dir.dot(side).atan2(dir.dot(across)).to_degrees();
+        self.value(vertical, horizontal)
+    }
+}
+
+/// A number of things in the file, which has to be a whole number that isn't
+/// unreasonably large.
+fn count(value: f64) -> Result<usize> {
+    if !(0.0..=MAX_COUNT as f64).contains(&value) || value.fract() != 0.0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a valid count"));
+    }
+    Ok(value as usize)
+}
+
+/// Indices of the angles around `angle`, and how far it is from the first to the
+/// second.
+fn bracket(angles: &[f64], angle: f64) -> (usize, usize, f64) {
+    let high = angles.partition_point(|&a| a < angle).min(angles.len() - 1);
+    if high == 0 {
+        return (0, 0, 0.0);
+    }
+    let low = high - 1;
+    let t = ((angle - angles[low]) / (angles[high] - angles[low])).clamp(0.0, 1.0);
+    (low, high, t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A downlight that is twice as bright straight down as sideways, and dark above
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[MANUFAC] Test
+TILT=NONE
+1 1000 1 3 1 1 2 0.1 0.1 0
+1.0 1 50
+0 90 180
+0
+200 100 0
+";
+
+    #[test]
+    fn interpolates_vertical_angles() {
+        let profile = IesProfile::parse(DOWNLIGHT).unwrap();
+        assert_eq!(profile.max_candela(), 200.0);
+        assert_eq!(profile.value(0.0, 0.0), 1.0);
+        assert!((profile.value(45.0, 123.0) - 0.75).abs() < 1e-12);
+        assert_eq!(profile.value(90.0, 0.0), 0.5);
+        assert_eq!(profile.value(180.0, 0.0), 0.0);
+
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let across = Vec3::new(1.0, 0.0, 0.0);
+        assert_eq!(profile.value_towards(down, down, across), 1.0);
+        assert!((profile.value_towards(Vec3::new(0.0, 0.0, 1.0), down, across) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mirrors_quarter_profiles() {
+        // Bright along horizontal 0, dark along 90, numbers split over lines
+        let text = "TILT=NONE\n1 -1 2 2 2 1 2 0 0 0\n1 1 10\n0 90\n0 90\n4 4\n2\n2\n";
+        let profile = IesProfile::parse(text).unwrap();
+        assert_eq!(profile.max_candela(), 8.0);
+        for horizontal in [0.0, 180.0, 360.0, -180.0] {
+            assert!((profile.value(0.0, horizontal) - 1.0).abs() < 1e-12);
+        }
+        for horizontal in [90.0, 270.0, -90.0] {
+            assert!((profile.value(0.0, horizontal) - 0.5).abs() < 1e-12);
+        }
+        assert!((profile.value(0.0, 135.0) - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn skips_tilt_and_rejects_bad_files() {
+        let tilted = DOWNLIGHT.replace("TILT=NONE", "TILT=INCLUDE\n1\n2\n0 90\n1 0.5");
+        assert_eq!(IesProfile::parse(&tilted).unwrap().max_candela(), 200.0);
+        assert!(IesProfile::parse("no tilt").is_err());
+        for pairs in ["1e19", "-3", "2.7", "NaN"] {
+            let garbage = DOWNLIGHT.replace("TILT=NONE", &format!("TILT=INCLUDE\n1\n{}\n0 90\n1 0.5", pairs));
+            assert!(IesProfile::parse(&garbage).is_err(), "{}", pairs);
+        }
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("200 100 0", "200 100")).is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("1 1000 1 3 1 1", "1 1000 1 3 1 2")).is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("0 90 180", "0 180 90")).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+//! A ray tracer that renders scenes of shapes, meshes and volumes to images on the
+//! CPU. Build a `Scene`, look at it with a `Camera` and render it with a `Renderer`,
+//! or trace rays through the scene yourself with `Scene::hit`.
+
+pub mod vector;
+pub mod aabb;
+mod packet;
+mod bvh;
+mod light_bvh;
+mod bdpt;
+mod sppm;
+mod mlt;
+mod preview;
+pub mod ray;
+pub mod shapes;
+pub mod csg;
+pub mod sdf;
+pub mod scene;
+pub mod camera;
+mod util;
+pub mod material;
+pub mod texture;
+pub mod light;
+pub mod ies;
+pub mod sky;
+pub mod obj;
+pub mod medium;
+pub mod volume;
+pub mod spectrum;
+pub mod renderer;
+pub mod scenes;
+#[cfg(feature = "bench")]
+pub mod bench;
+
+pub use camera::Camera;
+pub use light::Light;
+pub use material::{Material, RefractiveIndex};
+pub use ray::Ray;
+pub use renderer::{Integrator, Renderer};
+pub use scene::Scene;
+pub use sky::Sky;
+pub use texture::Texture;
+pub use vector::Vec3;
@@ -0,0 +1,207 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+use crate::ies::IesProfile;
+use crate::util::random;
+use crate::vector::Vec3;
+
+/// Lights that are a single point or direction. Rays never hit them, so they only
+/// light the scene through shadow rays.
+#[derive(Clone, Debug)]
+pub enum Light {
+    /// Shines the same in every direction, falling off with the square of the
+    /// distance. `intensity` is per unit solid angle. A profile makes it shine like a
+    /// real fixture hanging straight down instead, with `intensity` as its brightest.
+    Point {
+        position: Vec3,
+        color: Vec3,
+        intensity: f64,
+        profile: Option<Arc<IesProfile>>,
+    },
+    /// A point light shining in a cone around `direction`, like a flashlight. Full
+    /// intensity within `inner_angle` of the direction, fading out to nothing at
+    /// `outer_angle`. Angles are in degrees. A profile pointing along `direction`
+    /// shapes the light within the cone.
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        color: Vec3,
+        intensity: f64,
+        inner_angle: f64,
+        outer_angle: f64,
+        profile: Option<Arc<IesProfile>>,
+    },
+    /// Light from very far away shining along `direction`, like the sun. `intensity`
+    /// is the irradiance on a surface facing it. An angular diameter in degrees
+    /// (about 0.53 for the sun) makes the edges of shadows soft.
+    Directional {
+        direction: Vec3,
+        color: Vec3,
+        intensity: f64,
+        angular_diameter: f64,
+    },
+}
+
+/// Light arriving at a point from one light.
+pub struct LightSample {
+    /// Normalized, from the point towards the light.
+    pub dir: Vec3,
+    /// How far the light is, infinite for directional lights.
+    pub distance: f64,
+    /// Irradiance on a surface facing the light, if nothing is in the way.
+    pub irradiance: Vec3,
+}
+
+impl Light {
+    /// Light reaching `point`, or none if the light doesn't shine there.
+    pub fn sample(&self, point: Vec3) -> Option<LightSample> {
+        match self {
+            Light::Point { position, color, intensity, profile } => {
+                let (dir, distance) = towards(point, *position)?;
+                let falloff = profile_value(profile, -dir, Vec3::new(0.0, -1.0, 0.0));
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(LightSample { dir, distance, irradiance: falloff * *intensity / (distance * distance) * *color })
+            }
+            Light::Spot { position, direction, color, intensity, inner_angle, outer_angle, profile } => {
+                let (dir, distance) = towards(point, *position)?;
+                let cos = (-dir).dot(direction.normalize());
+                let falloff = smoothstep(outer_angle.to_radians().cos(), inner_angle.to_radians().cos(), cos)
+                    * profile_value(profile, -dir, *direction);
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(LightSample { dir, distance, irradiance: falloff * *intensity / (distance * distance) * *color })
+            }
+            Light::Directional { direction, color, intensity, angular_diameter } => {
+                let to_light = -direction.normalize();
+                let dir = if *angular_diameter > 0.0 {
+                    // Uniformly random direction within the disk of the light
+                    let cos_max = (angular_diameter.to_radians() / 2.0).cos();
+                    let cos = 1.0 - random::<f64>() * (1.0 - cos_max);
+                    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                    let phi = 2.0 * PI * random::<f64>();
+                    let (u, v) = to_light.orthonormal_basis();
+                    (sin * phi.cos()) * u + (sin * phi.sin()) * v + cos * to_light
+                } else {
+                    to_light
+                };
+                Some(LightSample { dir, distance: f64::INFINITY, irradiance: *intensity * *color })
+            }
+        }
+    }
+}
+
+/// How bright a fixture pointing along `down` is towards `dir`, 1 without a profile.
+/// Horizontal angles start towards +x, or +z when pointing along x.
+fn profile_value(profile: &Option<Arc<IesProfile>>, dir: Vec3, down: Vec3) -> f64 {
+    match profile {
+        Some(profile) => {
+            let down = down.normalize();
+            let across = if down.x().abs() < 0.9 { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 0.0, 1.0) };
+            profile.value_towards(dir, down, across)
+        }
+        None => 1.0,
+    }
+}
+
+fn towards(point: Vec3, position: Vec3) -> Option<(Vec3, f64)> {
+    let offset = position - point;
+    let distance = offset.norm();
+    if distance <= 0.0 {
+        return None;
+    }
+    Some((offset / distance, distance))
+}
+
+/// Smooth step from 0 at `edge0` to 1 at `edge1`.
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge0 == edge1 {
+        return if x < edge0 { 0.0 } else { 1.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn white() -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
+
+    #[test]
+    fn point_falls_off_with_distance_squared() {
+        let light = Light::Point { position: Vec3::new(0.0, 2.0, 0.0), color: white(), intensity: 8.0, profile: None };
+        let sample = light.sample(Vec3::zero()).unwrap();
+        assert!((sample.dir - Vec3::new(0.0, 1.0, 0.0)).norm() < 1e-12);
+        assert_eq!(sample.distance, 2.0);
+        assert!((sample.irradiance.x() - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn spot_fades_between_cone_angles() {
+        let light = Light::Spot {
+            position: Vec3::zero(),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+            color: white(),
+            intensity: 1.0,
+            inner_angle: 20.0,
+            outer_angle: 40.0,
+            profile: None,
+        };
+        let at_angle = |degrees: f64| {
+            let angle = degrees.to_radians();
+            light.sample(Vec3::new(angle.sin(), -angle.cos(), 0.0)).map_or(0.0, |sample| sample.irradiance.x())
+        };
+        assert!((at_angle(0.0) - 1.0).abs() < 1e-12);
+        assert!((at_angle(19.0) - 1.0).abs() < 1e-12);
+        assert!(at_angle(30.0) > 0.0 && at_angle(30.0) < 1.0);
+        assert!(at_angle(25.0) > at_angle(35.0));
+        assert_eq!(at_angle(41.0), 0.0);
+        assert_eq!(at_angle(150.0), 0.0);
+    }
+
+    #[test]
+    fn profile_shapes_point_light() {
+        // Twice as bright straight down as sideways, and dark above
+        let text = "TILT=NONE\n1 1000 1 3 1 1 2 0 0 0\n1 1 50\n0 90 180\n0\n200 100 0\n";
+        let profile = Arc::new(IesProfile::parse(text).unwrap());
+        let light = Light::Point { position: Vec3::zero(), color: white(), intensity: 4.0, profile: Some(profile.clone()) };
+        let irradiance = |point: Vec3| light.sample(point).map_or(0.0, |sample| sample.irradiance.x());
+        assert!((irradiance(Vec3::new(0.0, -1.0, 0.0)) - 4.0).abs() < 1e-12);
+        assert!((irradiance(Vec3::new(1.0, 0.0, 0.0)) - 2.0).abs() < 1e-12);
+        assert_eq!(irradiance(Vec3::new(0.0, 1.0, 0.0)), 0.0);
+
+        // Pointing sideways, the profile turns with the spot
+        let spot = Light::Spot {
+            position: Vec3::zero(),
+            direction: Vec3::new(1.0, 0.0, 0.0),
+            color: white(),
+            intensity: 4.0,
+            inner_angle: 90.0,
+            outer_angle: 90.0,
+            profile: Some(profile),
+        };
+        assert!((spot.sample(Vec3::new(1.0, 0.0, 0.0)).unwrap().irradiance.x() - 4.0).abs() < 1e-12);
+        assert!(spot.sample(Vec3::new(0.0, -1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn directional_stays_within_its_disk() {
+        let direction = Vec3::new(1.0, -1.0, 0.0);
+        let light = Light::Directional { direction, color: white(), intensity: 3.0, angular_diameter: 10.0 };
+        let to_light = -direction.normalize();
+        let mut spread: f64 = 0.0;
+        for _ in 0..1000 {
+            let sample = light.sample(Vec3::new(5.0, 1.0, 2.0)).unwrap();
+            assert_eq!(sample.distance, f64::INFINITY);
+            assert_eq!(sample.irradiance.x(), 3.0);
+            let angle = sample.dir.dot(to_light).min(1.0).acos().to_degrees();
+            assert!(angle <= 5.0 + 1e-9);
+            spread = spread.max(angle);
+        }
+        assert!(spread > 4.0);
+    }
+}
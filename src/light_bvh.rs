@@ -0,0 +1,277 @@
+use std::f64::consts::PI;
+use crate::aabb::Aabb;
+use crate::util::random;
+use crate::vector::Vec3;
+
+/// Where a group of lights is, how much light it gives off and in which directions.
+/// Every light in the group shines within `theta_o` of `axis`, and the light fades
+/// out over another `theta_e` beyond that.
+#[derive(Debug, Copy, Clone)]
+pub struct LightBounds {
+    pub bounds: Aabb,
+    pub power: f64,
+    pub axis: Vec3,
+    pub theta_o: f64,
+    pub theta_e: f64,
+}
+
+impl LightBounds {
+    /// A light shining in every direction, like a sphere or a two-sided quad.
+    pub fn everywhere(bounds: Aabb, power: f64) -> Self {
+        Self { bounds, power, axis: Vec3::new(0.0, 0.0, 1.0), theta_o: PI, theta_e: PI / 2.0 }
+    }
+
+    /// A flat light only shining out of its front, towards `normal`.
+    pub fn facing(bounds: Aabb, power: f64, normal: Vec3) -> Self {
+        Self { bounds, power, axis: normal.normalize(), theta_o: 0.0, theta_e: PI / 2.0 }
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let (axis, theta_o) = cone_union((self.axis, self.theta_o), (other.axis, other.theta_o));
+        Self {
+            bounds: self.bounds.union(&other.bounds),
+            power: self.power + other.power,
+            axis,
+            theta_o,
+            theta_e: self.theta_e.max(other.theta_e),
+        }
+    }
+
+    /// Upper bound on how much light from the group could reach `point`, roughly,
+    /// as in "Importance Sampling of Many Lights" by Conty Estevez and Kulla. Zero
+    /// if none of it can. A surface with `normal` only takes light from its side.
+    fn importance(&self, point: Vec3, normal: Option<Vec3>) -> f64 {
+        let center = self.bounds.center();
+        let radius = 0.5 * self.bounds.size().norm();
+        let offset = point - center;
+        // Don't blow up close to or inside the bounds
+        let distance_sq = offset.norm_sq().max(radius * radius);
+        let distance = offset.norm();
+
+        // Half the angle the bounds take up seen from the point
+        let theta_b = if distance > radius { (radius / distance).asin() } else { PI };
+        let angle_between = |a: Vec3, b: Vec3| a.dot(b).clamp(-1.0, 1.0).acos();
+
+        // Closest the lights could be to shining straight at the point
+        let theta_w = if distance > 0.0 { angle_between(self.axis, offset / distance) } else { 0.0 };
+        let theta = (theta_w - self.theta_o - theta_b).max(0.0);
+        if theta >= self.theta_e {
+            return 0.0;
+        }
+        let mut importance = self.power * theta.cos().max(0.0) / distance_sq;
+
+        if let Some(normal) = normal {
+            let theta_i = if distance > 0.0 { angle_between(normal, -offset / distance) } else { 0.0 };
+            let theta_i = (theta_i - theta_b).max(0.0);
+            if theta_i >= PI / 2.0 {
+                return 0.0;
+            }
+            importance *= theta_i.cos();
+        }
+        importance
+    }
+}
+
+/// Smallest cone, as an axis and half angle, containing the two cones.
+fn cone_union(a: (Vec3, f64), b: (Vec3, f64)) -> (Vec3, f64) {
+    let theta_d = a.0.dot(b.0).clamp(-1.0, 1.0).acos();
+    if (theta_d + b.1).min(PI) <= a.1 {
+        return a;
+    }
+    if (theta_d + a.1).min(PI) <= b.1 {
+        return b;
+    }
+    let theta_o = (a.1 + theta_d + b.1) / 2.0;
+    if theta_o >= PI {
+        return (a.0, PI);
+    }
+    // Turn a's axis towards b's until the cone covers both
+    let rotation_axis = a.0.cross(b.0);
+    if rotation_axis.norm_sq() < 1e-12 {
+        return (a.0, PI);
+    }
+    let turn = theta_o - a.1;
+    let towards = rotation_axis.normalize().cross(a.0);
+    ((turn.cos() * a.0 + turn.sin() * towards).normalize(), theta_o)
+}
+
+enum Node {
+    // The first child is the next node, the second one is further on
+    Interior { bounds: LightBounds, second: usize },
+    Leaf { bounds: LightBounds, light: usize },
+}
+
+impl Node {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            Node::Interior { bounds, .. } | Node::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// Binary tree over lights, to pick one that probably lights a point well without
+/// looking at every light. Lights are referred to by their index in the slice the
+/// tree was built from.
+pub struct LightBvh {
+    nodes: Vec<Node>,
+    // Which way to go at each level to get to a light, first level in the lowest bit
+    trails: Vec<u64>,
+}
+
+impl LightBvh {
+    /// None if no light gives off any light.
+    pub fn new(lights: &[LightBounds]) -> Option<Self> {
+        let items: Vec<(usize, LightBounds)> = lights.iter().copied().enumerate().filter(|(_, light)| light.power > 0.0).collect();
+        if items.is_empty() {
+            return None;
+        }
+        let mut bvh = Self { nodes: Vec::new(), trails: vec![0; lights.len()] };
+        bvh.build(items, 0, 0);
+        Some(bvh)
+    }
+
+    fn build(&mut self, mut items: Vec<(usize, LightBounds)>, trail: u64, depth: u32) -> LightBounds {
+        let index = self.nodes.len();
+        if items.len() == 1 {
+            let (light, bounds) = items[0];
+            self.trails[light] = trail;
+            self.nodes.push(Node::Leaf { bounds, light });
+            return bounds;
+        }
+        // Halve at the median, like the BVH of the scene. That also keeps the tree
+        // shallow enough for the trails.
+        let centers: Vec<_> = items.iter().map(|(_, light)| light.bounds.center()).collect();
+        let spread = Aabb::from_points(&centers).size();
+        let axis = (0..3).max_by(|&a, &b| spread.axis(a).total_cmp(&spread.axis(b))).unwrap();
+        items.sort_by(|(_, a), (_, b)| a.bounds.center().axis(axis).total_cmp(&b.bounds.center().axis(axis)));
+        let upper = items.split_off(items.len() / 2);
+
+        // Filled in once the children are built
+        self.nodes.push(Node::Leaf { bounds: items[0].1, light: items[0].0 });
+        let first = self.build(items, trail, depth + 1);
+        let second = self.nodes.len();
+        let bounds = first.union(&self.build(upper, trail | 1 << depth, depth + 1));
+        self.nodes[index] = Node::Interior { bounds, second };
+        bounds
+    }
+
+    /// Chances of going to either child of an interior node.
+    fn split(&self, index: usize, second: usize, point: Vec3, normal: Option<Vec3>) -> Option<f64> {
+        let first = self.nodes[index + 1].bounds().importance(point, normal);
+        let second = self.nodes[second].bounds().importance(point, normal);
+        if first + second > 0.0 { Some(first / (first + second)) } else { None }
+    }
+
+    /// Pick a light for `point`, and the chance it was picked with.
+    pub fn sample(&self, point: Vec3, normal: Option<Vec3>) -> Option<(usize, f64)> {
+        if self.nodes[0].bounds().importance(point, normal) <= 0.0 {
+            return None;
+        }
+        let (mut index, mut probability) = (0, 1.0);
+        loop {
+            match self.nodes[index] {
+                Node::Leaf { light, .. } => return Some((light, probability)),
+                Node::Interior { second, .. } => {
+                    let chance = self.split(index, second, point, normal)?;
+                    if random::<f64>() < chance {
+                        index += 1;
+                        probability *= chance;
+                    } else {
+                        index = second;
+                        probability *= 1.0 - chance;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Chance of `sample` picking `light` for `point`.
+    pub fn probability(&self, light: usize, point: Vec3, normal: Option<Vec3>) -> f64 {
+        if self.nodes[0].bounds().importance(point, normal) <= 0.0 {
+            return 0.0;
+        }
+        let (mut index, mut probability, mut depth) = (0, 1.0, 0);
+        loop {
+            match self.nodes[index] {
+                Node::Leaf { light: found, .. } => return if found == light { probability } else { 0.0 },
+                Node::Interior { second, .. } => {
+                    let chance = match self.split(index, second, point, normal) {
+                        Some(chance) => chance,
+                        None => return 0.0,
+                    };
+                    if self.trails[light] >> depth & 1 == 0 {
+                        index += 1;
+                        probability *= chance;
+                    } else {
+                        index = second;
+                        probability *= 1.0 - chance;
+                    }
+                    depth += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    fn light_at(center: Vec3, power: f64) -> LightBounds {
+        let half = Vec3::new(0.1, 0.1, 0.1);
+        LightBounds::everywhere(Aabb::new(center - half, center + half), power)
+    }
+
+    #[test]
+    fn probabilities_match_sampling() {
+        util::seed(1);
+        let lights: Vec<_> = (0..20).map(|i| light_at(10.0 * Vec3::random(), 1.0 + i as f64)).collect();
+        let bvh = LightBvh::new(&lights).unwrap();
+        let point = Vec3::new(5.0, 5.0, 5.0);
+        let mut counts = vec![0; lights.len()];
+        let count = 100_000;
+        for _ in 0..count {
+            let (light, probability) = bvh.sample(point, None).unwrap();
+            assert!((probability - bvh.probability(light, point, None)).abs() < 1e-12);
+            counts[light] += 1;
+        }
+        let total: f64 = (0..lights.len()).map(|light| bvh.probability(light, point, None)).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        for (light, count_i) in counts.into_iter().enumerate() {
+            let expected = bvh.probability(light, point, None);
+            assert!((count_i as f64 / count as f64 - expected).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn prefers_close_and_facing_lights() {
+        let point = Vec3::zero();
+        let near = light_at(Vec3::new(1.0, 0.0, 0.0), 1.0);
+        let far = light_at(Vec3::new(-10.0, 0.0, 0.0), 1.0);
+        let bvh = LightBvh::new(&[near, far]).unwrap();
+        assert!(bvh.probability(0, point, None) > 0.9);
+
+        // Both facing away from the point, so neither is picked
+        let half = Vec3::new(0.5, 0.0, 0.5);
+        let above = |y: f64| Aabb::new(Vec3::new(0.0, y, 0.0) - half, Vec3::new(0.0, y, 0.0) + half);
+        let up = LightBounds::facing(above(1.0), 1.0, Vec3::new(0.0, 1.0, 0.0));
+        let bvh = LightBvh::new(&[up, LightBounds::facing(above(2.0), 1.0, Vec3::new(0.0, 1.0, 0.0))]).unwrap();
+        assert!(bvh.sample(point, None).is_none());
+        assert_eq!(bvh.probability(0, point, None), 0.0);
+
+        // And one below a surface facing up can't light it
+        let bvh = LightBvh::new(&[light_at(Vec3::new(0.0, 1.0, 0.0), 1.0), light_at(Vec3::new(0.0, -1.0, 0.0), 1.0)]).unwrap();
+        assert_eq!(bvh.probability(0, point, Some(Vec3::new(0.0, 1.0, 0.0))), 1.0);
+        assert_eq!(bvh.probability(1, point, Some(Vec3::new(0.0, 1.0, 0.0))), 0.0);
+    }
+
+    #[test]
+    fn cone_union_covers_both() {
+        let (axis, theta) = cone_union((Vec3::new(1.0, 0.0, 0.0), 0.0), (Vec3::new(0.0, 1.0, 0.0), 0.0));
+        assert!((theta - PI / 4.0).abs() < 1e-9);
+        assert!((axis - Vec3::new(1.0, 1.0, 0.0).normalize()).norm() < 1e-9);
+        let (_, theta) = cone_union((Vec3::new(1.0, 0.0, 0.0), 0.0), (Vec3::new(-1.0, 0.0, 0.0), 0.0));
+        assert_eq!(theta, PI);
+    }
+}
@@ -1,6 +1,9 @@
-use rand::random;
+use std::f64::consts::PI;
+use crate::medium::Medium;
 use crate::ray::Ray;
 use crate::shapes::HitResult;
+use crate::texture::Texture;
+use crate::util::random;
 use crate::vector::Vec3;
 
 #[derive(Clone, Debug)]
@@ -13,110 +16,366 @@ pub enum Material {
         fuzz: f64,
     },
     Glass {
-        refractive_index: f64,
+        refractive_index: RefractiveIndex,
+        // Fraction of light left after travelling one unit of distance inside the glass
+        transmittance: Vec3,
+        // Infinitely thin sheet, like a window pane. Rays pass straight through.
+        thin_walled: bool,
+        // Scattering inside the glass, for things like murky water or jade
+        medium: Option<Medium>,
+    },
+    // Invisible boundary of a volume, like a cloud of smoke
+    Volume {
+        medium: Medium,
     },
     Light {
         color: Vec3,
         intensity: f64,
+    },
+    // Light that varies across the surface, like a screen or a neon sign. One sided
+    // lights only shine from the front face, which for meshes is the side the
+    // vertices go counter-clockwise around.
+    Emissive {
+        emission: Texture,
+        intensity: f64,
+        one_sided: bool,
+    },
+}
+
+/// How much light slows down in a material. Real glass bends short wavelengths more
+/// than long ones, which is what splits white light into a rainbow.
+#[derive(Clone, Debug)]
+pub enum RefractiveIndex {
+    Constant(f64),
+    // n = a + b / λ², with λ in micrometers
+    Cauchy {
+        a: f64,
+        b: f64,
+    },
+    // n² = 1 + Σ bᵢλ² / (λ² - cᵢ), with λ in micrometers
+    Sellmeier {
+        b: [f64; 3],
+        c: [f64; 3],
+    },
+}
+
+// Wavelength used when rendering without spectral sampling (sodium D line)
+const DEFAULT_WAVELENGTH: f64 = 589.3;
+
+impl RefractiveIndex {
+    // Common borosilicate crown glass
+    pub const BK7: RefractiveIndex = RefractiveIndex::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+    // Dense flint glass, disperses a lot more than BK7
+    pub const SF11: RefractiveIndex = RefractiveIndex::Sellmeier {
+        b: [1.73759695, 0.313747346, 1.89878101],
+        c: [0.013188707, 0.0623068142, 155.23629],
+    };
+    pub const DIAMOND: RefractiveIndex = RefractiveIndex::Sellmeier {
+        b: [0.3306, 4.3356, 0.0],
+        c: [0.030625, 0.011236, 0.0],
+    };
+
+    /// Refractive index at a wavelength in nanometers.
+    pub fn at(&self, wavelength: f64) -> f64 {
+        let micrometers = wavelength / 1000.0;
+        let l2 = micrometers * micrometers;
+        match self {
+            RefractiveIndex::Constant(n) => *n,
+            RefractiveIndex::Cauchy { a, b } => a + b / l2,
+            RefractiveIndex::Sellmeier { b, c } => {
+                let mut n2 = 1.0;
+                for i in 0..3 {
+                    n2 += b[i] * l2 / (l2 - c[i]);
+                }
+                n2.sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, RefractiveIndex::Constant(_))
     }
 }
 
 impl Material {
     pub fn scatter(&self, ray: Ray, hit_result: &HitResult) -> Option<Scatter> {
-        return Some(match self {
+        self.scatter_wavelength(ray, hit_result, None)
+    }
+
+    /// Scatter a ray carrying a specific wavelength in nanometers. Materials that
+    /// depend on the wavelength mark the scatter as dispersive.
+    pub fn scatter_wavelength(&self, ray: Ray, hit_result: &HitResult, wavelength: Option<f64>) -> Option<Scatter> {
+        Some(match self {
             Material::Diffuse { color } => {
+                // Points on a sphere touching the surface are spread like the cosine
+                // (Lambert's law)
                 let normal = hit_result.normal();
-                let mut bounce_dir = normal + Vec3::random().normalize();
+                let mut bounce_dir = normal + Vec3::random_unit();
 
                 // Avoid division by zero and other problems
                 if bounce_dir.is_near_zero() {
                     bounce_dir = normal;
                 }
 
-                let bounce_ray = Ray::new(hit_result.hit_point(), bounce_dir);
+                let bounce_ray = hit_result.spawn_ray(bounce_dir);
                 Scatter {
                     ray: bounce_ray,
-                    attenuation: *color
+                    attenuation: *color,
+                    dispersive: false,
                 }
             }
             Material::Metal { color, fuzz } => {
-                let reflected = ray.dir().reflect(hit_result.normal());
-                let mut fuzz_vector = Vec3::random();
-                fuzz_vector = (fuzz / fuzz_vector.norm()) * fuzz_vector;
-                let dir = reflected + fuzz_vector;
-                if dir.dot(hit_result.normal()) < 0.0 {
-                    return None;
+                let normal = hit_result.normal();
+                let reflected = ray.dir().normalize().reflect(normal);
+                // Uniformly random point in a ball around the reflection
+                let fuzz_vector = random::<f64>().cbrt() * Vec3::random_unit();
+                let mut dir = reflected + *fuzz * fuzz_vector;
+                if dir.is_near_zero() {
+                    dir = normal;
+                }
+                // Fuzz pointing into the surface is mirrored back out instead of
+                // losing the light
+                if dir.dot(normal) < 0.0 {
+                    dir = dir.reflect(normal);
                 }
-                let ray = Ray::new(hit_result.hit_point(), dir);
+                let cos_in = -ray.dir().normalize().dot(normal);
+                let cos_out = dir.normalize().dot(normal);
                 Scatter {
-                    ray,
-                    attenuation: *color
+                    ray: hit_result.spawn_ray(dir),
+                    attenuation: cos_out / cos_in.max(cos_out) * *color,
+                    dispersive: false,
                 }
             }
-            Material::Glass { refractive_index } => {
-                if random::<f64>() > 0.90 {
-                    let reflected = ray.dir().reflect(hit_result.normal());
-                    if reflected.dot(hit_result.normal()) < 0.0 {
-                        return None;
+            Material::Glass { refractive_index, transmittance, thin_walled, .. } => {
+                let dir = ray.dir().normalize();
+                let normal = hit_result.normal();
+                let cos = (-dir).dot(normal).min(1.0);
+                let dispersive = wavelength.is_some() && refractive_index.is_dispersive();
+                let refractive_index = refractive_index.at(wavelength.unwrap_or(DEFAULT_WAVELENGTH));
+
+                if *thin_walled {
+                    // Both faces of the sheet reflect, and light bouncing between them
+                    // eventually leaves through one of the sides.
+                    let mut reflect_chance = fresnel(cos, refractive_index);
+                    if reflect_chance < 1.0 {
+                        reflect_chance = 2.0 * reflect_chance / (1.0 + reflect_chance);
                     }
-                    let ray = Ray::new(hit_result.hit_point(), reflected);
+                    let (bounce_dir, attenuation) = if random::<f64>() < reflect_chance {
+                        (dir.reflect(normal), Vec3::new(1.0, 1.0, 1.0))
+                    } else {
+                        (dir, *transmittance)
+                    };
                     return Some(Scatter {
-                        ray,
-                        attenuation: Vec3::new(1.0, 1.0, 1.0)
+                        ray: hit_result.spawn_ray(bounce_dir),
+                        attenuation,
+                        dispersive,
                     });
                 }
-                let refraction_ratio = if hit_result.front_face() {
-                    1.0 / refractive_index
+
+                // Refractive index on the other side relative to this side
+                let eta = if hit_result.front_face() {
+                    refractive_index
                 } else {
-                    *refractive_index
+                    1.0 / refractive_index
                 };
 
-                let dir = ray.dir().normalize();
-                let normal = hit_result.normal();
-                let cos = (-dir).dot(normal).min(1.0);
-                let sin = (1.0 - cos * cos).sqrt();
-
-                let rand: f64 = random();
-                let bounce_dir = if refraction_ratio * sin > 1.0 || reflectance(cos, refraction_ratio) > rand {
-                    // Total internal reflection
+                let bounce_dir = if random::<f64>() < fresnel(cos, eta) {
+                    // Also covers total internal reflection
                     dir.reflect(normal)
                 } else {
-                    dir.refract(normal, refraction_ratio)
+                    dir.refract(normal, 1.0 / eta)
+                };
+
+                // Hitting the glass from the inside means the ray has travelled through
+                // it, so some light was absorbed along the way (Beer-Lambert).
+                let attenuation = if hit_result.front_face() {
+                    Vec3::new(1.0, 1.0, 1.0)
+                } else {
+                    let distance = hit_result.t() * ray.dir().norm();
+                    transmittance.powf(distance)
                 };
 
-                let ray = Ray::new(hit_result.hit_point(), bounce_dir);
+                let ray = hit_result.spawn_ray(bounce_dir);
 
                 Scatter {
                     ray,
-                    attenuation: Vec3::new(1.0, 1.0, 1.0)
+                    attenuation,
+                    dispersive,
+                }
+            }
+            Material::Volume { .. } => {
+                Scatter {
+                    ray: hit_result.spawn_ray(ray.dir()),
+                    attenuation: Vec3::new(1.0, 1.0, 1.0),
+                    dispersive: false,
                 }
             }
-            Material::Light { .. } => {
+            Material::Light { .. } | Material::Emissive { .. } => {
                 return None
             }
         })
     }
 
-    pub fn get_light(&self) -> Vec3 {
+    /// Density over solid angle of `scatter` sending a ray coming in along `dir_in`
+    /// out along `dir_out`, both normalized, with `normal` facing the incoming ray.
+    /// Zero for materials that only scatter into single directions, like glass.
+    pub fn pdf(&self, dir_in: Vec3, normal: Vec3, dir_out: Vec3) -> f64 {
+        let cos_out = dir_out.dot(normal);
+        if cos_out <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            Material::Diffuse { .. } => cos_out / PI,
+            Material::Metal { fuzz, .. } if *fuzz > 0.0 => {
+                let reflected = dir_in.reflect(normal);
+                fuzz_pdf(reflected, *fuzz, dir_out) + fuzz_pdf(reflected, *fuzz, dir_out.reflect(normal))
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// How much of the light arriving from `dir_out` leaves along `-dir_in` (the
+    /// BSDF), in the same directions as `pdf`. Sampling with `scatter` gives an
+    /// attenuation of `eval * cos / pdf`, with `cos` the cosine of `dir_out`.
+    pub fn eval(&self, dir_in: Vec3, normal: Vec3, dir_out: Vec3) -> Vec3 {
+        let (cos_in, cos_out) = (-dir_in.dot(normal), dir_out.dot(normal));
+        if cos_in <= 0.0 || cos_out <= 0.0 {
+            return Vec3::zero();
+        }
+        match self {
+            Material::Diffuse { color } => *color / PI,
+            // Dividing by the larger cosine like Ashikhmin-Shirley keeps it
+            // reciprocal without ever reflecting more than comes in
+            Material::Metal { color, .. } => self.pdf(dir_in, normal, dir_out) / cos_in.max(cos_out) * *color,
+            _ => Vec3::zero(),
+        }
+    }
+
+    /// Whether `scatter` only ever picks single directions, like mirrors and glass.
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Material::Glass { .. } => true,
+            Material::Metal { fuzz, .. } => *fuzz <= 0.0,
+            _ => false,
+        }
+    }
+
+    /// The medium filling the inside of shapes with this material.
+    pub fn interior(&self) -> Option<&Medium> {
+        match self {
+            Material::Glass { medium, .. } => medium.as_ref(),
+            Material::Volume { medium } => Some(medium),
+            _ => None,
+        }
+    }
+
+    /// Light given off at a hit, towards where the ray came from.
+    pub fn emitted(&self, hit_result: &HitResult) -> Vec3 {
         match self {
             Material::Light { color, intensity } => {
                 *intensity * *color
             },
+            Material::Emissive { emission, intensity, one_sided } => {
+                if *one_sided && !hit_result.front_face() {
+                    return Vec3::zero();
+                }
+                *intensity * emission.value(hit_result.uv())
+            }
             _ => {
                 Vec3::zero()
             }
         }
     }
+
+    /// Roughly how much light in total a surface of `area` made of this material
+    /// gives off, as luminance. Used to pick bright lights more often.
+    pub fn power(&self, area: f64) -> f64 {
+        // Light going out evenly over a hemisphere, per side
+        let (average, sides) = match self {
+            Material::Light { color, intensity } => (*intensity * *color, 2.0),
+            Material::Emissive { emission, intensity, one_sided } => {
+                (*intensity * emission.average(), if *one_sided { 1.0 } else { 2.0 })
+            }
+            _ => return 0.0,
+        };
+        PI * sides * area * average.luminance().max(0.0)
+    }
+}
+
+/// Density over solid angle of `dir` pointing at a uniformly random point in a ball
+/// of radius `fuzz` around the tip of the unit vector `reflected`. That's the volume
+/// of the ball in the cone the solid angle makes, ∫ t² dt where `dir` is inside it.
+fn fuzz_pdf(reflected: Vec3, fuzz: f64, dir: Vec3) -> f64 {
+    let cos = dir.dot(reflected);
+    // Distances t along dir to the surface solve t² - 2 t cos + 1 - fuzz² = 0
+    let discriminant = cos * cos - 1.0 + fuzz * fuzz;
+    if discriminant <= 0.0 {
+        return 0.0;
+    }
+    let sqrt = discriminant.sqrt();
+    let (near, far) = ((cos - sqrt).max(0.0), (cos + sqrt).max(0.0));
+    let volume = 4.0 / 3.0 * PI * fuzz * fuzz * fuzz;
+    (far.powi(3) - near.powi(3)) / 3.0 / volume
 }
 
-fn reflectance(cos: f64, refractive_index: f64) -> f64 {
-    // More magic maths. Schlick's approximation for reflectance.
-    let mut r0 = (1.0 - refractive_index) / (1.0 + refractive_index);
-    r0 = r0 * r0;
-    return r0 + (1.0 - r0) * (1.0 - cos).powi(5);
+/// Fraction of light reflected when going from one dielectric into another, where
+/// `eta` is the refractive index on the other side relative to this side.
+fn fresnel(cos: f64, eta: f64) -> f64 {
+    let cos_i = cos.clamp(0.0, 1.0);
+    // Snell's law
+    let sin_t_sq = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin_t_sq >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t_sq).sqrt();
+
+    // Fresnel equations for parallel and perpendicular polarized light
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
 }
 
 pub struct Scatter {
     pub ray: Ray,
     pub attenuation: Vec3,
-}
\ No newline at end of file
+    // The direction depends on the wavelength, so only that wavelength can follow it
+    pub dispersive: bool,
+}
+
+#[cfg(test)]
+mod validation;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bk7_refractive_index() {
+        // Catalog value at the helium d line
+        assert!((RefractiveIndex::BK7.at(587.6) - 1.5168).abs() < 1e-4);
+        assert!(RefractiveIndex::BK7.at(450.0) > RefractiveIndex::BK7.at(650.0));
+    }
+
+    #[test]
+    fn fresnel_head_on() {
+        // ((n - 1) / (n + 1))^2 at normal incidence
+        assert!((fresnel(1.0, 1.5) - 0.04).abs() < 1e-9);
+        assert!((fresnel(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fresnel_grazing() {
+        assert!((fresnel(0.0, 1.5) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fresnel_total_internal_reflection() {
+        // Critical angle for glass to air is about 41.8 degrees
+        assert_eq!(fresnel(43f64.to_radians().cos(), 1.0 / 1.5), 1.0);
+        assert!(fresnel(40f64.to_radians().cos(), 1.0 / 1.5) < 1.0);
+    }
+}
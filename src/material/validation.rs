@@ -0,0 +1,226 @@
+// Statistical checks that each material samples directions the way its `pdf` says,
+// agrees with its `eval`, and doesn't reflect more light than comes in.
+
+use super::*;
+use crate::util::seed;
+
+const SAMPLES: usize = 100_000;
+const THETA_BINS: usize = 16;
+const PHI_BINS: usize = 32;
+// A correct material fails with a chance of about 1e-4
+const Z_LIMIT: f64 = 3.7;
+
+fn white() -> Vec3 {
+    Vec3::new(1.0, 1.0, 1.0)
+}
+
+/// Materials that scatter into a spread of directions, with a `pdf`.
+fn materials() -> Vec<Material> {
+    let mut materials = vec![Material::Diffuse { color: white() }];
+    for fuzz in [0.3, 0.5, 1.0, 1.5] {
+        materials.push(Material::Metal { color: white(), fuzz });
+    }
+    materials
+}
+
+fn glass(thin_walled: bool) -> Material {
+    Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: white(), thin_walled, medium: None }
+}
+
+/// Straight down onto the surface, at 45° and almost grazing it. The surface is
+/// the xy plane with the normal along z.
+fn incoming() -> [Vec3; 3] {
+    [0.0f64, 45.0, 80.0].map(|degrees| {
+        let angle = degrees.to_radians();
+        Vec3::new(angle.sin(), 0.0, -angle.cos())
+    })
+}
+
+fn normal() -> Vec3 {
+    Vec3::new(0.0, 0.0, 1.0)
+}
+
+/// Direction and attenuation of the scattered ray, after coming in along `dir_in`.
+fn sample(material: &Material, dir_in: Vec3) -> Option<(Vec3, Vec3)> {
+    let ray = Ray::new(-dir_in, dir_in);
+    let hit_result = HitResult::new(ray, 1.0, normal(), (0.0, 0.0), material);
+    material.scatter(ray, &hit_result).map(|scatter| (scatter.ray.dir().normalize(), scatter.attenuation))
+}
+
+fn direction(theta: f64, phi: f64) -> Vec3 {
+    Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+}
+
+fn bin(dir: Vec3) -> usize {
+    let theta = dir.z().clamp(-1.0, 1.0).acos();
+    let phi = dir.y().atan2(dir.x()).rem_euclid(2.0 * PI);
+    let theta_bin = ((theta / PI * THETA_BINS as f64) as usize).min(THETA_BINS - 1);
+    let phi_bin = ((phi / (2.0 * PI) * PHI_BINS as f64) as usize).min(PHI_BINS - 1);
+    theta_bin * PHI_BINS + phi_bin
+}
+
+/// Adaptive Simpson's rule. Starts from a few pieces so narrow lobes aren't missed,
+/// and splits them further where the pdf bends sharply, like at the edge of a lobe.
+fn integrate(f: &dyn Fn(f64) -> f64, a: f64, b: f64, tolerance: f64) -> f64 {
+    fn simpson(f: &dyn Fn(f64) -> f64, (a, b): (f64, f64), [fa, fm, fb]: [f64; 3], whole: f64, tolerance: f64, depth: u32) -> f64 {
+        let m = (a + b) / 2.0;
+        let (left_m, right_m) = ((a + m) / 2.0, (m + b) / 2.0);
+        let (f_left, f_right) = (f(left_m), f(right_m));
+        let left = (m - a) / 6.0 * (fa + 4.0 * f_left + fm);
+        let right = (b - m) / 6.0 * (fm + 4.0 * f_right + fb);
+        let error = left + right - whole;
+        if depth == 0 || error.abs() <= 15.0 * tolerance {
+            return left + right + error / 15.0;
+        }
+        simpson(f, (a, m), [fa, f_left, fm], left, tolerance / 2.0, depth - 1)
+            + simpson(f, (m, b), [fm, f_right, fb], right, tolerance / 2.0, depth - 1)
+    }
+    let pieces = 4;
+    let width = (b - a) / pieces as f64;
+    (0..pieces)
+        .map(|i| {
+            let (a, b) = (a + i as f64 * width, a + (i + 1) as f64 * width);
+            let (fa, fm, fb) = (f(a), f((a + b) / 2.0), f(b));
+            simpson(f, (a, b), [fa, fm, fb], width / 6.0 * (fa + 4.0 * fm + fb), tolerance / pieces as f64, 10)
+        })
+        .sum()
+}
+
+/// Chance of a sample landing in each bin, from integrating the pdf over it.
+fn bin_probabilities(material: &Material, dir_in: Vec3) -> Vec<f64> {
+    let (d_theta, d_phi) = (PI / THETA_BINS as f64, 2.0 * PI / PHI_BINS as f64);
+    let mut probabilities = Vec::with_capacity(THETA_BINS * PHI_BINS);
+    for i in 0..THETA_BINS {
+        for j in 0..PHI_BINS {
+            let (theta, phi) = (i as f64 * d_theta, j as f64 * d_phi);
+            let ring = |phi: f64| {
+                let pdf = |theta: f64| material.pdf(dir_in, normal(), direction(theta, phi)) * theta.sin();
+                integrate(&pdf, theta, theta + d_theta, 1e-7)
+            };
+            probabilities.push(integrate(&ring, phi, phi + d_phi, 1e-6));
+        }
+    }
+    probabilities
+}
+
+/// Pearson's chi-square test of where the samples land against where the pdf says
+/// they should, as a z-score using the Wilson-Hilferty approximation.
+fn chi_square_z(material: &Material, dir_in: Vec3) -> f64 {
+    let mut observed = vec![0.0; THETA_BINS * PHI_BINS + 1];
+    for _ in 0..SAMPLES {
+        // The last bin is for rays that got absorbed
+        let index = sample(material, dir_in).map_or(THETA_BINS * PHI_BINS, |(dir, _)| bin(dir));
+        observed[index] += 1.0;
+    }
+    let mut expected: Vec<f64> = bin_probabilities(material, dir_in).iter().map(|p| p * SAMPLES as f64).collect();
+    expected.push((SAMPLES as f64 - expected.iter().sum::<f64>()).max(0.0));
+
+    // Bins expecting only a few samples are lumped together, the test needs more
+    let (mut statistic, mut bins) = (0.0, 0);
+    let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+    for (observed, expected) in observed.into_iter().zip(expected) {
+        if expected < 5.0 {
+            pooled_observed += observed;
+            pooled_expected += expected;
+        } else {
+            statistic += (observed - expected) * (observed - expected) / expected;
+            bins += 1;
+        }
+    }
+    if pooled_observed > 0.0 || pooled_expected > 0.0 {
+        statistic += (pooled_observed - pooled_expected) * (pooled_observed - pooled_expected) / pooled_expected.max(1.0);
+        bins += 1;
+    }
+    let dof = (bins - 1) as f64;
+    let variance = 2.0 / (9.0 * dof);
+    ((statistic / dof).cbrt() - (1.0 - variance)) / variance.sqrt()
+}
+
+#[test]
+fn sampling_matches_pdf() {
+    for material in materials() {
+        for dir_in in incoming() {
+            seed(1);
+            let z = chi_square_z(&material, dir_in);
+            assert!(z < Z_LIMIT, "{:?} coming in along {:?}: z = {}", material, dir_in, z);
+        }
+    }
+}
+
+#[test]
+fn attenuation_matches_eval() {
+    seed(1);
+    for material in materials() {
+        for dir_in in incoming() {
+            for _ in 0..1000 {
+                let (dir_out, attenuation) = sample(&material, dir_in).unwrap();
+                let pdf = material.pdf(dir_in, normal(), dir_out);
+                let expected = dir_out.dot(normal()) / pdf * material.eval(dir_in, normal(), dir_out);
+                assert!((attenuation - expected).norm() < 1e-6, "{:?}: {:?} != {:?}", material, attenuation, expected);
+            }
+        }
+    }
+}
+
+/// White materials in a white furnace: lit the same from everywhere, they can at
+/// most look as bright as the light.
+#[test]
+fn white_furnace() {
+    seed(1);
+    let mut all = materials();
+    all.extend([glass(false), glass(true)]);
+    for material in all {
+        for dir_in in incoming() {
+            let mut total = 0.0;
+            for _ in 0..10_000 {
+                let attenuation = sample(&material, dir_in).map_or(Vec3::zero(), |(_, attenuation)| attenuation);
+                assert!(attenuation.x() <= 1.0 + 1e-9, "{:?} reflects {:?}", material, attenuation);
+                total += attenuation.x();
+            }
+            let albedo = total / 10_000.0;
+            // Nothing absorbs light but the fuzzy metal at grazing angles
+            if !matches!(material, Material::Metal { .. }) {
+                assert!((albedo - 1.0).abs() < 1e-9, "{:?} has albedo {}", material, albedo);
+            }
+        }
+    }
+}
+
+/// Light going the other way is reflected just as much.
+#[test]
+fn reciprocity() {
+    seed(1);
+    for material in materials() {
+        for _ in 0..1000 {
+            let (a, b) = (Vec3::random_on_hemisphere(normal()), Vec3::random_on_hemisphere(normal()));
+            let forward = material.eval(-a, normal(), b);
+            let backward = material.eval(-b, normal(), a);
+            assert!((forward - backward).norm() <= 1e-9 * forward.norm(), "{:?}: {:?} != {:?}", material, forward, backward);
+        }
+    }
+}
+
+/// Glass either reflects like a mirror or refracts to the other side, as often as
+/// the Fresnel equations say.
+#[test]
+fn glass_reflects_or_refracts() {
+    seed(1);
+    let material = glass(false);
+    for dir_in in incoming() {
+        let cos = -dir_in.dot(normal());
+        let mirrored = dir_in.reflect(normal());
+        let refracted = dir_in.refract(normal(), 1.0 / 1.5).normalize();
+        let mut reflections = 0;
+        for _ in 0..SAMPLES {
+            let (dir_out, _) = sample(&material, dir_in).unwrap();
+            if (dir_out - mirrored).norm() < 1e-9 {
+                reflections += 1;
+            } else {
+                assert!((dir_out - refracted).norm() < 1e-9, "{:?} isn't {:?}", dir_out, refracted);
+            }
+        }
+        let chance = fresnel(cos, 1.5);
+        let deviation = (SAMPLES as f64 * chance * (1.0 - chance)).sqrt();
+        assert!((reflections as f64 - SAMPLES as f64 * chance).abs() < 4.0 * deviation + 1.0);
+    }
+}
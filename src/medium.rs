@@ -0,0 +1,249 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::spectrum::blackbody_rgb;
+use crate::util::random;
+use crate::vector::Vec3;
+use crate::volume::ScalarField;
+
+/// A volume of particles that absorb and scatter light, like fog, smoke or murky
+/// water. Coefficients are per unit of distance.
+#[derive(Clone, Debug)]
+pub struct Medium {
+    sigma_a: Vec3,
+    sigma_s: Vec3,
+    // Henyey-Greenstein asymmetry, -1 scatters backwards, 0 evenly, 1 forwards
+    g: f64,
+    // Scales the coefficients at each point. Uniform when there is none.
+    density: Option<Arc<ScalarField>>,
+    // Temperature in Kelvin is the value times the scale. Absorbing particles glow
+    // like a blackbody at that temperature.
+    temperature: Option<Arc<ScalarField>>,
+    temperature_scale: f64,
+    emission_intensity: f64,
+}
+
+pub struct MediumSample {
+    pub event: MediumEvent,
+    // Light emitted towards the ray origin before the event
+    pub emission: Vec3,
+}
+
+pub enum MediumEvent {
+    // The ray made it through the medium to the end of the range
+    Pass { weight: Vec3 },
+    // The ray was scattered by a particle at this point
+    Scatter { point: Vec3, weight: Vec3 },
+    Absorb,
+}
+
+impl Medium {
+    pub fn new(sigma_a: Vec3, sigma_s: Vec3, g: f64) -> Self {
+        Self {
+            sigma_a,
+            sigma_s,
+            g: g.clamp(-0.99, 0.99),
+            density: None,
+            temperature: None,
+            temperature_scale: 1.0,
+            emission_intensity: 0.0,
+        }
+    }
+
+    /// A medium whose coefficients vary with the density, like smoke or clouds.
+    pub fn heterogeneous(sigma_a: Vec3, sigma_s: Vec3, g: f64, density: ScalarField) -> Self {
+        Self {
+            density: Some(Arc::new(density)),
+            ..Self::new(sigma_a, sigma_s, g)
+        }
+    }
+
+    /// Make the medium glow based on its temperature, for fire and explosions.
+    pub fn with_blackbody(self, temperature: ScalarField, temperature_scale: f64, intensity: f64) -> Self {
+        Self {
+            temperature: Some(Arc::new(temperature)),
+            temperature_scale,
+            emission_intensity: intensity,
+            ..self
+        }
+    }
+
+    /// The region outside of which the medium is empty, if it is not uniform.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.density.as_ref().map(|density| density.bounds())
+    }
+
+    fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    fn majorant(&self) -> f64 {
+        let sigma_t = self.sigma_t();
+        let density_max = self.density.as_ref().map_or(1.0, |density| density.max());
+        sigma_t.x().max(sigma_t.y()).max(sigma_t.z()) * density_max
+    }
+
+    fn density_at(&self, point: Vec3) -> f64 {
+        self.density.as_ref().map_or(1.0, |density| density.value(point))
+    }
+
+    fn emission_at(&self, point: Vec3) -> Vec3 {
+        match &self.temperature {
+            Some(temperature) => {
+                let kelvin = temperature.value(point) * self.temperature_scale;
+                self.emission_intensity * blackbody_rgb(kelvin)
+            }
+            None => Vec3::zero(),
+        }
+    }
+
+    /// Find where a ray interacts with the medium using spectral delta tracking, so
+    /// colored coefficients work without tracing each channel separately. The
+    /// majorant bounds the density everywhere, and collisions with the difference are
+    /// null collisions that the ray continues through.
+    pub fn sample(&self, ray: Ray, t_max: f64) -> MediumSample {
+        let majorant = self.majorant();
+        let mut weight = Vec3::new(1.0, 1.0, 1.0);
+        let mut emission = Vec3::zero();
+        if majorant <= 0.0 {
+            return MediumSample { event: MediumEvent::Pass { weight }, emission };
+        }
+
+        // Only track where there is density
+        let (t_min, t_max) = match self.bounds() {
+            Some(bounds) => match bounds.hit_range(ray, 0.0..t_max) {
+                Some((t_enter, _, t_exit, _)) => (t_enter, t_exit),
+                None => return MediumSample { event: MediumEvent::Pass { weight }, emission },
+            },
+            None => (0.0, t_max),
+        };
+
+        // Track in distance rather than ray parameter
+        let dir_len = ray.dir().norm();
+        let distance_max = t_max * dir_len;
+        let mut distance = t_min * dir_len;
+        loop {
+            distance -= (1.0 - random::<f64>()).ln() / majorant;
+            if distance >= distance_max {
+                return MediumSample { event: MediumEvent::Pass { weight }, emission };
+            }
+
+            let point = ray.at(distance / dir_len);
+            let density = self.density_at(point);
+            let sigma_a = density * self.sigma_a;
+            let sigma_s = density * self.sigma_s;
+            let sigma_n = Vec3::new(majorant, majorant, majorant) - density * self.sigma_t();
+
+            if self.temperature.is_some() {
+                emission = emission + weight * sigma_a * self.emission_at(point) / majorant;
+            }
+
+            let p_absorb = max_component(sigma_a * weight);
+            let p_scatter = max_component(sigma_s * weight);
+            let p_null = max_component(sigma_n * weight);
+            let total = p_absorb + p_scatter + p_null;
+            if total <= 0.0 {
+                return MediumSample { event: MediumEvent::Absorb, emission };
+            }
+
+            let rand = random::<f64>() * total;
+            if rand < p_absorb {
+                return MediumSample { event: MediumEvent::Absorb, emission };
+            } else if rand < p_absorb + p_scatter {
+                weight = weight * sigma_s / (majorant * p_scatter / total);
+                return MediumSample { event: MediumEvent::Scatter { point, weight }, emission };
+            }
+            // Null collision, keep going
+            weight = weight * sigma_n / (majorant * p_null / total);
+        }
+    }
+
+    /// Pick a new direction for a ray travelling in `dir` that was scattered.
+    pub fn sample_phase(&self, dir: Vec3) -> Vec3 {
+        let g = self.g;
+        let rand = random::<f64>();
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0 * rand
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * rand);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * random::<f64>();
+
+        let dir = dir.normalize();
+        let (u, v) = dir.orthonormal_basis();
+        (sin * phi.cos()) * u + (sin * phi.sin()) * v + cos * dir
+    }
+
+    /// Henyey-Greenstein phase function, where `cos` is the cosine of the angle
+    /// between the old and new direction of travel.
+    pub fn phase(&self, cos: f64) -> f64 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+}
+
+fn max_component(vec: Vec3) -> f64 {
+    vec.x().max(vec.y()).max(vec.z())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::volume::VoxelGrid;
+
+    #[test]
+    fn phase_integrates_to_one() {
+        let medium = Medium::new(Vec3::zero(), Vec3::new(1.0, 1.0, 1.0), 0.7);
+        let steps = 10000;
+        let mut sum = 0.0;
+        for i in 0..steps {
+            let cos = -1.0 + 2.0 * (i as f64 + 0.5) / steps as f64;
+            sum += medium.phase(cos) * 2.0 * PI * (2.0 / steps as f64);
+        }
+        assert!((sum - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn phase_sampling_mean_cosine() {
+        // The mean cosine of Henyey-Greenstein is g
+        let medium = Medium::new(Vec3::zero(), Vec3::new(1.0, 1.0, 1.0), 0.5);
+        let dir = Vec3::new(0.0, 0.0, 1.0);
+        let count = 100000;
+        let mean = (0..count).map(|_| medium.sample_phase(dir).dot(dir)).sum::<f64>() / count as f64;
+        assert!((mean - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn gray_medium_transmittance() {
+        // Fraction of rays passing through should match Beer-Lambert
+        let medium = Medium::new(Vec3::new(0.5, 0.5, 0.5), Vec3::new(0.5, 0.5, 0.5), 0.0);
+        let ray = Ray::new(Vec3::zero(), Vec3::new(2.0, 0.0, 0.0));
+        let count = 100000;
+        let passed = (0..count)
+            .filter(|_| matches!(medium.sample(ray, 0.5).event, MediumEvent::Pass { .. }))
+            .count();
+        let expected = (-1.0f64).exp();
+        assert!((passed as f64 / count as f64 - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn heterogeneous_transmittance() {
+        // Density ramping from 0 to 2 along x averages to 1
+        let bounds = Aabb::new(Vec3::zero(), Vec3::new(1.0, 1.0, 1.0));
+        let values = (0..2 * 2).flat_map(|_| [0.0, 2.0]).collect();
+        let grid = ScalarField::Grid(VoxelGrid::new([2, 2, 2], values, bounds));
+        let medium = Medium::heterogeneous(Vec3::new(1.0, 1.0, 1.0), Vec3::zero(), 0.0, grid);
+        // Voxel centers at x = 0.25 and 0.75
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0));
+        let count = 100000;
+        let passed = (0..count)
+            .filter(|_| matches!(medium.sample(ray, 0.5).event, MediumEvent::Pass { .. }))
+            .count();
+        let expected = (-0.5f64).exp();
+        assert!((passed as f64 / count as f64 - expected).abs() < 0.01);
+    }
+}
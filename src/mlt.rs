@@ -0,0 +1,229 @@
+use std::f64::consts::PI;
+use rand::rngs::StdRng;
+use rand::{Error, Rng, RngCore, SeedableRng};
+use crate::camera::Camera;
+use crate::renderer::Renderer;
+use crate::scene::Scene;
+use crate::util::{self, random, AliasTable};
+use crate::vector::Vec3;
+
+// How far small steps move each random number, and how often all of them are
+// picked anew instead, from Kelemen et al.
+const SIGMA: f64 = 0.01;
+const LARGE_STEP_PROBABILITY: f64 = 0.3;
+
+/// One of the random numbers of a path, and what it was before the current step.
+#[derive(Clone, Copy)]
+struct Coordinate {
+    value: f64,
+    // Iteration it was last changed in
+    modified: u64,
+    backup: f64,
+    modified_backup: u64,
+}
+
+/// The random numbers a path was traced with, which `util::random` hands out in order
+/// while it's set. Each step of the Markov chain changes them a little, or picks all
+/// of them anew. They are only changed when they're used, so paths that stop early
+/// don't pay for the ones they didn't need.
+pub(crate) struct PrimarySample {
+    rng: StdRng,
+    coordinates: Vec<Coordinate>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    // Of the next number to hand out
+    index: usize,
+}
+
+impl PrimarySample {
+    /// Random numbers that always come out the same for `seed`.
+    fn new(seed: u64) -> Self {
+        Self { rng: StdRng::seed_from_u64(seed), coordinates: Vec::new(), iteration: 0, large_step: true, last_large_step: 0, index: 0 }
+    }
+
+    /// Get ready to hand out the numbers of the next proposed path from the start.
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < LARGE_STEP_PROBABILITY;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Go back to the numbers from before the last step.
+    fn reject(&mut self) {
+        for coordinate in &mut self.coordinates {
+            if coordinate.modified == self.iteration {
+                coordinate.value = coordinate.backup;
+                coordinate.modified = coordinate.modified_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> f64 {
+        if self.index == self.coordinates.len() {
+            self.coordinates.push(Coordinate { value: 0.0, modified: 0, backup: 0.0, modified_backup: 0 });
+        }
+        let (iteration, large_step, last_large_step) = (self.iteration, self.large_step, self.last_large_step);
+        let coordinate = &mut self.coordinates[self.index];
+        self.index += 1;
+
+        // Numbers nobody asked for since the last large step would have been picked anew then
+        if coordinate.modified < last_large_step {
+            coordinate.value = self.rng.gen();
+            coordinate.modified = last_large_step;
+        }
+        coordinate.backup = coordinate.value;
+        coordinate.modified_backup = coordinate.modified;
+        if large_step {
+            coordinate.value = self.rng.gen();
+        } else {
+            // All the small steps it missed at once
+            let steps = (iteration - coordinate.modified) as f64;
+            let (u, v): (f64, f64) = self.rng.gen();
+            let normal = (-2.0 * (1.0 - u).ln()).sqrt() * (2.0 * PI * v).cos();
+            coordinate.value += SIGMA * steps.sqrt() * normal;
+            coordinate.value -= coordinate.value.floor();
+        }
+        coordinate.modified = iteration;
+        coordinate.value
+    }
+}
+
+impl RngCore for PrimarySample {
+    // Turned into bits the way `rand` turns them back into floats
+    fn next_u32(&mut self) -> u32 {
+        ((self.next() * (1u64 << 24) as f64) as u32) << 8
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next() * (1u64 << 53) as f64) as u64) << 11
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Primary sample space Metropolis light transport, after Kelemen et al. Paths are
+/// traced by the path tracer, but its random numbers come from Markov chains that
+/// mostly make small changes to paths that found a lot of light, so light that only
+/// gets through small gaps is found again once it's found. A bootstrap phase of
+/// ordinary paths first finds how bright the image is overall.
+pub(crate) struct Metropolis<'a> {
+    renderer: &'a Renderer,
+    scene: &'a Scene,
+    camera: &'a Camera,
+    bootstrap: usize,
+    chains: usize,
+}
+
+impl<'a> Metropolis<'a> {
+    pub(crate) fn new(renderer: &'a Renderer, scene: &'a Scene, camera: &'a Camera, bootstrap: usize, chains: usize) -> Self {
+        Self { renderer, scene, camera, bootstrap, chains }
+    }
+
+    /// Color of each pixel after `mutations_per_pixel` steps of the chains for each
+    /// pixel on average, row by row.
+    pub(crate) fn render(&self, mutations_per_pixel: u32, progress: bool) -> Vec<Vec3> {
+        let pixel_count = (self.camera.width() * self.camera.height()) as usize;
+        let mut film = vec![Vec3::zero(); pixel_count];
+        // Every sample gets its own seed, so chains can start from any of them
+        let seed = random::<u64>();
+        let weights: Vec<f64> = (0..self.bootstrap as u64)
+            .map(|i| {
+                util::set_primary(Some(PrimarySample::new(seed.wrapping_add(i))));
+                self.path().1.luminance().max(0.0)
+            })
+            .collect();
+        util::set_primary(None);
+        let table = match AliasTable::new(&weights) {
+            Some(table) => table,
+            None => return film,
+        };
+        // Average brightness of a path, which is what the chains leave out
+        let brightness = weights.iter().sum::<f64>() / weights.len() as f64;
+
+        let chains = self.chains.max(1);
+        let starts: Vec<usize> = (0..chains).map(|_| table.sample()).collect();
+        let mutations = mutations_per_pixel as usize * pixel_count;
+        for (chain, start) in starts.into_iter().enumerate() {
+            if progress {
+                println!("{} / {}", chain, chains);
+            }
+            util::set_primary(Some(PrimarySample::new(seed.wrapping_add(start as u64))));
+            let (mut pixel, mut color) = self.path();
+            // Spread the mutations over the chains, the first ones taking any left over
+            let steps = mutations / chains + usize::from(chain < mutations % chains);
+            for _ in 0..steps {
+                util::with_primary(PrimarySample::start_iteration);
+                let (proposed_pixel, proposed) = self.path();
+                let (luminance, proposed_luminance) = (color.luminance(), proposed.luminance());
+                let accept = if luminance > 0.0 { (proposed_luminance / luminance).clamp(0.0, 1.0) } else { 1.0 };
+                // Both paths count as much as they're likely to be where the chain is next
+                if accept > 0.0 && proposed_luminance > 0.0 {
+                    film[proposed_pixel] = film[proposed_pixel] + (accept / proposed_luminance) * proposed;
+                }
+                if luminance > 0.0 {
+                    film[pixel] = film[pixel] + ((1.0 - accept) / luminance) * color;
+                }
+                // Not one of the path's own random numbers
+                if util::with_primary(|primary| primary.rng.gen::<f64>() < accept).unwrap_or(false) {
+                    (pixel, color) = (proposed_pixel, proposed);
+                    util::with_primary(PrimarySample::accept);
+                } else {
+                    util::with_primary(PrimarySample::reject);
+                }
+            }
+        }
+        util::set_primary(None);
+
+        let scale = brightness / mutations_per_pixel.max(1) as f64;
+        film.into_iter().map(|color| scale * color).collect()
+    }
+
+    /// A pixel and the light reaching the camera through it, traced with the random
+    /// numbers of the current primary sample.
+    fn path(&self) -> (usize, Vec3) {
+        let (width, height) = (self.camera.width(), self.camera.height());
+        let x = ((random::<f64>() * width as f64) as u32).min(width - 1);
+        let y = ((random::<f64>() * height as f64) as u32).min(height - 1);
+        let ray = self.camera.ray_rand(x, y);
+        ((y * width + x) as usize, self.renderer.trace(ray, self.scene))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_steps_go_back() {
+        util::set_primary(Some(PrimarySample::new(1)));
+        let first: Vec<f64> = (0..4).map(|_| random()).collect();
+        util::with_primary(PrimarySample::start_iteration);
+        let second: Vec<f64> = (0..4).map(|_| random()).collect();
+        assert_ne!(first, second);
+        let mut primary = util::set_primary(None).unwrap();
+        primary.reject();
+        let values: Vec<f64> = primary.coordinates.iter().map(|coordinate| coordinate.value).collect();
+        assert_eq!(values, first);
+
+        // Random numbers from the generator again
+        assert_ne!(random::<f64>(), first[0]);
+    }
+}
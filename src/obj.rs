@@ -6,11 +6,19 @@ use std::io::{BufRead, ErrorKind};
 use crate::material::Material;
 use crate::vector::Vec3;
 
+/// Load the triangles of a Wavefront OBJ file, moved by `pos`. Faces with more than
+/// three corners are split into triangles, and texture coordinates are kept so
+/// textured materials like `Material::Emissive` can use them.
 pub fn obj_to_triangles(file_path: &str, pos: Vec3, material: Material) -> Result<Vec<Triangle>> {
     let file = File::open(file_path)?;
-    let lines= io::BufReader::new(file).lines();
+    parse_obj(io::BufReader::new(file), pos, material)
+}
+
+fn parse_obj(reader: impl BufRead, pos: Vec3, material: Material) -> Result<Vec<Triangle>> {
+    let lines = reader.lines();
 
     let mut vertices = Vec::new();
+    let mut uvs = Vec::new();
     let mut triangles = Vec::new();
 
     for line in lines {
@@ -25,31 +33,84 @@ pub fn obj_to_triangles(file_path: &str, pos: Vec3, material: Material) -> Resul
             "v" => {
                 // Vertex
 
-                let x: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|err| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
-                let y: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|err| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
-                let z: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|err| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                let x: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                let y: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                let z: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
 
                 vertices.push(Vec3::new(x, y, z));
             }
-            "f" => {
-                // Polygon
+            "vt" => {
+                // Texture coordinates, v is optional
 
-                let v0_index: usize = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.split("/").next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|err| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
-                let v1_index: usize = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.split("/").next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|err| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
-                let v2_index: usize = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.split("/").next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|err| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                let u: f64 = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                let v: f64 = parts.next().unwrap_or("0").parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
 
-                let v0 = vertices.get(v0_index - 1).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Vertex not found"))?;
-                let v1 = vertices.get(v1_index - 1).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Vertex not found"))?;
-                let v2 = vertices.get(v2_index - 1).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Vertex not found"))?;
+                uvs.push((u, v));
+            }
+            "f" => {
+                // Polygon, with corners like v, v/vt, v//vn or v/vt/vn
 
-                let triangle = Triangle::new(*v0 + pos, *v1 + pos, *v2 + pos, material.clone());
-                println!("triangle = {:?}", triangle);
-                triangles.push(triangle);
+                let mut corners = Vec::new();
+                for corner in parts {
+                    let mut indices = corner.split('/');
+                    let v_index: usize = indices.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not enough elements"))?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                    let vertex = vertices.get(v_index.wrapping_sub(1)).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Vertex not found"))?;
+                    let uv = match indices.next().filter(|index| !index.is_empty()) {
+                        Some(index) => {
+                            let vt_index: usize = index.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid numbers"))?;
+                            Some(*uvs.get(vt_index.wrapping_sub(1)).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Texture coordinates not found"))?)
+                        }
+                        None => None,
+                    };
+                    corners.push((*vertex + pos, uv));
+                }
+                if corners.len() < 3 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Not enough elements"));
+                }
 
+                // Fan out from the first corner
+                for i in 1..corners.len() - 1 {
+                    let (c0, c1, c2) = (corners[0], corners[i], corners[i + 1]);
+                    let mut triangle = Triangle::new(c0.0, c1.0, c2.0, material.clone());
+                    if let (Some(uv0), Some(uv1), Some(uv2)) = (c0.1, c1.1, c2.1) {
+                        triangle = triangle.with_uvs([uv0, uv1, uv2]);
+                    }
+                    triangles.push(triangle);
+                }
             }
             _default => {}
         }
     }
 
     Ok(triangles)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+    use crate::shapes::Hittable;
+
+    fn material() -> Material {
+        Material::Diffuse { color: Vec3::new(0.5, 0.5, 0.5) }
+    }
+
+    #[test]
+    fn textured_quad() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nf 1/1 2/2 3/3 4/4\n";
+        let triangles = parse_obj(obj.as_bytes(), Vec3::new(0.0, 0.0, -1.0), material()).unwrap();
+        assert_eq!(triangles.len(), 2);
+        // In the second triangle, where uv follows x and y
+        let ray = Ray::new(Vec3::new(0.25, 0.75, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangles.iter().find_map(|triangle| triangle.hit(ray, 0.0..f64::INFINITY)).unwrap();
+        assert!((hit.t() - 2.0).abs() < 1e-9);
+        assert!((hit.uv().0 - 0.25).abs() < 1e-9 && (hit.uv().1 - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn missing_indices() {
+        assert!(parse_obj("v 0 0 0\nf 1 2 3\n".as_bytes(), Vec3::zero(), material()).is_err());
+        assert!(parse_obj("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1/4 2/4 3/4\n".as_bytes(), Vec3::zero(), material()).is_err());
+        assert!(parse_obj("v 0 0 0\nf 0 1 1\n".as_bytes(), Vec3::zero(), material()).is_err());
+    }
+}
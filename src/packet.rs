@@ -0,0 +1,224 @@
+use std::array;
+use std::ops::Range;
+use crate::ray::Ray;
+use crate::util::gamma;
+use crate::vector::Vec3;
+
+/// How many rays or shapes are tested at once. They are kept in plain arrays with
+/// one coordinate per array, which the compiler turns into SIMD instructions for
+/// whatever the target has.
+pub const WIDTH: usize = 4;
+
+pub type Lanes = [f64; WIDTH];
+
+// Relative slack in the quick checks, so they never miss what the exact tests hit
+const TOLERANCE: f64 = 1e-5;
+// Makes up for rounding in the slab test, so boxes are never missed
+const SLAB_PADDING: f64 = 1.0 + 2.0 * gamma(3);
+
+fn components(vec: Vec3) -> [f64; 3] {
+    [vec.x(), vec.y(), vec.z()]
+}
+
+/// The same value in every lane.
+fn splat(values: [f64; 3]) -> [Lanes; 3] {
+    values.map(|value| [value; WIDTH])
+}
+
+/// Slab test of the ray in each lane against the box in the same lane. Gives where
+/// each ray enters its box and whether it does so within its range.
+pub fn hit_boxes(origin: &[Lanes; 3], inv_dir: &[Lanes; 3], min: &[Lanes; 3], max: &[Lanes; 3], t_min: &Lanes, t_max: &Lanes) -> (Lanes, [bool; WIDTH]) {
+    let mut near = *t_min;
+    let mut far = *t_max;
+    for axis in 0..3 {
+        for lane in 0..WIDTH {
+            let t0 = (min[axis][lane] - origin[axis][lane]) * inv_dir[axis][lane];
+            let t1 = (max[axis][lane] - origin[axis][lane]) * inv_dir[axis][lane];
+            // Comparisons instead of min and max, which are slower because they handle
+            // NaN. It still gets skipped, from rays starting on a slab they are
+            // parallel to.
+            let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            near[lane] = if t0 > near[lane] { t0 } else { near[lane] };
+            far[lane] = if t1 * SLAB_PADDING < far[lane] { t1 * SLAB_PADDING } else { far[lane] };
+        }
+    }
+    (near, array::from_fn(|lane| near[lane] <= far[lane]))
+}
+
+/// Rays starting close together going roughly the same way, like the samples of a
+/// pixel, traced together.
+#[derive(Debug, Copy, Clone)]
+pub struct RayPacket {
+    rays: [Ray; WIDTH],
+    origin: [Lanes; 3],
+    inv_dir: [Lanes; 3],
+}
+
+impl RayPacket {
+    pub fn new(rays: [Ray; WIDTH]) -> Self {
+        let origin = array::from_fn(|axis| rays.map(|ray| ray.origin().axis(axis)));
+        let inv_dir = array::from_fn(|axis| rays.map(|ray| 1.0 / ray.dir().axis(axis)));
+        Self { rays, origin, inv_dir }
+    }
+
+    pub fn rays(&self) -> &[Ray; WIDTH] {
+        &self.rays
+    }
+
+    /// Where each ray enters the box and whether it does so within its range.
+    pub fn hit_box(&self, min: [f64; 3], max: [f64; 3], t_min: &Lanes, t_max: &Lanes) -> (Lanes, [bool; WIDTH]) {
+        hit_boxes(&self.origin, &self.inv_dir, &splat(min), &splat(max), t_min, t_max)
+    }
+}
+
+/// The origin and inverse direction of one ray in every lane, to test it against
+/// several boxes at once.
+pub fn splat_ray(ray: Ray) -> ([Lanes; 3], [Lanes; 3]) {
+    let inv_dir = components(ray.dir()).map(|value| 1.0 / value);
+    (splat(components(ray.origin())), splat(inv_dir))
+}
+
+/// Up to `WIDTH` spheres, to check a ray against all of them at once.
+#[derive(Debug, Clone)]
+pub struct SphereBlock {
+    center: [Lanes; 3],
+    radius: Lanes,
+}
+
+impl SphereBlock {
+    pub fn new(spheres: &[(Vec3, f64)]) -> Self {
+        assert!(spheres.len() <= WIDTH);
+        // NaN never passes the checks, so unused lanes are never candidates
+        let sphere = |lane: usize| spheres.get(lane).map_or(([f64::NAN; 3], f64::NAN), |&(center, radius)| (components(center), radius));
+        Self {
+            center: array::from_fn(|axis| array::from_fn(|lane| sphere(lane).0[axis])),
+            radius: array::from_fn(|lane| sphere(lane).1),
+        }
+    }
+
+    /// The spheres the ray might hit in `t_range`. Only a quick check, the real hit
+    /// comes from `Sphere::hit`.
+    pub fn candidates(&self, ray: Ray, t_range: Range<f64>) -> [bool; WIDTH] {
+        let (origin, dir) = (components(ray.origin()), components(ray.dir()));
+        let a = dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2];
+        array::from_fn(|lane| {
+            let oc: [f64; 3] = array::from_fn(|axis| origin[axis] - self.center[axis][lane]);
+            let half_b = oc[0] * dir[0] + oc[1] * dir[1] + oc[2] * dir[2];
+            let c = oc[0] * oc[0] + oc[1] * oc[1] + oc[2] * oc[2] - self.radius[lane] * self.radius[lane];
+            let discriminant = half_b * half_b - a * c;
+            let sqrt = discriminant.max(0.0).sqrt();
+            let (near, far) = ((-half_b - sqrt) / a, (-half_b + sqrt) / a);
+            let margin = TOLERANCE * (near.abs() + far.abs());
+            discriminant >= -TOLERANCE * (half_b * half_b + (a * c).abs())
+                && far >= t_range.start - margin
+                && near <= t_range.end + margin
+        })
+    }
+}
+
+/// Up to `WIDTH` triangles, to check a ray against all of them at once.
+#[derive(Debug, Clone)]
+pub struct TriangleBlock {
+    v0: [Lanes; 3],
+    edge1: [Lanes; 3],
+    edge2: [Lanes; 3],
+}
+
+impl TriangleBlock {
+    pub fn new(triangles: &[[Vec3; 3]]) -> Self {
+        assert!(triangles.len() <= WIDTH);
+        let vertex = |lane: usize, i: usize| triangles.get(lane).map_or([f64::NAN; 3], |vertices| components(vertices[i]));
+        let edge = |lane: usize, i: usize, axis: usize| vertex(lane, i)[axis] - vertex(lane, 0)[axis];
+        Self {
+            v0: array::from_fn(|axis| array::from_fn(|lane| vertex(lane, 0)[axis])),
+            edge1: array::from_fn(|axis| array::from_fn(|lane| edge(lane, 1, axis))),
+            edge2: array::from_fn(|axis| array::from_fn(|lane| edge(lane, 2, axis))),
+        }
+    }
+
+    /// The triangles the ray might hit in `t_range`. Only a quick check, the real hit
+    /// comes from the watertight `Triangle::hit`.
+    pub fn candidates(&self, ray: Ray, t_range: Range<f64>) -> [bool; WIDTH] {
+        let (origin, dir) = (components(ray.origin()), components(ray.dir()));
+        let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let cross = |a: [f64; 3], b: [f64; 3]| [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+        array::from_fn(|lane| {
+            // Möller-Trumbore
+            let edge1 = array::from_fn(|axis| self.edge1[axis][lane]);
+            let edge2 = array::from_fn(|axis| self.edge2[axis][lane]);
+            let p = cross(dir, edge2);
+            let det = dot(edge1, p);
+            // Too close to parallel to tell
+            let scale = (dot(edge1, edge1) * dot(edge2, edge2) * dot(dir, dir)).sqrt();
+            if det.abs() <= TOLERANCE * scale {
+                return true;
+            }
+            let inv_det = 1.0 / det;
+            let s = array::from_fn(|axis| origin[axis] - self.v0[axis][lane]);
+            let u = dot(s, p) * inv_det;
+            let q = cross(s, edge1);
+            let v = dot(dir, q) * inv_det;
+            let t = dot(edge2, q) * inv_det;
+            let margin = TOLERANCE * t.abs();
+            u >= -TOLERANCE && v >= -TOLERANCE && u + v <= 1.0 + TOLERANCE
+                && t >= t_range.start - margin && t <= t_range.end + margin
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::shapes::{Hittable, Sphere, Triangle};
+    use crate::util::random;
+
+    fn material() -> Material {
+        Material::Diffuse { color: Vec3::new(0.5, 0.5, 0.5) }
+    }
+
+    #[test]
+    fn packet_hits_box() {
+        let rays = [
+            Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0)),
+            Ray::new(Vec3::new(3.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0)),
+            Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, -1.0)),
+            // Starting inside
+            Ray::new(Vec3::zero(), Vec3::new(1.0, 1.0, 0.0)),
+        ];
+        let packet = RayPacket::new(rays);
+        let (_, hits) = packet.hit_box([-1.0; 3], [1.0; 3], &[0.0; WIDTH], &[f64::INFINITY; WIDTH]);
+        assert_eq!(hits, [true, false, false, true]);
+        // Too short to reach it
+        let (_, hits) = packet.hit_box([-1.0; 3], [1.0; 3], &[0.0; WIDTH], &[3.0; WIDTH]);
+        assert_eq!(hits, [false, false, false, true]);
+    }
+
+    #[test]
+    fn blocks_never_miss_real_hits() {
+        let spheres: Vec<Sphere> = (0..WIDTH).map(|_| Sphere::new(Vec3::random(), 0.3, material())).collect();
+        let sphere_block = SphereBlock::new(&spheres.iter().map(|sphere| (sphere.center(), sphere.radius())).collect::<Vec<_>>());
+        let triangles: Vec<Triangle> = (0..WIDTH).map(|_| Triangle::new(Vec3::random(), Vec3::random(), Vec3::random(), material())).collect();
+        let triangle_block = TriangleBlock::new(&triangles.iter().map(|triangle| triangle.vertices()).collect::<Vec<_>>());
+
+        let mut rejected = 0;
+        for _ in 0..10000 {
+            let ray = Ray::new(3.0 * Vec3::random(), Vec3::random());
+            let t_range = 0.0..(5.0 * random::<f64>());
+            let sphere_candidates = sphere_block.candidates(ray, t_range.clone());
+            let triangle_candidates = triangle_block.candidates(ray, t_range.clone());
+            for lane in 0..WIDTH {
+                assert!(sphere_candidates[lane] || spheres[lane].hit(ray, t_range.clone()).is_none());
+                assert!(triangle_candidates[lane] || triangles[lane].hit(ray, t_range.clone()).is_none());
+                rejected += !sphere_candidates[lane] as usize + !triangle_candidates[lane] as usize;
+            }
+        }
+        // Not just letting everything through either
+        assert!(rejected > 10000);
+
+        // Unused lanes are never candidates
+        let partial = SphereBlock::new(&[(Vec3::zero(), 1.0)]);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(partial.candidates(ray, 0.0..f64::INFINITY), [true, false, false, false]);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::renderer::{background, emitted, surface_direct_light, Integrator};
+use crate::scene::Scene;
+use crate::texture::Texture;
+use crate::vector::Vec3;
+
+/// Color of a ray from the camera for the integrators that show something about the
+/// scene instead of rendering it properly. Rays that don't hit anything are black,
+/// except for the sky in `Whitted`.
+pub(crate) fn shade(integrator: Integrator, scene: &Scene, ray: Ray, max_depth: u32) -> Vec3 {
+    if let Integrator::Heatmap { max } = integrator {
+        let tests = scene.count_tests(ray, 0.0..f64::INFINITY);
+        let t = (tests as f64 / max.max(1) as f64).min(1.0);
+        return Vec3::new(t, 0.0, 1.0 - t);
+    }
+    if let Integrator::Whitted = integrator {
+        return whitted(scene, ray, max_depth);
+    }
+
+    let hit = match scene.hit_surface(ray) {
+        Some(hit) => hit,
+        None => return Vec3::zero(),
+    };
+    let outward = if hit.front_face() { hit.normal() } else { -hit.normal() };
+    match integrator {
+        Integrator::Normals => 0.5 * (outward + Vec3::new(1.0, 1.0, 1.0)),
+        Integrator::Depth { far } => {
+            let value = 1.0 - (hit.t() * ray.dir().norm() / far).min(1.0);
+            Vec3::new(value, value, value)
+        }
+        Integrator::Uv => {
+            let (u, v) = hit.uv();
+            Vec3::new(u.rem_euclid(1.0), v.rem_euclid(1.0), 0.0)
+        }
+        Integrator::AmbientOcclusion { radius } => {
+            // Directions spread like the cosine, as light from an evenly lit sky would be
+            let dir = (hit.normal() + Vec3::random_unit()).normalize();
+            let open = if dir.is_near_zero() || scene.occluded(hit.spawn_ray(dir), 0.0..radius) { 0.0 } else { 1.0 };
+            Vec3::new(open, open, open)
+        }
+        Integrator::MaterialId => material_color(hit.material()),
+        _ => Vec3::zero(),
+    }
+}
+
+/// Light straight from the lights, following mirrors and glass but no other bounces.
+fn whitted(scene: &Scene, mut ray: Ray, max_depth: u32) -> Vec3 {
+    let mut color = Vec3::zero();
+    let mut weight = Vec3::new(1.0, 1.0, 1.0);
+    for _ in 0..max_depth {
+        let hit = match scene.hit_surface(ray) {
+            Some(hit) => hit,
+            None => return color + weight * background(scene, ray, None),
+        };
+        color = color + weight * (emitted(scene, ray, &hit, None) + surface_direct_light(scene, ray, &hit, None));
+        let material = hit.material();
+        if !material.is_specular() {
+            break;
+        }
+        match material.scatter(ray, &hit) {
+            Some(scatter) => {
+                weight = weight * scatter.attenuation;
+                ray = scatter.ray;
+            }
+            None => break,
+        }
+    }
+    color
+}
+
+/// A bright color made up from what kind of material it is and its colors, so the
+/// same material always gets the same color. Images on textures are left out, since
+/// telling them apart would take hashing every pixel.
+fn material_color(material: &Material) -> Vec3 {
+    let components = |vec: Vec3| vec![vec.x(), vec.y(), vec.z()];
+    let values = match material {
+        Material::Diffuse { color } => components(*color),
+        Material::Metal { color, fuzz } => [components(*color), vec![*fuzz]].concat(),
+        Material::Glass { refractive_index, transmittance, .. } => [components(*transmittance), vec![refractive_index.at(589.3)]].concat(),
+        Material::Light { color, intensity } => components(*intensity * *color),
+        Material::Emissive { emission: Texture::Image(_), .. } | Material::Volume { .. } => Vec::new(),
+        Material::Emissive { emission, intensity, .. } => components(*intensity * emission.average()),
+    };
+    let mut hasher = DefaultHasher::new();
+    std::mem::discriminant(material).hash(&mut hasher);
+    for value in values {
+        value.to_bits().hash(&mut hasher);
+    }
+    let hue = (hasher.finish() % 360) as f64;
+    // Fully saturated, from the hue around the color wheel
+    let channel = |offset: f64| {
+        let k = (hue / 60.0 + offset) % 6.0;
+        1.0 - (k.min(4.0 - k).clamp(0.0, 1.0))
+    };
+    Vec3::new(channel(5.0), channel(3.0), channel(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::Light;
+    use crate::shapes::{Quad, Sphere, Triangle};
+    use crate::util;
+
+    fn down() -> Ray {
+        Ray::new(Vec3::new(0.2, 1.0, 0.3), Vec3::new(0.0, -1.0, 0.0))
+    }
+
+    /// A white floor from -10 to 10 along x and z.
+    fn floor(scene: &mut Scene) {
+        let white = Material::Diffuse { color: Vec3::new(1.0, 1.0, 1.0) };
+        scene.add(Quad::new(Vec3::new(-10.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 20.0), Vec3::new(20.0, 0.0, 0.0), white));
+    }
+
+    #[test]
+    fn shows_normals_depth_and_uvs() {
+        let mut scene = Scene::new();
+        floor(&mut scene);
+        assert_eq!(shade(Integrator::Normals, &scene, down(), 5), Vec3::new(0.5, 1.0, 0.5));
+        let depth = shade(Integrator::Depth { far: 4.0 }, &scene, down(), 5);
+        assert!((depth.x() - 0.75).abs() < 1e-6);
+        assert_eq!(shade(Integrator::Depth { far: 4.0 }, &scene, Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), 5), Vec3::zero());
+
+        let mut scene = Scene::new();
+        let white = Material::Diffuse { color: Vec3::new(1.0, 1.0, 1.0) };
+        scene.add_triangle(Triangle::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), white));
+        let uv = shade(Integrator::Uv, &scene, down(), 5);
+        assert!((uv.x() - 0.2).abs() < 1e-6 && (uv.y() - 0.3).abs() < 1e-6, "{:?}", uv);
+    }
+
+    #[test]
+    fn occlusion_within_radius() {
+        util::seed(1);
+        let mut scene = Scene::new();
+        floor(&mut scene);
+        let open = (0..64).map(|_| shade(Integrator::AmbientOcclusion { radius: 1.0 }, &scene, down(), 5).x()).sum::<f64>();
+        assert_eq!(open, 64.0);
+        // A ceiling half a unit up blocks all but the flattest directions, unless the
+        // radius is smaller
+        let white = Material::Diffuse { color: Vec3::new(1.0, 1.0, 1.0) };
+        scene.add(Quad::new(Vec3::new(-10.0, 0.5, -10.0), Vec3::new(20.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 20.0), white));
+        let below = Ray::new(Vec3::new(0.2, 0.25, 0.3), Vec3::new(0.0, -1.0, 0.0));
+        let blocked = (0..64).map(|_| shade(Integrator::AmbientOcclusion { radius: 2.0 }, &scene, below, 5).x()).sum::<f64>();
+        assert!(blocked < 16.0, "{}", blocked);
+        assert_eq!(shade(Integrator::AmbientOcclusion { radius: 0.01 }, &scene, below, 5).x(), 1.0);
+    }
+
+    #[test]
+    fn same_materials_get_same_colors() {
+        let red = Material::Diffuse { color: Vec3::new(1.0, 0.0, 0.0) };
+        let green = Material::Diffuse { color: Vec3::new(0.0, 1.0, 0.0) };
+        assert_eq!(material_color(&red), material_color(&red.clone()));
+        assert_ne!(material_color(&red), material_color(&green));
+        let metal = Material::Metal { color: Vec3::new(1.0, 0.0, 0.0), fuzz: 0.0 };
+        assert_ne!(material_color(&red), material_color(&metal));
+    }
+
+    #[test]
+    fn heatmap_counts_tests() {
+        let mut scene = Scene::new();
+        floor(&mut scene);
+        let few = shade(Integrator::Heatmap { max: 16 }, &scene, down(), 5);
+        for i in 0..15 {
+            scene.add_sphere(Sphere::new(Vec3::new(0.2, 2.0 + i as f64 * 0.01, 0.3), 0.5, Material::Diffuse { color: Vec3::zero() }));
+        }
+        let up = Ray::new(Vec3::new(0.2, 1.0, 0.3), Vec3::new(0.0, 1.0, 0.0));
+        let many = shade(Integrator::Heatmap { max: 16 }, &scene, up, 5);
+        assert!(many.x() > few.x(), "{:?} {:?}", many, few);
+    }
+
+    #[test]
+    fn whitted_sees_lights_in_mirrors() {
+        // Straight up into a mirror, which shows the lit floor below the camera
+        let mut scene = Scene::new();
+        floor(&mut scene);
+        scene.add_light(Light::Point { position: Vec3::new(0.0, 1.0, 0.0), color: Vec3::new(1.0, 1.0, 1.0), intensity: 1.0, profile: None });
+        let lit = whitted(&scene, down(), 5);
+        assert!(lit.x() > 0.0);
+        let mirror = Material::Metal { color: Vec3::new(1.0, 1.0, 1.0), fuzz: 0.0 };
+        scene.add(Quad::new(Vec3::new(-10.0, 2.0, -10.0), Vec3::new(20.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 20.0), mirror));
+        let up = Ray::new(Vec3::new(0.2, 1.0, 0.3), Vec3::new(0.0, 1.0, 0.0));
+        let reflected = whitted(&scene, up, 5);
+        assert!((reflected.x() - lit.x()).abs() < 1e-6, "{:?} {:?}", reflected, lit);
+    }
+}
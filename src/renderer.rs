@@ -0,0 +1,945 @@
+use std::ops::Add;
+use std::time::Instant;
+use image::{Rgb, RgbImage};
+use crate::bdpt::Bdpt;
+use crate::camera::Camera;
+use crate::material::{Material, Scatter};
+use crate::medium::{Medium, MediumEvent};
+use crate::mlt::Metropolis;
+use crate::packet::{RayPacket, WIDTH};
+use crate::preview;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::shapes::HitResult;
+use crate::sppm::PhotonMapper;
+use crate::spectrum::{SampledSpectrum, Wavelengths};
+use crate::util;
+use crate::vector::Vec3;
+
+/// Renders images of scenes, set up builder style:
+///
+/// ```no_run
+/// # use alvinw_raytracer::{scenes, Renderer};
+/// let (scene, camera) = scenes::cornell_box(64, 64);
+/// let image = Renderer::new().samples_per_pixel(16).render(&scene, &camera);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    samples_per_pixel: u32,
+    max_depth: u32,
+    // Bounces of each kind a path can take, on top of `max_depth`
+    diffuse_depth: u32,
+    specular_depth: u32,
+    transmission_depth: u32,
+    // Bounces before paths start being stopped at random
+    roulette_depth: u32,
+    clamp_direct: f64,
+    clamp_indirect: f64,
+    integrator: Integrator,
+    spectral: bool,
+    progress: bool,
+    seed: Option<u64>,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self {
+            samples_per_pixel: 1000,
+            max_depth: 16,
+            diffuse_depth: 5,
+            specular_depth: u32::MAX,
+            transmission_depth: u32::MAX,
+            roulette_depth: 3,
+            clamp_direct: f64::INFINITY,
+            clamp_indirect: f64::INFINITY,
+            integrator: Integrator::Path,
+            spectral: false,
+            progress: false,
+            seed: None,
+        }
+    }
+
+    pub fn samples_per_pixel(mut self, samples_per_pixel: u32) -> Self {
+        self.samples_per_pixel = samples_per_pixel;
+        self
+    }
+
+    /// How many times a ray can bounce before it's given up on.
+    pub fn max_depth(mut self, max_depth: u32) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// How many times a path can bounce off surfaces that scatter light all over,
+    /// like diffuse ones and fuzzy metal, or in fog and volumes. These add the least
+    /// to the image for the time they take.
+    pub fn diffuse_depth(mut self, diffuse_depth: u32) -> Self {
+        self.diffuse_depth = diffuse_depth;
+        self
+    }
+
+    /// How many times a path can be reflected by mirrors and glass. Only `max_depth`
+    /// limits it unless this is set.
+    pub fn specular_depth(mut self, specular_depth: u32) -> Self {
+        self.specular_depth = specular_depth;
+        self
+    }
+
+    /// How many times a path can go through glass. Only `max_depth` limits it unless
+    /// this is set, so light gets through thick stacks of glass.
+    pub fn transmission_depth(mut self, transmission_depth: u32) -> Self {
+        self.transmission_depth = transmission_depth;
+        self
+    }
+
+    /// After this many bounces, paths are stopped at random, more likely the less
+    /// light they carry, and the ones that go on count for more to make up for it.
+    /// Doesn't change what the image converges to, but saves the time dim paths take.
+    pub fn russian_roulette(mut self, roulette_depth: u32) -> Self {
+        self.roulette_depth = roulette_depth;
+        self
+    }
+
+    /// Most any channel of a sample can get from light reaching it without bouncing
+    /// first. Lower values take out fireflies, but make the image darker than it
+    /// should be.
+    pub fn clamp_direct(mut self, clamp_direct: f64) -> Self {
+        self.clamp_direct = clamp_direct;
+        self
+    }
+
+    /// Like `clamp_direct`, for light found after bouncing, where most fireflies
+    /// come from.
+    pub fn clamp_indirect(mut self, clamp_indirect: f64) -> Self {
+        self.clamp_indirect = clamp_indirect;
+        self
+    }
+
+    /// How to find the light reaching the camera.
+    pub fn integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    /// Trace individual wavelengths instead of RGB colors. Slower to converge, but
+    /// needed for dispersion.
+    pub fn spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    /// Print how far the render has come.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Make every render come out the same, like for tests that compare images.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Render what the camera sees of the scene.
+    pub fn render(&self, scene: &Scene, camera: &Camera) -> RgbImage {
+        if let Some(seed) = self.seed {
+            util::seed(seed);
+        }
+        let start = Instant::now();
+
+        let (width, height) = (camera.width(), camera.height());
+        let sample_count = self.samples_per_pixel as usize;
+        let bdpt = matches!(self.integrator, Integrator::Bidirectional).then(|| Bdpt::new(scene, camera, self.max_depth));
+        // Light from the lights straight to the camera can land on any pixel
+        let mut splats = vec![Vec3::zero(); (width * height) as usize];
+        let mut film = vec![Vec3::zero(); (width * height) as usize];
+        if let Integrator::PhotonMapping { photons, radius } = self.integrator {
+            // Every pass is a sample of each pixel
+            film = PhotonMapper::new(scene, camera, self.max_depth, photons, radius).render(self.samples_per_pixel, self.progress);
+        } else if let Integrator::Metropolis { bootstrap, chains } = self.integrator {
+            film = Metropolis::new(self, scene, camera, bootstrap, chains).render(self.samples_per_pixel, self.progress);
+        } else {
+            for y in 0..height {
+                if self.progress {
+                    println!("{} / {}", y, height);
+                }
+                for x in 0..width {
+                    // Average colors (anti-aliasing)
+                    let color = match (&bdpt, self.integrator) {
+                        (Some(bdpt), _) => (0..sample_count).fold(Vec3::zero(), |color, _| color + bdpt.sample(x, y, &mut splats)),
+                        (None, Integrator::Path) => self.path_pixel(scene, camera, x, y),
+                        (None, integrator) => (0..sample_count).fold(Vec3::zero(), |color, _| {
+                            color + preview::shade(integrator, scene, camera.ray_rand(x, y), self.max_depth)
+                        }),
+                    };
+                    film[(y * width + x) as usize] = color / (sample_count as f64);
+                }
+            }
+        }
+
+        // Previews that show values instead of light keep them as they are
+        let encode = if self.integrator.is_linear() { |value| value } else { gamma_correction };
+        let mut img = RgbImage::new(width, height);
+        for (i, (color, splat)) in film.into_iter().zip(splats).enumerate() {
+            let color = color + splat / (sample_count as f64);
+            img.put_pixel(i as u32 % width, i as u32 / width, to_rgb(color, encode));
+        }
+
+        let elapsed = start.elapsed();
+        if self.progress {
+            println!("\nDone in {:.2?}", elapsed);
+        }
+
+        img
+    }
+
+    /// Sum of the colors of all samples of pixel (x, y) by path tracing.
+    fn path_pixel(&self, scene: &Scene, camera: &Camera, x: u32, y: u32) -> Vec3 {
+        let mut color = Vec3::zero();
+        let sample_count = self.samples_per_pixel as usize;
+        for first in (0..sample_count).step_by(WIDTH) {
+            // Samples of a pixel go the same way at first, so trace them together
+            let rays = [(); WIDTH].map(|_| camera.ray_rand(x, y));
+            let hits = scene.hit_packet(&RayPacket::new(rays), 0.0..f64::INFINITY);
+            // The last packet can have a few too many
+            for (ray, hit_result) in rays.into_iter().zip(hits).take(sample_count - first) {
+                let color_i = self.shade_sample(ray, hit_result, scene);
+                // color += color_i;
+                color = color + color_i;
+            }
+        }
+        color
+    }
+
+    /// Color of a ray from the camera by path tracing.
+    pub(crate) fn trace(&self, ray: Ray, scene: &Scene) -> Vec3 {
+        self.shade_sample(ray, scene.hit(ray, 0.0..f64::INFINITY), scene)
+    }
+
+    /// Like `trace`, for a ray that has already been traced to `hit_result`.
+    fn shade_sample<'a>(&self, ray: Ray, hit_result: Option<HitResult<'a>>, scene: &'a Scene) -> Vec3 {
+        let light = if self.spectral {
+            let mut wavelengths = Wavelengths::sample();
+            let light = self.shade_spectrum(ray, hit_result, scene, &mut wavelengths);
+            Found { direct: wavelengths.to_rgb(light.direct), indirect: wavelengths.to_rgb(light.indirect) }
+        } else {
+            self.shade(ray, hit_result, scene)
+        };
+        clamp(light.direct, self.clamp_direct) + clamp(light.indirect, self.clamp_indirect)
+    }
+
+    /// Light reaching the camera along a ray that has already been traced to
+    /// `hit_result`, following it as it bounces around the scene.
+    fn shade<'a>(&self, mut ray: Ray, mut hit_result: Option<HitResult<'a>>, scene: &'a Scene) -> Found<Vec3> {
+        let mut light = Found { direct: Vec3::zero(), indirect: Vec3::zero() };
+        let mut path = Path::new(scene.fog());
+        // How much of the light found from here on reaches the camera
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        loop {
+            if let Some(medium) = path.medium {
+                let t_max = hit_result.as_ref().map_or(f64::INFINITY, |hit_result| hit_result.t());
+                let sample = medium.sample(ray, t_max);
+                light.add(&path, throughput * sample.emission);
+                match sample.event {
+                    MediumEvent::Pass { weight } => throughput = throughput * weight,
+                    MediumEvent::Scatter { point, weight } => {
+                        throughput = throughput * weight;
+                        light.add(&path, throughput * medium_direct_light(scene, ray, point, medium));
+                        let (scattered, bounce) = scatter_in_medium(ray, point, medium);
+                        match self.next_path(path, Some(medium), bounce, Some(BounceKind::Diffuse), throughput.max_component()) {
+                            Some((next, scale)) => (path, throughput) = (next, scale * throughput),
+                            None => break,
+                        }
+                        ray = scattered;
+                        hit_result = scene.hit(ray, 0.0..f64::INFINITY);
+                        continue;
+                    }
+                    MediumEvent::Absorb => break,
+                }
+            }
+
+            let hit = match hit_result {
+                Some(hit) => hit,
+                None => {
+                    light.add(&path, throughput * background(scene, ray, path.bounce));
+                    break;
+                }
+            };
+            light.add(&path, throughput * (emitted(scene, ray, &hit, path.bounce) + surface_direct_light(scene, ray, &hit, path.medium)));
+            let scatter = match hit.material().scatter(ray, &hit) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let medium = next_medium(scene, &hit, &scatter, path.medium);
+            let bounce = next_bounce(ray, &hit, &scatter, path.bounce);
+            throughput = throughput * scatter.attenuation;
+            match self.next_path(path, medium, bounce, bounce_kind(&hit, &scatter), throughput.max_component()) {
+                Some((next, scale)) => (path, throughput) = (next, scale * throughput),
+                None => break,
+            }
+            ray = scatter.ray;
+            hit_result = scene.hit(ray, 0.0..f64::INFINITY);
+        }
+        light
+    }
+
+    /// Like `shade`, for the wavelengths of a spectral path.
+    fn shade_spectrum<'a>(&self, mut ray: Ray, mut hit_result: Option<HitResult<'a>>, scene: &'a Scene, wavelengths: &mut Wavelengths) -> Found<SampledSpectrum> {
+        let mut light = Found { direct: SampledSpectrum::zero(), indirect: SampledSpectrum::zero() };
+        let mut path = Path::new(scene.fog());
+        let mut throughput = SampledSpectrum::one();
+        loop {
+            if let Some(medium) = path.medium {
+                let t_max = hit_result.as_ref().map_or(f64::INFINITY, |hit_result| hit_result.t());
+                let sample = medium.sample(ray, t_max);
+                light.add(&path, throughput * SampledSpectrum::from_rgb(sample.emission, wavelengths));
+                match sample.event {
+                    MediumEvent::Pass { weight } => throughput = throughput * SampledSpectrum::from_rgb(weight, wavelengths),
+                    MediumEvent::Scatter { point, weight } => {
+                        throughput = throughput * SampledSpectrum::from_rgb(weight, wavelengths);
+                        let direct = SampledSpectrum::from_rgb(medium_direct_light(scene, ray, point, medium), wavelengths);
+                        light.add(&path, throughput * direct);
+                        let (scattered, bounce) = scatter_in_medium(ray, point, medium);
+                        match self.next_path(path, Some(medium), bounce, Some(BounceKind::Diffuse), throughput.max_value()) {
+                            Some((next, scale)) => (path, throughput) = (next, scale * throughput),
+                            None => break,
+                        }
+                        ray = scattered;
+                        hit_result = scene.hit(ray, 0.0..f64::INFINITY);
+                        continue;
+                    }
+                    MediumEvent::Absorb => break,
+                }
+            }
+
+            let hit = match hit_result {
+                Some(hit) => hit,
+                None => {
+                    light.add(&path, throughput * SampledSpectrum::from_rgb(background(scene, ray, path.bounce), wavelengths));
+                    break;
+                }
+            };
+            let found = emitted(scene, ray, &hit, path.bounce) + surface_direct_light(scene, ray, &hit, path.medium);
+            light.add(&path, throughput * SampledSpectrum::from_rgb(found, wavelengths));
+            let scatter = match hit.material().scatter_wavelength(ray, &hit, Some(wavelengths.hero())) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            if scatter.dispersive {
+                wavelengths.terminate_secondary();
+            }
+            let medium = next_medium(scene, &hit, &scatter, path.medium);
+            let bounce = next_bounce(ray, &hit, &scatter, path.bounce);
+            throughput = throughput * SampledSpectrum::from_rgb(scatter.attenuation, wavelengths);
+            match self.next_path(path, medium, bounce, bounce_kind(&hit, &scatter), throughput.max_value()) {
+                Some((next, scale)) => (path, throughput) = (next, scale * throughput),
+                None => break,
+            }
+            ray = scatter.ray;
+            hit_result = scene.hit(ray, 0.0..f64::INFINITY);
+        }
+        light
+    }
+
+    /// The path after another bounce, or None if it stops here because it has
+    /// bounced too often or lost at Russian roulette. Also gives how much more the
+    /// light found after this counts, to make up for the paths that were stopped.
+    fn next_path<'a>(&self, path: Path<'a>, medium: Option<&'a Medium>, bounce: Option<Bounce>, kind: Option<BounceKind>, throughput: f64) -> Option<(Path<'a>, f64)> {
+        let mut next = Path { depth: path.depth + 1, medium, bounce, ..path };
+        let within_limits = match kind {
+            Some(BounceKind::Diffuse) => {
+                next.diffuse += 1;
+                next.diffuse < self.diffuse_depth
+            }
+            Some(BounceKind::Specular) => {
+                next.specular += 1;
+                next.specular < self.specular_depth
+            }
+            Some(BounceKind::Transmission) => {
+                next.transmission += 1;
+                next.transmission < self.transmission_depth
+            }
+            None => true,
+        };
+        if next.depth >= self.max_depth || !within_limits || throughput <= 0.0 {
+            return None;
+        }
+        if next.depth < self.roulette_depth {
+            return Some((next, 1.0));
+        }
+        let survival = throughput.min(1.0);
+        (util::random::<f64>() < survival).then_some((next, 1.0 / survival))
+    }
+}
+
+/// How the light reaching the camera is found.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Integrator {
+    /// Follow rays from the camera, sending shadow rays to the lights at every
+    /// bounce.
+    #[default]
+    Path,
+    /// Follow rays from the lights too, and connect them to the rays from the
+    /// camera. Finds caustics and lights behind glass that the path tracer hardly
+    /// ever does, but always renders in RGB and leaves out fog and volumes.
+    Bidirectional,
+    /// Send `photons` photons out from the lights every pass, and collect the ones
+    /// landing within `radius` of where the camera sees a surface. The radius
+    /// shrinks every pass, with a pass for each sample per pixel. Good at caustics
+    /// seen on walls and floors, but like `Bidirectional` renders in RGB only,
+    /// leaves out fog and volumes, and only finds the sky and directional lights
+    /// where they shine straight on something.
+    PhotonMapping { photons: usize, radius: f64 },
+    /// Path trace with random numbers from Markov chains that keep making small
+    /// changes to paths that found light, for light coming through small gaps like
+    /// keyholes or doors left ajar. `bootstrap` ordinary paths first find how bright
+    /// the image is, and each chain starts from one of them. The samples per pixel
+    /// are how many changes the chains make per pixel on average.
+    Metropolis { bootstrap: usize, chains: usize },
+    /// Which way surfaces face, with the x, y and z of the normal going from -1 to 1
+    /// as red, green and blue from 0 to 1. Like the other previews below it renders
+    /// in RGB, and rays that don't hit anything are black. The ones that show values
+    /// rather than light are written without gamma correction, so the values can be
+    /// read straight off the image.
+    Normals,
+    /// How far away surfaces are, from white up close to black at `far` and beyond.
+    Depth { far: f64 },
+    /// Texture coordinates as red and green, repeating every unit. Triangles without
+    /// any show their barycentric coordinates.
+    Uv,
+    /// How much of the surroundings within `radius` of a surface are open, from
+    /// black when it's fully hidden to white.
+    AmbientOcclusion { radius: f64 },
+    /// A color for each material, the same for materials that look the same.
+    MaterialId,
+    /// How many objects each ray from the camera is tested against, from blue for
+    /// none to red for `max` or more.
+    Heatmap { max: u32 },
+    /// Only light straight from the lights and the sky, through mirrors and glass,
+    /// like a Whitted ray tracer.
+    Whitted,
+}
+
+impl Integrator {
+    /// Whether the image shows values like normals or distances instead of light.
+    fn is_linear(self) -> bool {
+        matches!(self, Integrator::Normals | Integrator::Depth { .. } | Integrator::Uv | Integrator::MaterialId | Integrator::Heatmap { .. })
+    }
+}
+
+/// What a ray brings along from the bounces before it.
+#[derive(Copy, Clone)]
+struct Path<'a> {
+    // Bounces so far, counting invisible boundaries it passed through
+    depth: u32,
+    diffuse: u32,
+    specular: u32,
+    transmission: u32,
+    medium: Option<&'a Medium>,
+    bounce: Option<Bounce>,
+}
+
+impl<'a> Path<'a> {
+    fn new(medium: Option<&'a Medium>) -> Self {
+        Self { depth: 0, diffuse: 0, specular: 0, transmission: 0, medium, bounce: None }
+    }
+
+    /// Whether the path has bounced off anything yet, besides passing through the
+    /// boundaries of volumes.
+    fn bounced(&self) -> bool {
+        self.diffuse + self.specular + self.transmission > 0
+    }
+}
+
+/// The kinds of bounces that each have their own limit.
+#[derive(Copy, Clone)]
+enum BounceKind {
+    Diffuse,
+    Specular,
+    Transmission,
+}
+
+/// What kind of bounce a scattered ray makes, if any. Passing through the boundary
+/// of a volume isn't one.
+fn bounce_kind(hit_result: &HitResult, scatter: &Scatter) -> Option<BounceKind> {
+    let material = hit_result.material();
+    if matches!(material, Material::Volume { .. }) {
+        None
+    } else if scatter.ray.dir().dot(hit_result.normal()) < 0.0 {
+        Some(BounceKind::Transmission)
+    } else if material.is_specular() {
+        Some(BounceKind::Specular)
+    } else {
+        Some(BounceKind::Diffuse)
+    }
+}
+
+/// Light a sample found, split into light that reached the camera without bouncing
+/// first and light that did, so they can be clamped on their own.
+struct Found<T> {
+    direct: T,
+    indirect: T,
+}
+
+impl<T: Add<Output = T> + Copy> Found<T> {
+    fn add(&mut self, path: &Path, light: T) {
+        if path.bounced() {
+            self.indirect = self.indirect + light;
+        } else {
+            self.direct = self.direct + light;
+        }
+    }
+}
+
+/// Scale a color down to have no channel above `max`, keeping its hue.
+fn clamp(color: Vec3, max: f64) -> Vec3 {
+    let largest = color.max_component();
+    if largest > max { (max / largest) * color } else { color }
+}
+
+/// Where a ray was scattered from in a random direction, and the density per solid
+/// angle of picking that direction. Lights the ray hits could have been sampled from
+/// there too, so the two ways of finding them are weighed against each other.
+#[derive(Copy, Clone)]
+pub(crate) struct Bounce {
+    origin: Vec3,
+    // Of the surface it bounced off, if any
+    normal: Option<Vec3>,
+    pdf: f64,
+}
+
+/// The bounce of a ray scattered by a surface. Passing straight through an
+/// invisible boundary keeps the bounce from before it, and mirrors and glass pick
+/// a single direction, which lights can't be sampled in.
+pub(crate) fn next_bounce(ray: Ray, hit_result: &HitResult, scatter: &Scatter, previous: Option<Bounce>) -> Option<Bounce> {
+    let material = hit_result.material();
+    if matches!(material, Material::Volume { .. }) {
+        return previous;
+    }
+    let pdf = material.pdf(ray.dir().normalize(), hit_result.normal(), scatter.ray.dir().normalize());
+    (pdf > 0.0).then_some(Bounce { origin: hit_result.hit_point(), normal: Some(hit_result.normal()), pdf })
+}
+
+fn scatter_in_medium(ray: Ray, point: Vec3, medium: &Medium) -> (Ray, Option<Bounce>) {
+    let dir = medium.sample_phase(ray.dir());
+    let pdf = medium.phase(ray.dir().normalize().dot(dir.normalize()));
+    (Ray::new(point, dir), Some(Bounce { origin: point, normal: None, pdf }))
+}
+
+/// Light given off by what the ray hit. Lights that could also have been sampled from
+/// the last bounce only count as much as the power heuristic says.
+pub(crate) fn emitted(scene: &Scene, ray: Ray, hit_result: &HitResult, bounce: Option<Bounce>) -> Vec3 {
+    let emitted = hit_result.material().emitted(hit_result);
+    match bounce {
+        Some(bounce) if !emitted.is_near_zero() => {
+            let light_pdf = scene.emitter_pdf(bounce.origin, bounce.normal, ray.dir(), hit_result);
+            power_heuristic(bounce.pdf, light_pdf) * emitted
+        }
+        _ => emitted,
+    }
+}
+
+/// Light from the sky for a ray that didn't hit anything. The sun is sampled directly
+/// too, so it's weighed like lights are in `emitted`.
+pub(crate) fn background(scene: &Scene, ray: Ray, bounce: Option<Bounce>) -> Vec3 {
+    let sky = match scene.sky() {
+        Some(sky) => sky,
+        None => return Vec3::zero(),
+    };
+    let radiance = sky.radiance(ray.dir());
+    match bounce {
+        Some(bounce) => power_heuristic(bounce.pdf, sky.sun_pdf(ray.dir())) * radiance,
+        None => radiance,
+    }
+}
+
+/// Multiple importance sampling weight for a sample picked with density `pdf`, which
+/// could also have been picked with density `other_pdf`.
+pub(crate) fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+/// Light from the lights of the scene reflected towards where the ray came from.
+/// Point, spot and directional lights can't be hit by rays, so they are only found
+/// this way. Objects giving off light and the sun are sampled here too.
+pub(crate) fn surface_direct_light(scene: &Scene, ray: Ray, hit_result: &HitResult, medium: Option<&Medium>) -> Vec3 {
+    let dir_in = ray.dir().normalize();
+    let normal = hit_result.normal();
+    let material = hit_result.material();
+    direct_light(
+        scene,
+        hit_result.hit_point(),
+        Some(normal),
+        medium,
+        |dir| hit_result.spawn_ray(dir),
+        |dir| dir.dot(normal) * material.eval(dir_in, normal, dir),
+        |dir| material.pdf(dir_in, normal, dir),
+    )
+}
+
+/// Like `surface_direct_light`, for a ray scattered by a particle at `point`.
+fn medium_direct_light(scene: &Scene, ray: Ray, point: Vec3, medium: &Medium) -> Vec3 {
+    let dir_in = ray.dir().normalize();
+    direct_light(
+        scene,
+        point,
+        None,
+        Some(medium),
+        |dir| Ray::new(point, dir),
+        |dir| {
+            let phase = medium.phase(dir_in.dot(dir));
+            Vec3::new(phase, phase, phase)
+        },
+        |dir| medium.phase(dir_in.dot(dir)),
+    )
+}
+
+/// Light from each light arriving at `point`, and from one object giving off light
+/// and the sun, through shadow rays started by `spawn`. Points on surfaces have a
+/// `normal`. `scattering` gives how much of the light from a direction goes on
+/// towards the camera, and `pdf` how likely scattering would have picked that
+/// direction by itself.
+fn direct_light(
+    scene: &Scene,
+    point: Vec3,
+    normal: Option<Vec3>,
+    medium: Option<&Medium>,
+    spawn: impl Fn(Vec3) -> Ray,
+    scattering: impl Fn(Vec3) -> Vec3,
+    pdf: impl Fn(Vec3) -> f64,
+) -> Vec3 {
+    let mut total = Vec3::zero();
+    for light in scene.lights() {
+        if let Some(sample) = light.sample(point) {
+            let scattered = scattering(sample.dir);
+            if scattered.is_near_zero() {
+                continue;
+            }
+            let transmittance = transmittance(scene, spawn(sample.dir), sample.distance, medium);
+            total = total + scattered * sample.irradiance * transmittance;
+        }
+    }
+
+    if let Some(sample) = scene.sample_emitter(point, normal) {
+        let scattered = scattering(sample.dir);
+        let emitted = sample.hit.material().emitted(&sample.hit);
+        if !scattered.is_near_zero() && !emitted.is_near_zero() {
+            // Stop just short of the light so it doesn't block itself
+            let transmittance = transmittance(scene, spawn(sample.dir), sample.distance * (1.0 - 1e-4), medium);
+            let weight = power_heuristic(sample.pdf, pdf(sample.dir)) / sample.pdf;
+            total = total + weight * scattered * emitted * transmittance;
+        }
+    }
+
+    if let Some(sky) = scene.sky() {
+        if let Some(dir) = sky.sample_sun() {
+            let scattered = scattering(dir);
+            if !scattered.is_near_zero() {
+                let transmittance = transmittance(scene, spawn(dir), f64::INFINITY, medium);
+                let sun_pdf = sky.sun_pdf(dir);
+                let weight = power_heuristic(sun_pdf, pdf(dir)) / sun_pdf;
+                total = total + weight * scattered * sky.radiance(dir) * transmittance;
+            }
+        }
+    }
+    total
+}
+
+/// How much light gets through along a shadow ray with a normalized direction, up to
+/// `distance`. Surfaces block it, except for the invisible boundaries of volumes,
+/// and media in the way absorb and scatter some of it.
+fn transmittance<'a>(scene: &'a Scene, mut ray: Ray, mut distance: f64, mut medium: Option<&'a Medium>) -> Vec3 {
+    // Quick check for the usual case of nothing in the way
+    if medium.is_none() && !scene.occluded(ray, 0.0..distance) {
+        return Vec3::new(1.0, 1.0, 1.0);
+    }
+    let mut weight = Vec3::new(1.0, 1.0, 1.0);
+    loop {
+        let hit_result = scene.hit(ray, 0.0..distance);
+        if let Some(current) = medium {
+            let t_max = hit_result.as_ref().map_or(distance, |hit_result| hit_result.t());
+            match current.sample(ray, t_max).event {
+                MediumEvent::Pass { weight: pass_weight } => weight = weight * pass_weight,
+                _ => return Vec3::zero(),
+            }
+        }
+        let hit_result = match hit_result {
+            Some(hit_result) => hit_result,
+            None => return weight,
+        };
+        if !matches!(hit_result.material(), Material::Volume { .. }) {
+            return Vec3::zero();
+        }
+        medium = if hit_result.front_face() { hit_result.material().interior() } else { scene.fog() };
+        ray = hit_result.spawn_ray(ray.dir());
+        distance -= hit_result.t();
+    }
+}
+
+/// The medium a scattered ray travels through. Passing through glass or the boundary
+/// of a volume means entering or leaving what's inside, which for clear glass is no
+/// medium at all. Nested volumes are not tracked, so leaving one always goes back to
+/// the scene's fog.
+fn next_medium<'a>(scene: &'a Scene, hit_result: &HitResult<'a>, scatter: &Scatter, current: Option<&'a Medium>) -> Option<&'a Medium> {
+    let interior = match hit_result.material() {
+        Material::Glass { medium, .. } => medium.as_ref(),
+        Material::Volume { medium } => Some(medium),
+        _ => return current,
+    };
+    let transmitted = scatter.ray.dir().dot(hit_result.normal()) < 0.0;
+    if !transmitted {
+        current
+    } else if hit_result.front_face() {
+        interior
+    } else {
+        scene.fog()
+    }
+}
+
+fn to_rgb(color: Vec3, encode: fn(f64) -> f64) -> Rgb<u8> {
+    Rgb([
+        (encode(color.x()) * 255.0) as u8,
+        (encode(color.y()) * 255.0) as u8,
+        (encode(color.z()) * 255.0) as u8,
+    ])
+}
+
+fn gamma_correction(value: f64) -> f64 {
+    value.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+    use crate::light::Light;
+    use crate::material::RefractiveIndex;
+    use crate::scenes;
+    use crate::shapes::{Hittable, Quad, Sphere};
+    use crate::sky::Sky;
+    use crate::texture::Texture;
+
+    /// A white floor, which a camera looks at the middle of from the side.
+    fn floor() -> (Scene, Camera) {
+        let mut scene = Scene::new();
+        let white = Material::Diffuse { color: Vec3::new(1.0, 1.0, 1.0) };
+        scene.add(Quad::new(Vec3::new(-10.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 20.0), Vec3::new(20.0, 0.0, 0.0), white));
+        let camera = Camera::new(Vec3::new(0.5, 0.5, 0.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 1, 1, 1.0);
+        (scene, camera)
+    }
+
+    /// The floor lit by a point light straight above the middle.
+    fn lit_floor() -> (Scene, Camera) {
+        let (mut scene, camera) = floor();
+        // Radiance (1 / π) · I / h² = 0.25, which comes out as 127 after gamma
+        scene.add_light(Light::Point { position: Vec3::new(0.0, 1.0, 0.0), color: Vec3::new(1.0, 1.0, 1.0), intensity: 0.25 * PI, profile: None });
+        (scene, camera)
+    }
+
+    #[test]
+    fn point_light_matches_falloff() {
+        let (scene, camera) = lit_floor();
+        let image = Renderer::new().samples_per_pixel(4).render(&scene, &camera);
+        assert_eq!(image.get_pixel(0, 0).0, [127; 3]);
+    }
+
+    #[test]
+    fn shadows_block_point_lights() {
+        let (mut scene, camera) = lit_floor();
+        let renderer = Renderer::new().samples_per_pixel(4);
+        let lit = renderer.render(&scene, &camera);
+        // Between the light and the floor, but off to the side of the camera's view
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.5, 0.0), 0.2, Material::Light { color: Vec3::zero(), intensity: 0.0 }));
+        let hidden = renderer.render(&scene, &camera);
+        assert_ne!(lit.get_pixel(0, 0), hidden.get_pixel(0, 0));
+        assert_eq!(hidden.get_pixel(0, 0).0, [0; 3]);
+    }
+
+    #[test]
+    fn sphere_light_matches_solid_angle() {
+        // Straight below a sphere of radiance L, the floor reflects L (r / d)² = 0.25
+        let light = Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity: 25.0 };
+        let (mut scene, camera) = floor();
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.1, light));
+        let image = Renderer::new().samples_per_pixel(4).render(&scene, &camera);
+        assert_eq!(image.get_pixel(0, 0).0, [127; 3]);
+    }
+
+    #[test]
+    fn one_sided_lights_face_one_way() {
+        let emissive = |one_sided| Material::Emissive { emission: Texture::Constant(Vec3::new(1.0, 1.0, 1.0)), intensity: 1.0, one_sided };
+        // Facing up, away from the floor
+        let ceiling = |one_sided| Quad::new(Vec3::new(-1.0, 1.0, -1.0), Vec3::new(0.0, 0.0, 2.0), Vec3::new(2.0, 0.0, 0.0), emissive(one_sided));
+        let renderer = Renderer::new().samples_per_pixel(4);
+        for (one_sided, lit) in [(false, true), (true, false)] {
+            let (mut scene, camera) = floor();
+            scene.add(ceiling(one_sided));
+            assert_eq!(renderer.render(&scene, &camera).get_pixel(0, 0).0 != [0; 3], lit);
+        }
+    }
+
+    #[test]
+    fn sky_lights_floor_like_the_ground() {
+        // A white floor reflects what the sky's own ground does
+        let (mut scene, camera) = floor();
+        let sky = Sky::new(90.0, 0.0, 3.0, Vec3::new(1.0, 1.0, 1.0));
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let sky = sky.clone().with_intensity(0.25 / sky.radiance(down).luminance());
+        let radiance = sky.radiance(down);
+        let expected = [radiance.x(), radiance.y(), radiance.z()].map(|value| (255.0 * value.sqrt()) as i32);
+        scene.set_sky(sky);
+        let image = Renderer::new().samples_per_pixel(64).seed(1).render(&scene, &camera);
+        let pixel = image.get_pixel(0, 0).0;
+        for (channel, expected) in pixel.into_iter().zip(expected) {
+            assert!((channel as i32 - expected).abs() <= 3, "{:?} != {:?}", pixel, expected);
+        }
+    }
+
+    #[test]
+    fn bidirectional_matches_path_tracing() {
+        let renderer = Renderer::new().samples_per_pixel(16).seed(1).integrator(Integrator::Bidirectional);
+        let (scene, camera) = lit_floor();
+        let image = renderer.render(&scene, &camera);
+        assert!(image.get_pixel(0, 0).0.iter().all(|&channel| channel.abs_diff(127) <= 2), "{:?}", image.get_pixel(0, 0));
+
+        // Lights are sampled all over, so half the samples land on their far side
+        let light = Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity: 25.0 };
+        let (mut scene, camera) = floor();
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.1, light));
+        let image = renderer.samples_per_pixel(1024).render(&scene, &camera);
+        assert!(image.get_pixel(0, 0).0.iter().all(|&channel| channel.abs_diff(127) <= 2), "{:?}", image.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn bidirectional_finds_lights_behind_glass() {
+        // A point light inside a glass ball can only reach the floor through the glass
+        let (mut scene, _) = lit_floor();
+        // Light from the light has to land on the pixel by itself, so look at a lot of floor
+        let camera = Camera::new(Vec3::new(1.0, 0.5, 0.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 1, 1, 60.0);
+        let glass = Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: Vec3::new(1.0, 1.0, 1.0), thin_walled: false, medium: None };
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.3, glass));
+        let renderer = Renderer::new().samples_per_pixel(16).seed(1);
+        assert_eq!(renderer.render(&scene, &camera).get_pixel(0, 0).0, [0; 3]);
+        let image = renderer.integrator(Integrator::Bidirectional).render(&scene, &camera);
+        assert!(image.get_pixel(0, 0).0[0] > 20, "{:?}", image.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn photon_mapping_matches_path_tracing() {
+        // A ceiling above the floor, which only lights it after a bounce
+        let (mut scene, camera) = lit_floor();
+        let white = Material::Diffuse { color: Vec3::new(1.0, 1.0, 1.0) };
+        scene.add(Quad::new(Vec3::new(-10.0, 2.0, -10.0), Vec3::new(20.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 20.0), white));
+        // Light keeps bouncing between them, so both stop after as many bounces
+        let path = Renderer::new().samples_per_pixel(256).max_depth(5).seed(1).render(&scene, &camera);
+        let renderer = Renderer::new().samples_per_pixel(16).max_depth(5).seed(1).integrator(Integrator::PhotonMapping { photons: 5000, radius: 0.2 });
+        let image = renderer.render(&scene, &camera);
+        let (expected, pixel) = (path.get_pixel(0, 0).0, image.get_pixel(0, 0).0);
+        assert!(pixel.iter().zip(expected).all(|(&channel, expected)| channel.abs_diff(expected) <= 3), "{:?} != {:?}", pixel, expected);
+
+        // The point light inside a glass ball from `bidirectional_finds_lights_behind_glass`
+        let (mut scene, camera) = lit_floor();
+        let glass = Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: Vec3::new(1.0, 1.0, 1.0), thin_walled: false, medium: None };
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.3, glass));
+        let image = renderer.render(&scene, &camera);
+        assert!(image.get_pixel(0, 0).0[0] > 100, "{:?}", image.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn metropolis_matches_path_tracing() {
+        // Brighter right under the light, so the chains spend more time there
+        let (scene, _) = lit_floor();
+        let camera = Camera::new(Vec3::new(0.5, 0.5, 0.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 4, 4, 90.0);
+        let path = Renderer::new().samples_per_pixel(256).seed(1).render(&scene, &camera);
+        let renderer = Renderer::new().samples_per_pixel(4096).seed(1).integrator(Integrator::Metropolis { bootstrap: 10000, chains: 64 });
+        let image = renderer.render(&scene, &camera);
+        for (expected, pixel) in path.pixels().zip(image.pixels()) {
+            assert!(pixel.0.iter().zip(expected.0).all(|(&channel, expected)| channel.abs_diff(expected) <= 5), "{:?} != {:?}", pixel, expected);
+        }
+    }
+
+    #[test]
+    fn russian_roulette_keeps_brightness() {
+        // Light bounces between the floor and a ceiling for a long time
+        let (mut scene, camera) = lit_floor();
+        let white = Material::Diffuse { color: Vec3::new(0.8, 0.8, 0.8) };
+        scene.add(Quad::new(Vec3::new(-10.0, 2.0, -10.0), Vec3::new(20.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 20.0), white));
+        let renderer = Renderer::new().samples_per_pixel(1024).seed(1).max_depth(32).diffuse_depth(32);
+        let all = renderer.clone().russian_roulette(u32::MAX).render(&scene, &camera);
+        let roulette = renderer.russian_roulette(1).render(&scene, &camera);
+        let (expected, pixel) = (all.get_pixel(0, 0).0, roulette.get_pixel(0, 0).0);
+        assert!(pixel.iter().zip(expected).all(|(&channel, expected)| channel.abs_diff(expected) <= 2), "{:?} != {:?}", pixel, expected);
+    }
+
+    #[test]
+    fn limits_each_kind_of_bounce() {
+        // The floor seen through a glass ball, which takes going through glass twice
+        let (mut scene, camera) = lit_floor();
+        let glass = Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: Vec3::new(1.0, 1.0, 1.0), thin_walled: false, medium: None };
+        scene.add_sphere(Sphere::new(Vec3::new(0.25, 0.25, 0.0), 0.1, glass));
+        let renderer = Renderer::new().samples_per_pixel(4).seed(1);
+        assert_ne!(renderer.render(&scene, &camera).get_pixel(0, 0).0, [0; 3]);
+        assert_ne!(renderer.clone().transmission_depth(3).render(&scene, &camera).get_pixel(0, 0).0, [0; 3]);
+        assert_eq!(renderer.clone().transmission_depth(2).render(&scene, &camera).get_pixel(0, 0).0, [0; 3]);
+        assert_eq!(renderer.max_depth(2).render(&scene, &camera).get_pixel(0, 0).0, [0; 3]);
+    }
+
+    #[test]
+    fn clamps_direct_and_indirect_light() {
+        let (scene, camera) = lit_floor();
+        let renderer = Renderer::new().samples_per_pixel(4);
+        // 0.25 without clamping, which only comes straight from the light
+        assert_eq!(renderer.clone().clamp_indirect(0.01).render(&scene, &camera).get_pixel(0, 0).0, [127; 3]);
+        let expected = (0.1f64.sqrt() * 255.0) as u8;
+        assert_eq!(renderer.clamp_direct(0.1).render(&scene, &camera).get_pixel(0, 0).0, [expected; 3]);
+    }
+
+    #[test]
+    fn clear_glass_leaves_fog() {
+        let mut scene = Scene::new();
+        scene.set_fog(Medium::new(Vec3::zero(), Vec3::new(0.1, 0.1, 0.1), 0.0));
+        let glass = Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: Vec3::new(1.0, 1.0, 1.0), thin_walled: false, medium: None };
+        let sphere = Sphere::new(Vec3::zero(), 1.0, glass);
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let fog = scene.fog();
+
+        let enter = sphere.hit(ray, 0.0..f64::INFINITY).unwrap();
+        let through = Scatter { ray: enter.spawn_ray(ray.dir()), attenuation: Vec3::new(1.0, 1.0, 1.0), dispersive: false };
+        assert!(next_medium(&scene, &enter, &through, fog).is_none());
+        let reflected = Scatter { ray: enter.spawn_ray(-ray.dir()), ..through };
+        assert!(next_medium(&scene, &enter, &reflected, fog).is_some());
+
+        let leave = sphere.hit(through.ray, 0.0..f64::INFINITY).unwrap();
+        let out = Scatter { ray: leave.spawn_ray(ray.dir()), ..through };
+        assert!(next_medium(&scene, &leave, &out, None).is_some());
+    }
+
+    #[test]
+    fn previews_skip_gamma() {
+        let (scene, camera) = floor();
+        let image = Renderer::new().samples_per_pixel(4).integrator(Integrator::Normals).render(&scene, &camera);
+        assert_eq!(image.get_pixel(0, 0).0, [127, 255, 127]);
+    }
+
+    #[test]
+    fn renders_at_camera_resolution() {
+        let (scene, camera) = scenes::furnace(7, 3);
+        // Fewer samples than fit in a packet
+        let image = Renderer::new().samples_per_pixel(3).render(&scene, &camera);
+        assert_eq!(image.dimensions(), (7, 3));
+    }
+
+    #[test]
+    fn seeded_renders_repeat() {
+        let (scene, camera) = scenes::spheres(8, 6);
+        let renderer = Renderer::new().samples_per_pixel(6).seed(1);
+        assert_eq!(renderer.render(&scene, &camera), renderer.render(&scene, &camera));
+    }
+}
@@ -1,61 +1,721 @@
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::light::Light;
+use crate::light_bvh::{LightBounds, LightBvh};
+use crate::medium::Medium;
+use crate::packet::{RayPacket, SphereBlock, TriangleBlock, WIDTH};
 use crate::ray::Ray;
+use crate::material::Material;
 use crate::shapes::{HitResult, Hittable, InfinitePlane, Sphere, Triangle};
+use crate::sky::Sky;
+use crate::util::AliasTable;
+use crate::vector::Vec3;
+
+/// Something in a scene. The most common shapes are stored directly, so checking
+/// them for hits doesn't go through a pointer.
+pub enum Object {
+    Sphere(Sphere),
+    InfinitePlane(InfinitePlane),
+    Triangle(Triangle),
+    Other(Box<dyn Hittable>),
+}
+
+impl Object {
+    fn as_hittable(&self) -> &dyn Hittable {
+        match self {
+            Object::Sphere(sphere) => sphere,
+            Object::InfinitePlane(plane) => plane,
+            Object::Triangle(triangle) => triangle,
+            Object::Other(other) => other.as_ref(),
+        }
+    }
+}
+
+impl Hittable for Object {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        match self {
+            Object::Sphere(sphere) => sphere.hit(ray, t_range),
+            Object::InfinitePlane(plane) => plane.hit(ray, t_range),
+            Object::Triangle(triangle) => triangle.hit(ray, t_range),
+            Object::Other(other) => other.hit(ray, t_range),
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.as_hittable().bounding_box()
+    }
+
+    fn sample_point(&self, origin: Vec3) -> Option<Vec3> {
+        self.as_hittable().sample_point(origin)
+    }
+
+    fn sample_area(&self) -> Option<(Vec3, Vec3)> {
+        self.as_hittable().sample_area()
+    }
+
+    fn pdf(&self, origin: Vec3, dir: Vec3) -> f64 {
+        self.as_hittable().pdf(origin, dir)
+    }
+
+    fn area(&self) -> f64 {
+        self.as_hittable().area()
+    }
+
+    fn material(&self) -> Option<&Material> {
+        self.as_hittable().material()
+    }
+
+    fn plane_normal(&self) -> Option<Vec3> {
+        self.as_hittable().plane_normal()
+    }
+}
+
+/// Refers to an object in a scene. Stays invalid once the object is removed, even
+/// if another object takes its place.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectHandle {
+    index: usize,
+    generation: u32,
+}
+
+struct Slot {
+    generation: u32,
+    object: Option<Object>,
+}
+
+/// The objects of a BVH leaf. Spheres and triangles are also copied into blocks, to
+/// rule most of them out at once before hitting them one by one.
+struct Leaf {
+    spheres: Vec<usize>,
+    sphere_block: SphereBlock,
+    triangles: Vec<usize>,
+    triangle_block: TriangleBlock,
+    others: Vec<usize>,
+}
+
+/// Everything needed to find hits quickly. Objects are referred to by slot index.
+struct Accelerator {
+    bvh: Bvh,
+    leaves: Vec<Leaf>,
+    // Infinite planes and such, which can't go in the BVH
+    unbounded: Vec<usize>,
+    // Objects giving off light, with both ways of picking them
+    emitters: Vec<usize>,
+    emitter_table: Option<AliasTable>,
+    light_bvh: Option<LightBvh>,
+    // Where each slot is in `emitters`
+    emitter_of_slot: Vec<Option<usize>>,
+}
+
+/// How objects giving off light are picked to send shadow rays to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LightSampling {
+    /// By how much light they give off in total. Cheap, and good enough for a few
+    /// lights.
+    Power,
+    /// By how much light they could give off towards the point being lit, going
+    /// down a tree over all of them. Much less noisy with many lights.
+    #[default]
+    Tree,
+}
+
+/// A point on a light that a shadow ray can be sent to.
+pub(crate) struct EmitterSample<'a> {
+    /// The point, as hit by a ray from where the light was sampled for.
+    pub hit: HitResult<'a>,
+    /// Normalized, towards the point.
+    pub dir: Vec3,
+    pub distance: f64,
+    /// Density per solid angle of picking this direction.
+    pub pdf: f64,
+}
 
 pub struct Scene {
-    spheres: Vec<Sphere>,
-    infinite_planes: Vec<InfinitePlane>,
-    triangles: Vec<Triangle>,
+    slots: Vec<Slot>,
+    // Slots of removed objects that can be reused
+    free: Vec<usize>,
+    fog: Option<Medium>,
+    sky: Option<Sky>,
+    lights: Vec<Light>,
+    light_sampling: LightSampling,
+    // Built the first time the scene is hit, and thrown away when it changes
+    accelerator: OnceLock<Accelerator>,
+    rays_traced: AtomicU64,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scene {
     pub fn new() -> Self {
         Self {
-            spheres: Vec::new(),
-            infinite_planes: Vec::new(),
-            triangles: Vec::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            fog: None,
+            sky: None,
+            lights: Vec::new(),
+            light_sampling: LightSampling::default(),
+            accelerator: OnceLock::new(),
+            rays_traced: AtomicU64::new(0),
         }
     }
 
-    pub fn add_sphere(&mut self, sphere: Sphere) {
-        self.spheres.push(sphere);
+    fn insert(&mut self, object: Object) -> ObjectHandle {
+        self.accelerator.take();
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.object = Some(object);
+                ObjectHandle { index, generation: slot.generation }
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, object: Some(object) });
+                ObjectHandle { index: self.slots.len() - 1, generation: 0 }
+            }
+        }
     }
 
-    pub fn add_inf_plane(&mut self, plane: InfinitePlane) {
-        self.infinite_planes.push(plane);
+    pub fn add_sphere(&mut self, sphere: Sphere) -> ObjectHandle {
+        self.insert(Object::Sphere(sphere))
     }
 
-    pub fn add_triangle(&mut self, triangle: Triangle) {
-        self.triangles.push(triangle);
+    pub fn add_inf_plane(&mut self, plane: InfinitePlane) -> ObjectHandle {
+        self.insert(Object::InfinitePlane(plane))
     }
 
-    pub fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult> {
-        let mut closest: Option<HitResult> = None;
+    pub fn add_triangle(&mut self, triangle: Triangle) -> ObjectHandle {
+        self.insert(Object::Triangle(triangle))
+    }
+
+    /// Add any kind of shape. Spheres, planes and triangles are faster to hit when
+    /// added with their own methods.
+    pub fn add(&mut self, object: impl Hittable + 'static) -> ObjectHandle {
+        self.insert(Object::Other(Box::new(object)))
+    }
+
+    pub fn get(&self, handle: ObjectHandle) -> Option<&Object> {
+        let slot = self.slots.get(handle.index)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.object.as_ref()
+    }
+
+    /// Change an object, for example by replacing it with a moved copy.
+    pub fn get_mut(&mut self, handle: ObjectHandle) -> Option<&mut Object> {
+        let slot = self.slots.get_mut(handle.index)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        self.accelerator.take();
+        slot.object.as_mut()
+    }
+
+    pub fn remove(&mut self, handle: ObjectHandle) -> Option<Object> {
+        let slot = self.slots.get_mut(handle.index)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let object = slot.object.take()?;
+        slot.generation += 1;
+        self.accelerator.take();
+        self.free.push(handle.index);
+        Some(object)
+    }
+
+    /// All objects in the scene with their handles.
+    pub fn objects(&self) -> impl Iterator<Item = (ObjectHandle, &Object)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let handle = ObjectHandle { index, generation: slot.generation };
+            slot.object.as_ref().map(|object| (handle, object))
+        })
+    }
+
+    /// Fill all space outside of other volumes with a medium.
+    pub fn set_fog(&mut self, fog: Medium) {
+        self.fog = Some(fog);
+    }
+
+    pub fn fog(&self) -> Option<&Medium> {
+        self.fog.as_ref()
+    }
+
+    /// Light up everything rays escape to with a sky, instead of leaving it black.
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.sky = Some(sky);
+    }
+
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref()
+    }
+
+    /// Add a point, spot or directional light. Lights on surfaces are added as
+    /// objects with `Material::Light` or `Material::Emissive` instead.
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn set_light_sampling(&mut self, light_sampling: LightSampling) {
+        self.light_sampling = light_sampling;
+    }
+
+    pub fn light_sampling(&self) -> LightSampling {
+        self.light_sampling
+    }
+
+    pub(crate) fn object(&self, index: usize) -> &Object {
+        self.slots[index].object.as_ref().unwrap()
+    }
+
+    /// Build the BVH now instead of when the scene is first hit.
+    pub fn build_accelerator(&self) {
+        self.accelerator();
+    }
+
+    /// How many rays have been traced through the scene, including shadow rays.
+    pub fn rays_traced(&self) -> u64 {
+        self.rays_traced.load(Ordering::Relaxed)
+    }
+
+    fn accelerator(&self) -> &Accelerator {
+        self.accelerator.get_or_init(|| {
+            let (bounded, unbounded): (Vec<_>, Vec<_>) = self.slots.iter()
+                .enumerate()
+                .filter_map(|(index, slot)| slot.object.as_ref().map(|object| (index, object.bounding_box())))
+                .partition(|(_, bounds)| bounds.size().norm().is_finite());
+            let bvh = Bvh::new(&bounded);
+
+            let leaves = bvh.leaves().iter().map(|objects| {
+                let of_kind = |kind: fn(&Object) -> bool| objects.iter().copied().filter(|&index| kind(self.object(index))).collect::<Vec<_>>();
+                let spheres = of_kind(|object| matches!(object, Object::Sphere(_)));
+                let triangles = of_kind(|object| matches!(object, Object::Triangle(_)));
+                let others = of_kind(|object| !matches!(object, Object::Sphere(_) | Object::Triangle(_)));
+                let sphere_block = SphereBlock::new(&spheres.iter().map(|&index| match self.object(index) {
+                    Object::Sphere(sphere) => (sphere.center(), sphere.radius()),
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>());
+                let triangle_block = TriangleBlock::new(&triangles.iter().map(|&index| match self.object(index) {
+                    Object::Triangle(triangle) => triangle.vertices(),
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>());
+                Leaf { spheres, sphere_block, triangles, triangle_block, others }
+            }).collect();
+
+            // Any shape that can be sampled can be a light, like every triangle of a mesh
+            let power = |index: usize| {
+                let object = self.object(index);
+                object.material().map_or(0.0, |material| material.power(object.area()))
+            };
+            let emitters: Vec<usize> = (0..self.slots.len())
+                .filter(|&index| self.slots[index].object.is_some() && power(index) > 0.0)
+                .collect();
+            let emitter_table = AliasTable::new(&emitters.iter().map(|&index| power(index)).collect::<Vec<_>>());
+            let light_bounds: Vec<LightBounds> = emitters.iter().map(|&index| {
+                let object = self.object(index);
+                let one_sided = matches!(object.material(), Some(Material::Emissive { one_sided: true, .. }));
+                match object.plane_normal() {
+                    Some(normal) if one_sided => LightBounds::facing(object.bounding_box(), power(index), normal),
+                    _ => LightBounds::everywhere(object.bounding_box(), power(index)),
+                }
+            }).collect();
+            let light_bvh = LightBvh::new(&light_bounds);
+            let mut emitter_of_slot = vec![None; self.slots.len()];
+            for (i, &index) in emitters.iter().enumerate() {
+                emitter_of_slot[index] = Some(i);
+            }
+
+            Accelerator {
+                bvh,
+                leaves,
+                unbounded: unbounded.into_iter().map(|(index, _)| index).collect(),
+                emitters,
+                emitter_table,
+                light_bvh,
+                emitter_of_slot,
+            }
+        })
+    }
+
+    /// Closest hit with the objects of a leaf, if closer than `closest`. Gives the t
+    /// of the closest hit so far.
+    fn hit_leaf<'a>(&'a self, leaf: &Leaf, ray: Ray, t_range: Range<f64>, closest: &mut Option<HitResult<'a>>) -> f64 {
         let mut closest_t = t_range.end;
+        let sphere_candidates = leaf.sphere_block.candidates(ray, t_range.clone());
+        let triangle_candidates = leaf.triangle_block.candidates(ray, t_range.clone());
+        let spheres = leaf.spheres.iter().enumerate().filter(|&(lane, _)| sphere_candidates[lane]);
+        let triangles = leaf.triangles.iter().enumerate().filter(|&(lane, _)| triangle_candidates[lane]);
 
-        for sphere in &self.spheres {
-            if let Some(hit_result) = sphere.hit(ray, t_range.start..closest_t) {
+        for (_, &index) in spheres.chain(triangles).chain(leaf.others.iter().enumerate()) {
+            if let Some(hit_result) = self.object(index).hit(ray, t_range.start..closest_t) {
                 closest_t = hit_result.t();
-                closest = Some(hit_result);
+                *closest = Some(hit_result.with_object(index));
             }
         }
-        for inf_plane in &self.infinite_planes {
-            if let Some(hit_result) = inf_plane.hit(ray, t_range.start..closest_t) {
+        closest_t
+    }
+
+    fn hit_unbounded<'a>(&'a self, ray: Ray, t_range: Range<f64>, closest: &mut Option<HitResult<'a>>) -> f64 {
+        let mut closest_t = t_range.end;
+        for &index in &self.accelerator().unbounded {
+            if let Some(hit_result) = self.object(index).hit(ray, t_range.start..closest_t) {
                 closest_t = hit_result.t();
-                closest = Some(hit_result);
+                *closest = Some(hit_result.with_object(index));
             }
         }
-        for triangle in &self.triangles {
-            if let Some(hit_result) = triangle.hit(ray, t_range.start..closest_t) {
-                closest_t = hit_result.t();
-                closest = Some(hit_result);
+        closest_t
+    }
+
+    pub fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        self.rays_traced.fetch_add(1, Ordering::Relaxed);
+        let accelerator = self.accelerator();
+        let mut closest: Option<HitResult> = None;
+        let closest_t = self.hit_unbounded(ray, t_range.clone(), &mut closest);
+
+        accelerator.bvh.traverse(ray, t_range.start..closest_t, |leaf, t_range| {
+            self.hit_leaf(&accelerator.leaves[leaf], ray, t_range, &mut closest)
+        });
+        closest
+    }
+
+    /// How many objects `hit` tests the ray against, for seeing where the scene is
+    /// slow to trace.
+    pub(crate) fn count_tests(&self, ray: Ray, t_range: Range<f64>) -> u32 {
+        let accelerator = self.accelerator();
+        let mut closest: Option<HitResult> = None;
+        let closest_t = self.hit_unbounded(ray, t_range.clone(), &mut closest);
+        let mut tests = accelerator.unbounded.len();
+        accelerator.bvh.traverse(ray, t_range.start..closest_t, |leaf, t_range| {
+            let leaf = &accelerator.leaves[leaf];
+            tests += leaf.spheres.len() + leaf.triangles.len() + leaf.others.len();
+            self.hit_leaf(leaf, ray, t_range, &mut closest)
+        });
+        tests as u32
+    }
+
+    /// Closest hits of several rays at once. Gives the same hits as `hit`, but is
+    /// faster for rays going the same way, like the samples of a pixel.
+    pub(crate) fn hit_packet(&self, packet: &RayPacket, t_range: Range<f64>) -> [Option<HitResult<'_>>; WIDTH] {
+        self.rays_traced.fetch_add(WIDTH as u64, Ordering::Relaxed);
+        let accelerator = self.accelerator();
+        let mut closest: [Option<HitResult>; WIDTH] = Default::default();
+        let mut t_max = [t_range.end; WIDTH];
+        for lane in 0..WIDTH {
+            t_max[lane] = self.hit_unbounded(packet.rays()[lane], t_range.clone(), &mut closest[lane]);
+        }
+
+        accelerator.bvh.traverse_packet(packet, t_range.start, &mut t_max, |leaf, hit, t_max| {
+            for lane in (0..WIDTH).filter(|&lane| hit[lane]) {
+                let leaf = &accelerator.leaves[leaf];
+                t_max[lane] = self.hit_leaf(leaf, packet.rays()[lane], t_range.start..t_max[lane], &mut closest[lane]);
+            }
+        });
+        closest
+    }
+
+    /// Whether anything is in the way of the ray within `t_range`, like for shadow
+    /// rays. Stops at the first hit found instead of looking for the closest.
+    pub fn occluded(&self, ray: Ray, t_range: Range<f64>) -> bool {
+        self.rays_traced.fetch_add(1, Ordering::Relaxed);
+        let accelerator = self.accelerator();
+        let mut hit = None;
+        if self.hit_unbounded(ray, t_range.clone(), &mut hit) < t_range.end {
+            return true;
+        }
+        accelerator.bvh.traverse(ray, t_range.clone(), |leaf, t_range| {
+            self.hit_leaf(&accelerator.leaves[leaf], ray, t_range.clone(), &mut hit);
+            if hit.is_some() { t_range.start } else { t_range.end }
+        });
+        hit.is_some()
+    }
+
+    /// The closest hit on something other than the invisible boundary of a volume,
+    /// for integrators that don't follow media.
+    pub(crate) fn hit_surface(&self, mut ray: Ray) -> Option<HitResult<'_>> {
+        loop {
+            match self.hit(ray, 0.0..f64::INFINITY) {
+                Some(hit) if matches!(hit.material(), Material::Volume { .. }) => ray = hit.spawn_ray(ray.dir()),
+                hit => return hit,
+            }
+        }
+    }
+
+    /// `occluded` for several rays at once. Only the benchmarks trace shadow rays in
+    /// packets so far.
+    #[cfg_attr(not(feature = "bench"), allow(dead_code))]
+    pub(crate) fn occluded_packet(&self, packet: &RayPacket, t_range: Range<f64>) -> [bool; WIDTH] {
+        self.rays_traced.fetch_add(WIDTH as u64, Ordering::Relaxed);
+        let accelerator = self.accelerator();
+        let mut occluded = [false; WIDTH];
+        let mut t_max = [t_range.end; WIDTH];
+        for lane in 0..WIDTH {
+            if self.hit_unbounded(packet.rays()[lane], t_range.clone(), &mut None) < t_range.end {
+                occluded[lane] = true;
+                t_max[lane] = t_range.start;
+            }
+        }
+
+        accelerator.bvh.traverse_packet(packet, t_range.start, &mut t_max, |leaf, hit, t_max| {
+            for lane in 0..WIDTH {
+                if !hit[lane] || occluded[lane] {
+                    continue;
+                }
+                let leaf = &accelerator.leaves[leaf];
+                if self.hit_leaf(leaf, packet.rays()[lane], t_range.start..t_max[lane], &mut None) < t_max[lane] {
+                    occluded[lane] = true;
+                    t_max[lane] = t_range.start;
+                }
+            }
+        });
+        occluded
+    }
+
+    /// Pick a point on one of the objects giving off light, to light `origin` with.
+    /// Points on surfaces have a `normal`, and only take light from that side.
+    pub(crate) fn sample_emitter(&self, origin: Vec3, normal: Option<Vec3>) -> Option<EmitterSample<'_>> {
+        let accelerator = self.accelerator();
+        let (picked, probability) = match self.light_sampling {
+            LightSampling::Power => {
+                let table = accelerator.emitter_table.as_ref()?;
+                let picked = table.sample();
+                (picked, table.probability(picked))
             }
+            LightSampling::Tree => accelerator.light_bvh.as_ref()?.sample(origin, normal)?,
+        };
+        let index = accelerator.emitters[picked];
+        let object = self.object(index);
+        let dir = (object.sample_point(origin)? - origin).normalize();
+        let pdf = probability * object.pdf(origin, dir);
+        let hit = object.hit(Ray::new(origin, dir), 0.0..f64::INFINITY)?;
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(EmitterSample { distance: hit.t(), hit: hit.with_object(index), dir, pdf })
+    }
+
+    /// Density per solid angle of `sample_emitter` picking the direction `dir` from
+    /// `origin`, which hit the light `hit_result`.
+    pub(crate) fn emitter_pdf(&self, origin: Vec3, normal: Option<Vec3>, dir: Vec3, hit_result: &HitResult) -> f64 {
+        let accelerator = self.accelerator();
+        let picked = match hit_result.object().and_then(|index| accelerator.emitter_of_slot[index]) {
+            Some(picked) => picked,
+            None => return 0.0,
+        };
+        let probability = match self.light_sampling {
+            LightSampling::Power => accelerator.emitter_table.as_ref().map_or(0.0, |table| table.probability(picked)),
+            LightSampling::Tree => accelerator.light_bvh.as_ref().map_or(0.0, |bvh| bvh.probability(picked, origin, normal)),
+        };
+        if probability == 0.0 {
+            return 0.0;
         }
+        probability * self.object(accelerator.emitters[picked]).pdf(origin, dir)
+    }
+
+    /// Slots of the objects giving off light.
+    pub(crate) fn emitters(&self) -> &[usize] {
+        &self.accelerator().emitters
+    }
 
-        return closest;
+    /// Box around everything in the scene.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.objects()
+            .map(|(_, object)| object.bounding_box())
+            .reduce(|a, b| a.union(&b))
     }
 
     pub fn count(&self) -> usize {
-        self.spheres.len() + self.infinite_planes.len() + self.triangles.len()
+        self.slots.len() - self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use std::f64::consts::PI;
+    use crate::{scenes, util};
+    use crate::shapes::Quad;
+    use crate::texture::Texture;
+
+    fn material() -> Material {
+        Material::Diffuse { color: Vec3::new(0.5, 0.5, 0.5) }
+    }
+
+    fn ray_along_z() -> Ray {
+        Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0))
+    }
+
+    #[test]
+    fn closest_of_mixed_objects() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, material()));
+        scene.add(Quad::new(Vec3::new(-1.0, -1.0, 2.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0), material()));
+        assert_eq!(scene.count(), 2);
+        assert_eq!(scene.hit(ray_along_z(), 0.001..f64::INFINITY).unwrap().t(), 2.0);
+    }
+
+    #[test]
+    fn remove_and_reuse() {
+        let mut scene = Scene::new();
+        let near = scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 3.0), 1.0, material()));
+        let far = scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 6.0), 1.0, material()));
+
+        assert!(scene.remove(near).is_some());
+        assert_eq!(scene.count(), 1);
+        assert_eq!(scene.hit(ray_along_z(), 0.001..f64::INFINITY).unwrap().t(), 5.0);
+
+        // The old handle doesn't refer to the object taking over its slot
+        let replacement = scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 10.0), 1.0, material()));
+        assert!(scene.get(near).is_none());
+        assert!(scene.remove(near).is_none());
+        assert!(scene.get(replacement).is_some());
+        assert_eq!(scene.objects().count(), 2);
+
+        // Move the far sphere out of the way
+        *scene.get_mut(far).unwrap() = Object::Sphere(Sphere::new(Vec3::new(5.0, 0.0, 0.0), 1.0, material()));
+        assert_eq!(scene.hit(ray_along_z(), 0.001..f64::INFINITY).unwrap().t(), 9.0);
+    }
+
+    fn random_scene() -> Scene {
+        let mut scene = Scene::new();
+        for _ in 0..200 {
+            scene.add_sphere(Sphere::new(10.0 * Vec3::random(), 0.5, material()));
+            let corner = 10.0 * Vec3::random();
+            scene.add_triangle(Triangle::new(corner, corner + Vec3::random(), corner + Vec3::random(), material()));
+        }
+        scene.add(Quad::new(Vec3::new(-1.0, -1.0, 2.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0), material()));
+        scene.add_inf_plane(InfinitePlane::new(-12.0, Vec3::new(0.0, 1.0, 0.0), material()));
+        scene
+    }
+
+    fn brute_force_hit(scene: &Scene, ray: Ray) -> Option<f64> {
+        scene.objects()
+            .filter_map(|(_, object)| object.hit(ray, 0.0..f64::INFINITY).map(|hit| hit.t()))
+            .min_by(f64::total_cmp)
+    }
+
+    #[test]
+    fn accelerated_hits_match_brute_force() {
+        let scene = random_scene();
+        for _ in 0..500 {
+            // Rays of a packet starting at the same point, as from a camera
+            let origin = 15.0 * Vec3::random();
+            let rays: [Ray; WIDTH] = std::array::from_fn(|_| Ray::new(origin, Vec3::random()));
+            let packet_hits = scene.hit_packet(&RayPacket::new(rays), 0.0..f64::INFINITY);
+            let occluded = scene.occluded_packet(&RayPacket::new(rays), 0.0..f64::INFINITY);
+
+            for (lane, ray) in rays.into_iter().enumerate() {
+                let expected = brute_force_hit(&scene, ray);
+                assert_eq!(scene.hit(ray, 0.0..f64::INFINITY).map(|hit| hit.t()), expected);
+                assert_eq!(packet_hits[lane].as_ref().map(|hit| hit.t()), expected);
+                assert_eq!(scene.occluded(ray, 0.0..f64::INFINITY), expected.is_some());
+                assert_eq!(occluded[lane], expected.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn occlusion_within_range() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, material()));
+        assert!(scene.occluded(ray_along_z(), 0.0..4.5));
+        assert!(!scene.occluded(ray_along_z(), 0.0..3.5));
+        let rays = [ray_along_z(); WIDTH];
+        let occluded = scene.occluded_packet(&RayPacket::new(rays), 0.0..3.5);
+        assert_eq!(occluded, [false; WIDTH]);
+    }
+
+    #[test]
+    fn emitters_picked_by_power() {
+        let light = |intensity| Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity };
+        let mut scene = Scene::new();
+        scene.set_light_sampling(LightSampling::Power);
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, material()));
+        scene.add(Quad::new(Vec3::new(-1.0, 2.0, -1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), light(1.0)));
+        scene.add(Quad::new(Vec3::new(-1.0, -2.0, -1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), light(3.0)));
+        let count = 10000;
+        let below = (0..count).filter(|_| scene.sample_emitter(Vec3::zero(), None).unwrap().dir.y() < 0.0).count();
+        assert!((below as f64 / count as f64 - 0.75).abs() < 0.02);
+
+        // Straight up through the dimmer light, which is picked a quarter of the time
+        // and then has a density of d² / A = 1
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0));
+        let hit = scene.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((scene.emitter_pdf(Vec3::zero(), None, ray.dir(), &hit) - 0.25).abs() < 1e-9);
+        let hit = scene.hit(ray_along_z(), 0.0..f64::INFINITY).unwrap();
+        assert_eq!(scene.emitter_pdf(Vec3::zero(), None, ray_along_z().dir(), &hit), 0.0);
+    }
+
+    #[test]
+    fn tree_skips_lights_facing_away() {
+        let light = Material::Emissive { emission: Texture::Constant(Vec3::new(1.0, 1.0, 1.0)), intensity: 1.0, one_sided: true };
+        let mut scene = Scene::new();
+        // Above the origin, one facing down towards it and one facing up, away from it
+        scene.add(Quad::new(Vec3::new(-1.0, 2.0, -1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), light.clone()));
+        scene.add(Quad::new(Vec3::new(-1.0, 3.0, -1.0), Vec3::new(0.0, 0.0, 2.0), Vec3::new(2.0, 0.0, 0.0), light));
+        for _ in 0..100 {
+            let sample = scene.sample_emitter(Vec3::zero(), None).unwrap();
+            assert!((sample.distance - 2.0 / sample.dir.y()).abs() < 1e-6);
+            assert!((scene.emitter_pdf(Vec3::zero(), None, sample.dir, &sample.hit) - sample.pdf).abs() < 1e-9 * sample.pdf);
+        }
+        // Nothing lights a surface facing down
+        assert!(scene.sample_emitter(Vec3::zero(), Some(Vec3::new(0.0, -1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn light_sampling_is_unbiased() {
+        // Light reaching a few points on the floor. Picking lights differently should
+        // only change the noise, and the tree should make a lot less of it.
+        util::seed(1);
+        let (mut scene, _) = scenes::many_lights(1, 1);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let estimate = |scene: &Scene, point: Vec3| {
+            let count = 20000;
+            let sum: f64 = (0..count).filter_map(|_| scene.sample_emitter(point, Some(up))).map(|sample| {
+                sample.hit.material().emitted(&sample.hit).luminance() * sample.dir.dot(up).max(0.0) / sample.pdf
+            }).sum();
+            sum / count as f64
+        };
+        // The lights are all above the floor, where each one lights it like a point
+        // light of intensity π r² L would
+        let exact = |scene: &Scene, point: Vec3| -> f64 {
+            scene.objects().filter_map(|(_, object)| match object {
+                Object::Sphere(sphere) => {
+                    let offset = sphere.center() - point;
+                    let radiance = sphere.material().unwrap().power(1.0) / (2.0 * PI);
+                    Some(PI * sphere.radius().powi(2) * radiance * offset.normalize().dot(up) / offset.norm_sq())
+                }
+                _ => None,
+            }).sum()
+        };
+        for point in [Vec3::zero(), Vec3::new(5.3, 0.0, -2.1), Vec3::new(-19.0, 0.0, 19.0)] {
+            let exact = exact(&scene, point);
+            for (light_sampling, tolerance) in [(LightSampling::Tree, 0.03), (LightSampling::Power, 0.15)] {
+                scene.set_light_sampling(light_sampling);
+                let estimate = estimate(&scene, point);
+                assert!((estimate / exact - 1.0).abs() < tolerance, "{:?}: {} != {}", light_sampling, estimate, exact);
+            }
+        }
+    }
+
+    #[test]
+    fn rebuilt_after_changes() {
+        let mut scene = Scene::new();
+        let sphere = scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, material()));
+        assert_eq!(scene.hit(ray_along_z(), 0.0..f64::INFINITY).unwrap().t(), 4.0);
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 3.0), 1.0, material()));
+        assert_eq!(scene.hit(ray_along_z(), 0.0..f64::INFINITY).unwrap().t(), 2.0);
+        scene.remove(sphere);
+        *scene.get_mut(ObjectHandle { index: 1, generation: 0 }).unwrap() = Object::Sphere(Sphere::new(Vec3::new(0.0, 0.0, 8.0), 1.0, material()));
+        assert_eq!(scene.hit(ray_along_z(), 0.0..f64::INFINITY).unwrap().t(), 7.0);
     }
 }
@@ -0,0 +1,121 @@
+use crate::camera::Camera;
+use crate::material::{Material, RefractiveIndex};
+use crate::scene::Scene;
+use crate::shapes::{AxisAlignedBox, InfinitePlane, Quad, Sphere, Triangle};
+use crate::vector::Vec3;
+
+/// Builds a standard scene, and a camera looking at it rendering images of the given
+/// width and height.
+pub type Setup = fn(u32, u32) -> (Scene, Camera);
+
+/// The scene from `main`.
+pub fn spheres(width: u32, height: u32) -> (Scene, Camera) {
+    let mut scene = Scene::new();
+    let metal1 = Material::Metal { color: Vec3::new(0.8, 0.8, 0.8), fuzz: 0.3 };
+    let metal2 = Material::Metal { color: Vec3::new(0.8, 0.6, 0.2), fuzz: 1.0 };
+    let glass = Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: Vec3::new(1.0, 1.0, 1.0), thin_walled: false, medium: None };
+    let light = Material::Light { color: Vec3::new(1.0, 0.5, 0.5), intensity: 50.0 };
+    scene.add_inf_plane(InfinitePlane::new(0.5, Vec3::new(0.0, -1.0, 0.0), metal1.clone()));
+    scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, metal1));
+    scene.add_sphere(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), -0.4, glass));
+    scene.add_sphere(Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5, metal2));
+    scene.add_sphere(Sphere::new(Vec3::new(1.0, 3.5, 2.0), 1.0, light));
+
+    let camera = Camera::new(Vec3::new(-0.8, 0.9, 1.6), Vec3::new(-0.4, 0.65, 0.0), Vec3::new(0.0, 1.0, 0.0), width, height, 55.0);
+    (scene, camera)
+}
+
+/// Red and green walls, a light in the ceiling and two white boxes.
+pub fn cornell_box(width: u32, height: u32) -> (Scene, Camera) {
+    let mut scene = Scene::new();
+    let red = Material::Diffuse { color: Vec3::new(0.65, 0.05, 0.05) };
+    let white = Material::Diffuse { color: Vec3::new(0.73, 0.73, 0.73) };
+    let green = Material::Diffuse { color: Vec3::new(0.12, 0.45, 0.15) };
+    let light = Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity: 15.0 };
+    let (x, y, z) = (Vec3::new(555.0, 0.0, 0.0), Vec3::new(0.0, 555.0, 0.0), Vec3::new(0.0, 0.0, 555.0));
+    scene.add(Quad::new(x, y, z, green));
+    scene.add(Quad::new(Vec3::zero(), y, z, red));
+    scene.add(Quad::new(Vec3::zero(), x, z, white.clone()));
+    scene.add(Quad::new(y, x, z, white.clone()));
+    scene.add(Quad::new(z, x, y, white.clone()));
+    scene.add(Quad::new(Vec3::new(213.0, 554.0, 227.0), Vec3::new(130.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 105.0), light));
+    scene.add(AxisAlignedBox::new(Vec3::new(130.0, 0.0, 65.0), Vec3::new(295.0, 165.0, 230.0), white.clone()));
+    scene.add(AxisAlignedBox::new(Vec3::new(265.0, 0.0, 295.0), Vec3::new(430.0, 330.0, 460.0), white));
+
+    let camera = Camera::new(Vec3::new(278.0, 278.0, -800.0), Vec3::new(278.0, 278.0, 0.0), Vec3::new(0.0, 1.0, 0.0), width, height, 40.0);
+    (scene, camera)
+}
+
+/// A glass ball in front of a striped wall, which it shows upside down.
+pub fn glass_sphere(width: u32, height: u32) -> (Scene, Camera) {
+    let mut scene = Scene::new();
+    let floor = Material::Diffuse { color: Vec3::new(0.7, 0.7, 0.7) };
+    let glass = Material::Glass { refractive_index: RefractiveIndex::Constant(1.5), transmittance: Vec3::new(1.0, 1.0, 1.0), thin_walled: false, medium: None };
+    let light = Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity: 4.0 };
+    scene.add(Quad::new(Vec3::new(-4.0, 0.0, -4.0), Vec3::new(8.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 8.0), floor));
+    for (i, color) in [Vec3::new(0.8, 0.1, 0.1), Vec3::new(0.1, 0.8, 0.1), Vec3::new(0.1, 0.1, 0.8)].into_iter().enumerate() {
+        let stripe = Material::Diffuse { color };
+        scene.add(Quad::new(Vec3::new(-3.0, i as f64, -2.0), Vec3::new(6.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), stripe));
+    }
+    scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.6, 0.0), 0.6, glass));
+    scene.add(Quad::new(Vec3::new(-1.0, 4.0, -1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), light));
+
+    let camera = Camera::new(Vec3::new(0.0, 1.0, 4.0), Vec3::new(0.0, 0.8, 0.0), Vec3::new(0.0, 1.0, 0.0), width, height, 40.0);
+    (scene, camera)
+}
+
+/// A white ball lit the same from every direction by a light surrounding everything.
+/// It doesn't absorb any light, so it should look exactly as bright as the light
+/// around it and disappear.
+pub fn furnace(width: u32, height: u32) -> (Scene, Camera) {
+    let mut scene = Scene::new();
+    let white = Material::Diffuse { color: Vec3::new(1.0, 1.0, 1.0) };
+    let light = Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity: FURNACE_LIGHT };
+    scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, white));
+    scene.add_sphere(Sphere::new(Vec3::zero(), 100.0, light));
+
+    let camera = Camera::new(Vec3::new(0.0, 0.0, 4.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), width, height, 40.0);
+    (scene, camera)
+}
+
+/// How bright the light in `furnace` is.
+pub const FURNACE_LIGHT: f64 = 0.5;
+
+/// Spheres and triangles spread out in a cube, to give the BVH something to do.
+pub fn many_objects(count: usize) -> Scene {
+    let material = Material::Diffuse { color: Vec3::new(0.6, 0.6, 0.6) };
+    let mut scene = Scene::new();
+    for _ in 0..count / 2 {
+        scene.add_sphere(Sphere::new(10.0 * Vec3::random(), 0.2, material.clone()));
+        let corner = 10.0 * Vec3::random();
+        scene.add_triangle(Triangle::new(corner, corner + 0.5 * Vec3::random(), corner + 0.5 * Vec3::random(), material.clone()));
+    }
+    scene
+}
+
+/// A floor lit by a grid of small, dim lights of every color, where the light
+/// reaching a point mostly comes from the few lights close to it.
+pub fn many_lights(width: u32, height: u32) -> (Scene, Camera) {
+    let mut scene = Scene::new();
+    let floor = Material::Diffuse { color: Vec3::new(0.7, 0.7, 0.7) };
+    scene.add(Quad::new(Vec3::new(-20.0, 0.0, -20.0), Vec3::new(0.0, 0.0, 40.0), Vec3::new(40.0, 0.0, 0.0), floor));
+    for x in -10..10 {
+        for z in -10..10 {
+            let color = Vec3::new((x + 10) as f64 / 20.0, 0.3, (z + 10) as f64 / 20.0);
+            let light = Material::Light { color, intensity: 20.0 };
+            scene.add_sphere(Sphere::new(Vec3::new(2.0 * x as f64 + 1.0, 0.5, 2.0 * z as f64 + 1.0), 0.1, light));
+        }
+    }
+
+    let camera = Camera::new(Vec3::new(0.0, 12.0, 16.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), width, height, 55.0);
+    (scene, camera)
+}
+
+/// Lots of objects lit from above.
+pub fn many(width: u32, height: u32) -> (Scene, Camera) {
+    let mut scene = many_objects(10_000);
+    let light = Material::Light { color: Vec3::new(1.0, 1.0, 1.0), intensity: 5.0 };
+    scene.add_sphere(Sphere::new(Vec3::new(0.0, 30.0, 0.0), 10.0, light));
+    let camera = Camera::new(Vec3::new(0.0, 0.0, 25.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), width, height, 55.0);
+    (scene, camera)
+}
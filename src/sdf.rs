@@ -0,0 +1,232 @@
+use std::ops::Range;
+use crate::aabb::Aabb;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::shapes::{HitResult, Hittable};
+use crate::vector::Vec3;
+
+// Close enough to the surface to count as a hit
+const EPSILON: f64 = 1e-4;
+const MAX_STEPS: usize = 512;
+
+/// Distance from a point to a surface, negative inside of it. Fractals only give an
+/// estimate, but it never overshoots the surface.
+#[derive(Debug, Clone)]
+pub enum DistanceField {
+    Sphere { center: Vec3, radius: f64 },
+    RoundedBox { center: Vec3, half_size: Vec3, radius: f64 },
+    Mandelbulb { center: Vec3, scale: f64, power: f64, iterations: u32 },
+    // Like a union, but blends the shapes together where they are closer than k
+    SmoothUnion { a: Box<DistanceField>, b: Box<DistanceField>, k: f64 },
+}
+
+impl DistanceField {
+    pub fn smooth_union(a: DistanceField, b: DistanceField, k: f64) -> Self {
+        DistanceField::SmoothUnion { a: Box::new(a), b: Box::new(b), k }
+    }
+
+    pub fn distance(&self, point: Vec3) -> f64 {
+        match self {
+            DistanceField::Sphere { center, radius } => (point - *center).norm() - radius,
+            DistanceField::RoundedBox { center, half_size, radius } => {
+                let local = point - *center;
+                let q = [0, 1, 2].map(|axis| local.axis(axis).abs() - half_size.axis(axis));
+                let outside = Vec3::new(q[0].max(0.0), q[1].max(0.0), q[2].max(0.0)).norm();
+                let inside = q[0].max(q[1]).max(q[2]).min(0.0);
+                outside + inside - radius
+            }
+            DistanceField::Mandelbulb { center, scale, power, iterations } => {
+                scale * mandelbulb((point - *center) / *scale, *power, *iterations)
+            }
+            DistanceField::SmoothUnion { a, b, k } => {
+                let (a, b) = (a.distance(point), b.distance(point));
+                // Polynomial smooth minimum
+                let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+                b + (a - b) * h - k * h * (1.0 - h)
+            }
+        }
+    }
+
+    /// Box that the surface is inside of.
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            DistanceField::Sphere { center, radius } => {
+                let r = Vec3::new(*radius, *radius, *radius);
+                Aabb::new(*center - r, *center + r)
+            }
+            DistanceField::RoundedBox { center, half_size, radius } => {
+                let extent = *half_size + Vec3::new(*radius, *radius, *radius);
+                Aabb::new(*center - extent, *center + extent)
+            }
+            DistanceField::Mandelbulb { center, scale, .. } => {
+                let extent = 1.2 * Vec3::new(*scale, *scale, *scale);
+                Aabb::new(*center - extent, *center + extent)
+            }
+            DistanceField::SmoothUnion { a, b, k } => {
+                let both = a.bounds().union(&b.bounds());
+                // Blending can bulge out a bit past both shapes
+                let padding = Vec3::new(*k, *k, *k);
+                Aabb::new(both.min() - padding, both.max() + padding)
+            }
+        }
+    }
+
+    /// Points away from the surface, estimated with the tetrahedron technique.
+    fn gradient(&self, point: Vec3) -> Vec3 {
+        let h = EPSILON;
+        [Vec3::new(1.0, -1.0, -1.0), Vec3::new(-1.0, -1.0, 1.0), Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, 1.0)]
+            .into_iter()
+            .fold(Vec3::zero(), |sum, k| sum + self.distance(point + h * k) * k)
+    }
+}
+
+/// Distance estimate for the Mandelbulb fractal centered on the origin.
+fn mandelbulb(point: Vec3, power: f64, iterations: u32) -> f64 {
+    let mut z = point;
+    let mut dr = 1.0;
+    let mut r = z.norm();
+    for _ in 0..iterations {
+        if r > 2.0 || r == 0.0 {
+            break;
+        }
+        // Raise to the power in spherical coordinates
+        let theta = (z.z() / r).clamp(-1.0, 1.0).acos() * power;
+        let phi = z.y().atan2(z.x()) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+        z = r.powf(power) * Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) + point;
+        r = z.norm();
+    }
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+/// Implicit surface found by sphere tracing, stepping along the ray by the distance
+/// to the surface until it gets close enough.
+pub struct Sdf {
+    field: DistanceField,
+    bounds: Aabb,
+    material: Material,
+}
+
+impl Sdf {
+    pub fn new(field: DistanceField, material: Material) -> Self {
+        Self { bounds: field.bounds(), field, material }
+    }
+}
+
+impl Hittable for Sdf {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        let (t_enter, _, t_exit, _) = self.bounds.hit_range(ray, t_range)?;
+        let dir_len = ray.dir().norm();
+
+        let mut t = t_enter;
+        let start = self.field.distance(ray.at(t));
+        // Rays starting inside march towards the surface from the other side
+        let mut side = if start < 0.0 { -1.0 } else { 1.0 };
+        // A ray leaving a surface shouldn't hit it again right away
+        let mut left_surface = start.abs() >= EPSILON;
+        for _ in 0..MAX_STEPS {
+            let distance = side * self.field.distance(ray.at(t));
+            if distance < EPSILON {
+                if left_surface {
+                    let hit_point = ray.at(t);
+                    let outward_normal = self.field.gradient(hit_point).normalize();
+                    // Stopping this close to the surface is the biggest error
+                    let error = Vec3::new(EPSILON, EPSILON, EPSILON);
+                    // No natural way to wrap a texture around an implicit surface
+                    let hit = HitResult::new(ray, t, outward_normal, (0.0, 0.0), &self.material);
+                    return Some(hit.with_point(hit_point, error));
+                }
+                if distance < 0.0 {
+                    // Went through the surface it started on
+                    side = -side;
+                }
+            } else {
+                left_surface = true;
+            }
+
+            if t >= t_exit {
+                return None;
+            }
+            // Surfaces can touch the bounds, so check right at the edge too
+            t = (t + distance.abs().max(EPSILON) / dir_len).min(t_exit);
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material() -> Material {
+        Material::Diffuse { color: Vec3::new(0.5, 0.5, 0.5) }
+    }
+
+    #[test]
+    fn sphere_matches_analytic() {
+        let sdf = Sdf::new(DistanceField::Sphere { center: Vec3::zero(), radius: 1.0 }, material());
+        let ray = Ray::new(Vec3::new(-5.0, 0.3, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        let hit = sdf.hit(ray, 0.001..f64::INFINITY).unwrap();
+        let expected_x = -(1.0f64 - 0.3 * 0.3).sqrt();
+        assert!((hit.hit_point().x() - expected_x).abs() < 1e-3);
+        assert!((hit.normal() - Vec3::new(expected_x, 0.3, 0.0)).norm() < 1e-3);
+        assert!(hit.front_face());
+
+        // From the inside
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0));
+        let hit = sdf.hit(ray, 0.001..f64::INFINITY).unwrap();
+        assert!((hit.t() - 1.0).abs() < 1e-3);
+        assert!(!hit.front_face());
+    }
+
+    #[test]
+    fn leaving_surface_does_not_hit_it() {
+        let sdf = Sdf::new(DistanceField::Sphere { center: Vec3::zero(), radius: 1.0 }, material());
+        // Bouncing off the top of the sphere
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.01, 0.0));
+        assert!(sdf.hit(ray, 0.001..f64::INFINITY).is_none());
+        // Going into the sphere reaches the other side
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = sdf.hit(ray, 0.001..f64::INFINITY).unwrap();
+        assert!((hit.t() - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rounded_box_distance() {
+        let field = DistanceField::RoundedBox { center: Vec3::zero(), half_size: Vec3::new(1.0, 1.0, 1.0), radius: 0.25 };
+        assert!((field.distance(Vec3::new(2.0, 0.0, 0.0)) - 0.75).abs() < 1e-9);
+        assert!((field.distance(Vec3::new(0.0, 0.0, 0.0)) + 1.25).abs() < 1e-9);
+        // The corner is rounded off
+        let corner = Vec3::new(2.0, 2.0, 0.0);
+        assert!((field.distance(corner) - (2f64.sqrt() - 0.25)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn smooth_union_blends() {
+        let a = DistanceField::Sphere { center: Vec3::new(-1.0, 0.0, 0.0), radius: 0.75 };
+        let b = DistanceField::Sphere { center: Vec3::new(1.0, 0.0, 0.0), radius: 0.75 };
+        let blob = DistanceField::smooth_union(a.clone(), b.clone(), 0.5);
+        // Same as a union far from where they meet, filled in between them
+        let far = Vec3::new(-2.5, 0.0, 0.0);
+        assert!((blob.distance(far) - a.distance(far)).abs() < 1e-9);
+        let middle = Vec3::zero();
+        assert!(blob.distance(middle) < a.distance(middle).min(b.distance(middle)));
+    }
+
+    #[test]
+    fn mandelbulb_is_hit() {
+        let field = DistanceField::Mandelbulb { center: Vec3::zero(), scale: 1.0, power: 8.0, iterations: 8 };
+        let sdf = Sdf::new(field.clone(), material());
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -3.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = sdf.hit(ray, 0.001..f64::INFINITY).unwrap();
+        assert!(field.distance(hit.hit_point()).abs() < 1e-3);
+        assert!(hit.hit_point().norm() < 1.2);
+    }
+}
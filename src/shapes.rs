@@ -1,6 +1,10 @@
+use std::f64::consts::PI;
 use std::ops::Range;
+use crate::aabb::Aabb;
 use crate::material::Material;
+use crate::medium::Medium;
 use crate::ray::Ray;
+use crate::util::{gamma, random, solve_quadratic, solve_quartic};
 use crate::vector::Vec3;
 
 pub struct HitResult<'a> {
@@ -9,18 +13,131 @@ pub struct HitResult<'a> {
     normal: Vec3,
     material: &'a Material,
     front_face: bool,
+    uv: (f64, f64),
+    // How far off from the real surface the hit point could be on each axis
+    error: Vec3,
+    // Slot of the object in the scene that was hit
+    object: Option<usize>,
 }
 
 impl<'a> HitResult<'a> {
+    /// Flips the normal to point against the ray if it is hitting the back side.
+    pub fn new(ray: Ray, t: f64, outward_normal: Vec3, uv: (f64, f64), material: &'a Material) -> Self {
+        let (normal, front_face) = if ray.dir().dot(outward_normal) > 0.0 {
+            (-outward_normal, false)
+        } else {
+            (outward_normal, true)
+        };
+        // Shapes that can calculate the point more precisely replace this. How far off
+        // t is isn't known here, so be generous.
+        let error = gamma(16) * (ray.origin().abs() + (t * ray.dir()).abs());
+        Self {
+            t,
+            hit_point: ray.at(t),
+            normal,
+            material,
+            front_face,
+            uv,
+            error,
+            object: None,
+        }
+    }
+
+    /// Replace the hit point with a more precise one.
+    pub fn with_point(self, hit_point: Vec3, error: Vec3) -> Self {
+        Self { hit_point, error, ..self }
+    }
+
+    pub(crate) fn with_object(self, object: usize) -> Self {
+        Self { object: Some(object), ..self }
+    }
+
     pub fn t(&self) -> f64 { self.t }
     pub fn hit_point(&self) -> Vec3 { self.hit_point }
     pub fn normal(&self) -> Vec3 { self.normal }
-    pub fn material(&self) -> &Material { self.material }
+    pub fn material(&self) -> &'a Material { self.material }
     pub fn front_face(&self) -> bool { self.front_face }
+    pub fn uv(&self) -> (f64, f64) { self.uv }
+    pub(crate) fn object(&self) -> Option<usize> { self.object }
+
+    /// The same hit from the other side of the surface, for surfaces cut out of a
+    /// solid. The normal still points against the ray.
+    pub fn flip_face(self) -> Self {
+        Self { front_face: !self.front_face, ..self }
+    }
+
+    /// Ray leaving the surface in direction `dir`. It starts just far enough from the
+    /// hit point that rounding errors can't put it back on the wrong side of the
+    /// surface, so it won't hit the surface again right away.
+    pub fn spawn_ray(&self, dir: Vec3) -> Ray {
+        let distance = self.normal.abs().dot(self.error);
+        let side = if dir.dot(self.normal) < 0.0 { -self.normal } else { self.normal };
+        // Adding the offset rounds too, so round away from the surface. That also
+        // moves points off surfaces lying exactly on an axis, where the error is zero.
+        let origin = (self.hit_point + distance * side).round_away(side);
+        Ray::new(origin, dir)
+    }
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult>;
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>>;
+
+    fn bounding_box(&self) -> Aabb;
+
+    /// Pick a random point on the surface to send a ray to from `origin`, for shapes
+    /// that can be used as lights.
+    fn sample_point(&self, _origin: Vec3) -> Option<Vec3> {
+        None
+    }
+
+    /// Pick a point uniformly over the surface, and the outward normal there, for
+    /// lights sending out light themselves.
+    fn sample_area(&self) -> Option<(Vec3, Vec3)> {
+        None
+    }
+
+    /// Probability density, per solid angle, that `sample_point` picks the point the
+    /// ray from `origin` in direction `dir` hits.
+    fn pdf(&self, _origin: Vec3, _dir: Vec3) -> f64 {
+        0.0
+    }
+
+    /// Surface area of shapes that can be sampled, which lights with a larger area
+    /// are picked more often by.
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    /// The material of the whole shape, for shapes that can be sampled.
+    fn material(&self) -> Option<&Material> {
+        None
+    }
+
+    /// The outward normal of flat shapes, the same all over them.
+    fn plane_normal(&self) -> Option<Vec3> {
+        None
+    }
+}
+
+/// Density per solid angle of uniformly picking a point from a surface of `area`,
+/// seen through the hits of a ray.
+fn area_pdf<'a>(hits: impl IntoIterator<Item = HitResult<'a>>, ray: Ray, area: f64) -> f64 {
+    let dir_len = ray.dir().norm();
+    hits.into_iter()
+        .map(|hit| {
+            let distance = hit.t() * dir_len;
+            let cos = (hit.normal().dot(ray.dir()) / dir_len).abs();
+            if cos > 0.0 { distance * distance / (cos * area) } else { 0.0 }
+        })
+        .sum()
+}
+
+/// A closed shape with an inside, which can be combined with other solids.
+pub trait Solid: Hittable {
+    /// Every hit along the whole line of the ray, also behind its origin, in order.
+    /// Front faces are where the line goes into the shape and back faces where it
+    /// comes out.
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>>;
 }
 
 pub struct Sphere {
@@ -31,10 +148,33 @@ pub struct Sphere {
 
 impl Sphere {
     pub fn new(center: Vec3, radius: f64, material: Material) -> Self { Self { center, radius, material } }
+    pub fn center(&self) -> Vec3 { self.center }
+    pub fn radius(&self) -> f64 { self.radius }
+
+    /// One minus the cosine of the angle the sphere covers seen from `distance` away
+    /// from its center, written so it doesn't cancel out for far away spheres.
+    fn cone_one_minus_cos(&self, distance: f64) -> f64 {
+        let sin_sq = (self.radius * self.radius / (distance * distance)).min(1.0);
+        sin_sq / (1.0 + (1.0 - sin_sq).sqrt())
+    }
+
+    fn hit_at(&self, ray: Ray, t: f64) -> HitResult<'_> {
+        // Move the point onto the surface, which is more precise than t
+        let mut local = ray.at(t) - self.center;
+        local = (self.radius.abs() / local.norm()) * local;
+        let hit_point = self.center + local;
+        let error = gamma(5) * local.abs() + gamma(2) * hit_point.abs();
+
+        let outward_normal = local / self.radius;
+        // Longitude and latitude
+        let u = ((-outward_normal.z()).atan2(outward_normal.x()) + PI) / (2.0 * PI);
+        let v = (-outward_normal.y()).clamp(-1.0, 1.0).acos() / PI;
+        HitResult::new(ray, t, outward_normal, (u, v), &self.material).with_point(hit_point, error)
+    }
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult> {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
         let oc = ray.origin() - self.center;
         let a = ray.dir().norm_sq();
         let half_b = oc.dot(ray.dir());
@@ -53,22 +193,69 @@ impl Hittable for Sphere {
             }
         }
 
-        let hit_point = ray.at(root);
-        let outward_normal = (hit_point - self.center) / self.radius;
-        let (normal, front_face) =  if ray.dir().dot(outward_normal) > 0.0 {
-            // ray is inside the sphere
-            (-outward_normal, false)
-        } else {
-            // ray is outside the sphere
-            (outward_normal, true)
+        Some(self.hit_at(ray, root))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius.abs();
+        Aabb::new(self.center - Vec3::new(r, r, r), self.center + Vec3::new(r, r, r))
+    }
+
+    fn sample_point(&self, origin: Vec3) -> Option<Vec3> {
+        let radius = self.radius.abs();
+        let (to_center, distance) = {
+            let offset = self.center - origin;
+            (offset / offset.norm(), offset.norm())
         };
-        Some(HitResult {
-            t: root,
-            hit_point,
-            normal,
-            material: &self.material,
-            front_face,
-        })
+        if distance <= radius {
+            return self.sample_area().map(|(point, _)| point);
+        }
+        // Uniformly in the cone of directions the sphere covers, so no samples are
+        // wasted on the side facing away
+        let one_minus_cos_max = self.cone_one_minus_cos(distance);
+        let cos = 1.0 - random::<f64>() * one_minus_cos_max;
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * random::<f64>();
+        let (u, v) = to_center.orthonormal_basis();
+        let dir = (sin * phi.cos()) * u + (sin * phi.sin()) * v + cos * to_center;
+        // Closest point along that direction, or where it grazes the sphere
+        let along = distance * cos - (radius * radius - distance * distance * sin * sin).max(0.0).sqrt();
+        Some(origin + along * dir)
+    }
+
+    fn sample_area(&self) -> Option<(Vec3, Vec3)> {
+        let normal = Vec3::random_unit();
+        Some((self.center + self.radius.abs() * normal, normal))
+    }
+
+    fn pdf(&self, origin: Vec3, dir: Vec3) -> f64 {
+        let ray = Ray::new(origin, dir);
+        let distance = (self.center - origin).norm();
+        if distance > self.radius.abs() {
+            return match self.hit(ray, 0.0..f64::INFINITY) {
+                Some(_) => 1.0 / (2.0 * PI * self.cone_one_minus_cos(distance)),
+                None => 0.0,
+            };
+        }
+        // Inside, points all around could be picked
+        let hits = self.crossings(ray).into_iter().filter(|hit| hit.t() > 0.0);
+        area_pdf(hits, ray, self.area())
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+}
+
+impl Solid for Sphere {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        let oc = ray.origin() - self.center;
+        let roots = solve_quadratic(ray.dir().norm_sq(), 2.0 * oc.dot(ray.dir()), oc.norm_sq() - self.radius * self.radius);
+        roots.into_iter().map(|t| self.hit_at(ray, t)).collect()
     }
 }
 
@@ -89,7 +276,7 @@ impl InfinitePlane {
 }
 
 impl Hittable for InfinitePlane {
-    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult> {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
         let denominator = ray.dir().dot(self.normal);
         if denominator == 0.0 {
             return None;
@@ -100,13 +287,17 @@ impl Hittable for InfinitePlane {
         if !t_range.contains(&t) {
             return None;
         }
-        Some(HitResult {
-            t,
-            hit_point: ray.at(t),
-            normal: -self.normal,
-            material: &self.material,
-            front_face: false,
-        })
+        let outward_normal = -self.normal;
+        // Coordinates along the plane, repeating every unit
+        let hit_point = ray.at(t);
+        let (tangent, bitangent) = self.normal.orthonormal_basis();
+        let uv = (hit_point.dot(tangent).rem_euclid(1.0), hit_point.dot(bitangent).rem_euclid(1.0));
+        Some(HitResult::new(ray, t, outward_normal, uv, &self.material))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let infinity = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        Aabb::new(-infinity, infinity)
     }
 }
 
@@ -115,54 +306,883 @@ pub struct Triangle {
     v0: Vec3,
     v1: Vec3,
     v2: Vec3,
+    // Texture coordinates at each vertex, otherwise the uv of a hit is the
+    // barycentric coordinates of v1 and v2
+    uvs: Option<[(f64, f64); 3]>,
     material: Material,
 }
 
 impl Triangle {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Material) -> Self {
-        Self { v0, v1, v2, material, }
+        Self { v0, v1, v2, uvs: None, material, }
+    }
+
+    /// Give each vertex texture coordinates, which are blended across the triangle.
+    pub fn with_uvs(self, uvs: [(f64, f64); 3]) -> Self {
+        Self { uvs: Some(uvs), ..self }
+    }
+
+    pub fn vertices(&self) -> [Vec3; 3] {
+        [self.v0, self.v1, self.v2]
     }
 }
 
 impl Hittable for Triangle {
-    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult> {
-        // https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
-        let edge1 = self.v1 - self.v0;
-        let edge2 = self.v2 - self.v0;
-        let ray_cross_e2 = ray.dir().cross(edge2);
-        let det = edge1.dot(ray_cross_e2);
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        // Watertight intersection by Woop et al., so rays can't slip through the edge
+        // between two triangles. Done without Vec3, which could round to f32.
+        let (origin, dir) = (ray.origin(), ray.dir());
+        let relative = |vertex: Vec3| [vertex.x() - origin.x(), vertex.y() - origin.y(), vertex.z() - origin.z()];
+        let d = [dir.x(), dir.y(), dir.z()];
+
+        // Swap axes so the ray mostly goes along z, then shear it to go straight along z
+        let kz = (0..3).max_by(|&a, &b| d[a].abs().total_cmp(&d[b].abs())).unwrap();
+        let (kx, ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        let (shear_x, shear_y, shear_z) = (-d[kx] / d[kz], -d[ky] / d[kz], 1.0 / d[kz]);
+        let transform = |vertex: Vec3| {
+            let p = relative(vertex);
+            [p[kx] + shear_x * p[kz], p[ky] + shear_y * p[kz], p[kz] * shear_z]
+        };
+        let (p0, p1, p2) = (transform(self.v0), transform(self.v1), transform(self.v2));
 
+        // Which side of each edge the ray passes. Neighbours calculate the exact same
+        // value for a shared edge, just negated.
+        let e0 = p1[0] * p2[1] - p1[1] * p2[0];
+        let e1 = p2[0] * p0[1] - p2[1] * p0[0];
+        let e2 = p0[0] * p1[1] - p0[1] * p1[0];
+        if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+            return None;
+        }
+        let det = e0 + e1 + e2;
         // Triangle is parallel to ray
-        if det.abs() < f64::EPSILON {
+        if det == 0.0 {
             return None;
         }
 
         let inv_det = 1.0 / det;
-        let s = ray.origin() - self.v0;
-        let u = inv_det * s.dot(ray_cross_e2);
-        if u < 0.0 || u > 1.0 {
+        let t = (e0 * p0[2] + e1 * p1[2] + e2 * p2[2]) * inv_det;
+        if !t_range.contains(&t) {
             return None;
         }
 
-        let s_cross_e1 = s.cross(edge1);
-        let v = inv_det * ray.dir().dot(s_cross_e1);
-        if v < 0.0 || u + v > 1.0 {
+        // Make sure t isn't only positive because of rounding errors
+        let max_abs = |a: f64, b: f64, c: f64| a.abs().max(b.abs()).max(c.abs());
+        let max_x = max_abs(p0[0], p1[0], p2[0]);
+        let max_y = max_abs(p0[1], p1[1], p2[1]);
+        let max_z = max_abs(p0[2], p1[2], p2[2]);
+        let delta_x = gamma(5) * (max_x + max_z);
+        let delta_y = gamma(5) * (max_y + max_z);
+        let delta_z = gamma(3) * max_z;
+        let delta_e = 2.0 * (gamma(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+        let max_e = max_abs(e0, e1, e2);
+        let delta_t = 3.0 * (gamma(3) * max_e * max_z + delta_e * max_z + delta_z * max_e) * inv_det.abs();
+        if t <= delta_t {
             return None;
         }
 
-        // Cramers regel wow!
-        let t = edge2.dot(s_cross_e1) * inv_det;
-        
+        // The point from the barycentric coordinates is more precise than from t
+        let (b0, b1, b2) = (e0 * inv_det, e1 * inv_det, e2 * inv_det);
+        let hit_point = b0 * self.v0 + b1 * self.v1 + b2 * self.v2;
+        let error = gamma(7) * ((b0 * self.v0).abs() + (b1 * self.v1).abs() + (b2 * self.v2).abs());
+
+        let uv = match self.uvs {
+            Some([uv0, uv1, uv2]) => (b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0, b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1),
+            None => (b1, b2),
+        };
+
+        // Counter-clockwise winding is the front face
+        let outward_normal = (self.v1 - self.v0).cross(self.v2 - self.v0).normalize();
+        Some(HitResult::new(ray, t, outward_normal, uv, &self.material).with_point(hit_point, error))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(&[self.v0, self.v1, self.v2])
+    }
+
+    fn sample_point(&self, _origin: Vec3) -> Option<Vec3> {
+        let sqrt = random::<f64>().sqrt();
+        let (u, v) = (1.0 - sqrt, random::<f64>() * sqrt);
+        Some(self.v0 + u * (self.v1 - self.v0) + v * (self.v2 - self.v0))
+    }
+
+    fn sample_area(&self) -> Option<(Vec3, Vec3)> {
+        let normal = (self.v1 - self.v0).cross(self.v2 - self.v0).normalize();
+        Some((self.sample_point(self.v0)?, normal))
+    }
+
+    fn pdf(&self, origin: Vec3, dir: Vec3) -> f64 {
+        let ray = Ray::new(origin, dir);
+        area_pdf(self.hit(ray, 0.0..f64::INFINITY), ray, self.area())
+    }
+
+    fn area(&self) -> f64 {
+        0.5 * (self.v1 - self.v0).cross(self.v2 - self.v0).norm()
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+
+    fn plane_normal(&self) -> Option<Vec3> {
+        Some((self.v1 - self.v0).cross(self.v2 - self.v0).normalize())
+    }
+}
+
+/// Box around a volume. Rays only travel through the medium inside of it, so the
+/// medium is only marched where it has density.
+pub struct Volume {
+    bounds: Aabb,
+    material: Material,
+}
+
+impl Volume {
+    pub fn new(bounds: Aabb, medium: Medium) -> Self {
+        Self { bounds, material: Material::Volume { medium } }
+    }
+}
+
+impl Hittable for Volume {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        let (t, outward_normal, uv) = box_hit(&self.bounds, ray, t_range)?;
+        Some(HitResult::new(ray, t, outward_normal, uv, &self.material))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+/// A hit before it has a material, as the t, outward normal and uv coordinates.
+type SurfaceHit = (f64, Vec3, (f64, f64));
+
+/// Where a ray enters and leaves a box.
+fn box_crossings(bounds: &Aabb, ray: Ray) -> Option<[SurfaceHit; 2]> {
+    let (t_enter, enter_axis, t_exit, exit_axis) = bounds.hit_range(ray, f64::NEG_INFINITY..f64::INFINITY)?;
+    let face = |t: f64, axis: usize, sign: f64| {
+        let outward_normal = match axis {
+            0 => Vec3::new(sign, 0.0, 0.0),
+            1 => Vec3::new(0.0, sign, 0.0),
+            _ => Vec3::new(0.0, 0.0, sign),
+        };
+        let relative = bounds.relative(ray.at(t));
+        (t, outward_normal, (relative.axis((axis + 1) % 3), relative.axis((axis + 2) % 3)))
+    };
+    Some([
+        face(t_enter, enter_axis, -ray.dir().axis(enter_axis).signum()),
+        face(t_exit, exit_axis, ray.dir().axis(exit_axis).signum()),
+    ])
+}
+
+/// The closest intersection with the surface of a box.
+fn box_hit(bounds: &Aabb, ray: Ray, t_range: Range<f64>) -> Option<SurfaceHit> {
+    box_crossings(bounds, ray)?.into_iter().find(|(t, _, _)| t_range.contains(t))
+}
+
+/// Local coordinate system of a shape, where w is "up".
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Frame {
+    fn new(origin: Vec3, w: Vec3) -> Self {
+        let w = w.normalize();
+        let (u, v) = w.orthonormal_basis();
+        Self { origin, u, v, w }
+    }
+
+    fn from_axes(origin: Vec3, x_axis: Vec3, y_axis: Vec3) -> Self {
+        let u = x_axis.normalize();
+        let w = u.cross(y_axis).normalize();
+        let v = w.cross(u);
+        Self { origin, u, v, w }
+    }
+
+    fn dir_to_local(&self, dir: Vec3) -> Vec3 {
+        Vec3::new(dir.dot(self.u), dir.dot(self.v), dir.dot(self.w))
+    }
+
+    fn dir_from_local(&self, dir: Vec3) -> Vec3 {
+        dir.x() * self.u + dir.y() * self.v + dir.z() * self.w
+    }
+
+    fn ray_to_local(&self, ray: Ray) -> Ray {
+        Ray::new(self.dir_to_local(ray.origin() - self.origin), self.dir_to_local(ray.dir()))
+    }
+
+    /// Turn hits found in local space into hits of the ray in the world. The frame
+    /// keeps lengths, so t is the same in both spaces.
+    fn hits_from_local<'a>(
+        &self,
+        ray: Ray,
+        local_hits: impl IntoIterator<Item = SurfaceHit>,
+        material: &'a Material,
+    ) -> Vec<HitResult<'a>> {
+        local_hits
+            .into_iter()
+            .map(|(t, local_normal, uv)| HitResult::new(ray, t, self.dir_from_local(local_normal), uv, material))
+            .collect()
+    }
+
+    /// World space box around a box in local space.
+    fn bounding_box(&self, local: Aabb) -> Aabb {
+        let (min, max) = (local.min(), local.max());
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                let corner = Vec3::new(
+                    if i & 1 == 0 { min.x() } else { max.x() },
+                    if i & 2 == 0 { min.y() } else { max.y() },
+                    if i & 4 == 0 { min.z() } else { max.z() },
+                );
+                self.origin + self.dir_from_local(corner)
+            })
+            .collect();
+        Aabb::from_points(&corners)
+    }
+}
+
+/// Polar coordinates around the local w axis, both between 0 and 1.
+fn polar_uv(local: Vec3, radius: f64) -> (f64, f64) {
+    let angle = (local.y().atan2(local.x()) + PI) / (2.0 * PI);
+    let distance = (local.x() * local.x() + local.y() * local.y()).sqrt() / radius;
+    (distance, angle)
+}
+
+pub struct AxisAlignedBox {
+    bounds: Aabb,
+    material: Material,
+}
+
+impl AxisAlignedBox {
+    pub fn new(a: Vec3, b: Vec3, material: Material) -> Self {
+        Self { bounds: Aabb::new(a, b), material }
+    }
+}
+
+impl Hittable for AxisAlignedBox {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        let (t, outward_normal, uv) = box_hit(&self.bounds, ray, t_range)?;
+        Some(HitResult::new(ray, t, outward_normal, uv, &self.material))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+impl Solid for AxisAlignedBox {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        box_crossings(&self.bounds, ray)
+            .into_iter()
+            .flatten()
+            .map(|(t, outward_normal, uv)| HitResult::new(ray, t, outward_normal, uv, &self.material))
+            .collect()
+    }
+}
+
+/// Box that can be rotated in any direction.
+pub struct OrientedBox {
+    frame: Frame,
+    local: Aabb,
+    material: Material,
+}
+
+impl OrientedBox {
+    /// Box centered on `center`, with its width along `x_axis` and its height along
+    /// `y_axis` (adjusted to be perpendicular to `x_axis`).
+    pub fn new(center: Vec3, size: Vec3, x_axis: Vec3, y_axis: Vec3, material: Material) -> Self {
+        Self {
+            frame: Frame::from_axes(center, x_axis, y_axis),
+            local: Aabb::new(-0.5 * size, 0.5 * size),
+            material,
+        }
+    }
+}
+
+impl Hittable for OrientedBox {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        // The frame keeps lengths, so t is the same in both spaces
+        let (t, local_normal, uv) = box_hit(&self.local, self.frame.ray_to_local(ray), t_range)?;
+        let outward_normal = self.frame.dir_from_local(local_normal);
+        Some(HitResult::new(ray, t, outward_normal, uv, &self.material))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.frame.bounding_box(self.local)
+    }
+}
+
+impl Solid for OrientedBox {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        let local_hits = box_crossings(&self.local, self.frame.ray_to_local(ray)).into_iter().flatten();
+        self.frame.hits_from_local(ray, local_hits, &self.material)
+    }
+}
+
+/// Parallelogram spanned by two edges from a corner. Perfect for walls.
+pub struct Quad {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    normal: Vec3,
+    // Projects a point in the plane onto the edges
+    w: Vec3,
+    material: Material,
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, edge_u: Vec3, edge_v: Vec3, material: Material) -> Self {
+        let n = edge_u.cross(edge_v);
+        Self {
+            corner,
+            edge_u,
+            edge_v,
+            normal: n.normalize(),
+            w: n / n.norm_sq(),
+            material,
+        }
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        let denominator = ray.dir().dot(self.normal);
+        if denominator.abs() < 1e-12 {
+            return None;
+        }
+        let t = (self.corner - ray.origin()).dot(self.normal) / denominator;
         if !t_range.contains(&t) {
             return None;
         }
 
-        Some(HitResult {
-            t,
-            hit_point: ray.at(t),
-            normal: edge1.cross(edge2).normalize(),
-            material: &self.material,
-            front_face: false,
-        })
+        let planar = ray.at(t) - self.corner;
+        let alpha = self.w.dot(planar.cross(self.edge_v));
+        let beta = self.w.dot(self.edge_u.cross(planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+        // Rebuilt from the corner, so only the position along the edges is off
+        let (along_u, along_v) = (alpha * self.edge_u, beta * self.edge_v);
+        let hit_point = self.corner + along_u + along_v;
+        let error = gamma(7) * (self.corner.abs() + along_u.abs() + along_v.abs());
+        Some(HitResult::new(ray, t, self.normal, (alpha, beta), &self.material).with_point(hit_point, error))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let c = self.corner;
+        Aabb::from_points(&[c, c + self.edge_u, c + self.edge_v, c + self.edge_u + self.edge_v])
+    }
+
+    fn sample_point(&self, _origin: Vec3) -> Option<Vec3> {
+        Some(self.corner + random::<f64>() * self.edge_u + random::<f64>() * self.edge_v)
+    }
+
+    fn sample_area(&self) -> Option<(Vec3, Vec3)> {
+        Some((self.sample_point(self.corner)?, self.normal))
+    }
+
+    fn pdf(&self, origin: Vec3, dir: Vec3) -> f64 {
+        let ray = Ray::new(origin, dir);
+        area_pdf(self.hit(ray, 0.0..f64::INFINITY), ray, self.area())
+    }
+
+    fn area(&self) -> f64 {
+        self.edge_u.cross(self.edge_v).norm()
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+
+    fn plane_normal(&self) -> Option<Vec3> {
+        Some(self.normal)
+    }
+}
+
+pub struct Disk {
+    frame: Frame,
+    radius: f64,
+    material: Material,
+}
+
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: f64, material: Material) -> Self {
+        Self { frame: Frame::new(center, normal), radius, material }
+    }
+}
+
+impl Hittable for Disk {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        let local_ray = self.frame.ray_to_local(ray);
+        let (t, local) = cap_hit(local_ray, 0.0, self.radius, &t_range)?;
+        Some(HitResult::new(ray, t, self.frame.w, polar_uv(local, self.radius), &self.material))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius;
+        self.frame.bounding_box(Aabb::new(Vec3::new(-r, -r, 0.0), Vec3::new(r, r, 0.0)))
+    }
+
+    fn sample_point(&self, _origin: Vec3) -> Option<Vec3> {
+        let distance = self.radius * random::<f64>().sqrt();
+        let angle = 2.0 * PI * random::<f64>();
+        let local = Vec3::new(distance * angle.cos(), distance * angle.sin(), 0.0);
+        Some(self.frame.origin + self.frame.dir_from_local(local))
+    }
+
+    fn sample_area(&self) -> Option<(Vec3, Vec3)> {
+        Some((self.sample_point(self.frame.origin)?, self.frame.w))
+    }
+
+    fn pdf(&self, origin: Vec3, dir: Vec3) -> f64 {
+        let ray = Ray::new(origin, dir);
+        area_pdf(self.hit(ray, 0.0..f64::INFINITY), ray, self.area())
+    }
+
+    fn area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+
+    fn plane_normal(&self) -> Option<Vec3> {
+        Some(self.frame.w)
+    }
+}
+
+/// Intersection with a circle of the given radius at height z in local space.
+fn cap_hit(local_ray: Ray, z: f64, radius: f64, t_range: &Range<f64>) -> Option<(f64, Vec3)> {
+    if local_ray.dir().z().abs() < 1e-12 {
+        return None;
+    }
+    let t = (z - local_ray.origin().z()) / local_ray.dir().z();
+    if !t_range.contains(&t) {
+        return None;
+    }
+    let local = local_ray.at(t);
+    if local.x() * local.x() + local.y() * local.y() > radius * radius {
+        return None;
+    }
+    Some((t, local))
+}
+
+/// Cylinder with flat caps at both ends.
+pub struct Cylinder {
+    frame: Frame,
+    radius: f64,
+    height: f64,
+    material: Material,
+}
+
+impl Cylinder {
+    /// Cylinder between the centers of its two caps.
+    pub fn new(base: Vec3, top: Vec3, radius: f64, material: Material) -> Self {
+        Self {
+            frame: Frame::new(base, top - base),
+            radius,
+            height: (top - base).norm(),
+            material,
+        }
+    }
+
+    /// Hits along the whole line of a ray in local space, in order.
+    fn local_crossings(&self, local_ray: Ray) -> Vec<SurfaceHit> {
+        let (o, d) = (local_ray.origin(), local_ray.dir());
+        let mut crossings = Vec::new();
+
+        // Side
+        let a = d.x() * d.x() + d.y() * d.y();
+        let b = 2.0 * (o.x() * d.x() + o.y() * d.y());
+        let c = o.x() * o.x() + o.y() * o.y() - self.radius * self.radius;
+        for t in solve_quadratic(a, b, c) {
+            let local = local_ray.at(t);
+            if (0.0..=self.height).contains(&local.z()) {
+                let normal = Vec3::new(local.x(), local.y(), 0.0) / self.radius;
+                let (_, angle) = polar_uv(local, self.radius);
+                crossings.push((t, normal, (angle, local.z() / self.height)));
+            }
+        }
+
+        // Caps
+        for (z, normal) in [(0.0, Vec3::new(0.0, 0.0, -1.0)), (self.height, Vec3::new(0.0, 0.0, 1.0))] {
+            if let Some((t, local)) = cap_hit(local_ray, z, self.radius, &(f64::NEG_INFINITY..f64::INFINITY)) {
+                crossings.push((t, normal, polar_uv(local, self.radius)));
+            }
+        }
+
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        crossings
+    }
+}
+
+impl Hittable for Cylinder {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        self.crossings(ray).into_iter().find(|hit| t_range.contains(&hit.t()))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius;
+        self.frame.bounding_box(Aabb::new(Vec3::new(-r, -r, 0.0), Vec3::new(r, r, self.height)))
+    }
+}
+
+impl Solid for Cylinder {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        let local_hits = self.local_crossings(self.frame.ray_to_local(ray));
+        self.frame.hits_from_local(ray, local_hits, &self.material)
+    }
+}
+
+/// Cone with a flat base.
+pub struct Cone {
+    frame: Frame,
+    radius: f64,
+    height: f64,
+    material: Material,
+}
+
+impl Cone {
+    /// Cone from the center of its base to its tip.
+    pub fn new(base: Vec3, apex: Vec3, radius: f64, material: Material) -> Self {
+        Self {
+            frame: Frame::new(base, apex - base),
+            radius,
+            height: (apex - base).norm(),
+            material,
+        }
+    }
+
+    /// Hits along the whole line of a ray in local space, in order.
+    fn local_crossings(&self, local_ray: Ray) -> Vec<SurfaceHit> {
+        let (o, d) = (local_ray.origin(), local_ray.dir());
+        let h = self.height;
+        // Slope, x² + y² = k² (h - z)² on the side
+        let k2 = (self.radius / h) * (self.radius / h);
+        let mut crossings = Vec::new();
+
+        let a = d.x() * d.x() + d.y() * d.y() - k2 * d.z() * d.z();
+        let b = 2.0 * (o.x() * d.x() + o.y() * d.y() + k2 * (h - o.z()) * d.z());
+        let c = o.x() * o.x() + o.y() * o.y() - k2 * (h - o.z()) * (h - o.z());
+        for t in solve_quadratic(a, b, c) {
+            let local = local_ray.at(t);
+            // The equation also describes a mirrored cone above the tip
+            if (0.0..=h).contains(&local.z()) {
+                let mut normal = Vec3::new(local.x(), local.y(), k2 * (h - local.z()));
+                normal = if normal.is_near_zero() { Vec3::new(0.0, 0.0, 1.0) } else { normal.normalize() };
+                let (_, angle) = polar_uv(local, self.radius);
+                crossings.push((t, normal, (angle, local.z() / h)));
+            }
+        }
+
+        if let Some((t, local)) = cap_hit(local_ray, 0.0, self.radius, &(f64::NEG_INFINITY..f64::INFINITY)) {
+            crossings.push((t, Vec3::new(0.0, 0.0, -1.0), polar_uv(local, self.radius)));
+        }
+
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        crossings
+    }
+}
+
+impl Hittable for Cone {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        self.crossings(ray).into_iter().find(|hit| t_range.contains(&hit.t()))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius;
+        self.frame.bounding_box(Aabb::new(Vec3::new(-r, -r, 0.0), Vec3::new(r, r, self.height)))
+    }
+}
+
+impl Solid for Cone {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        let local_hits = self.local_crossings(self.frame.ray_to_local(ray));
+        self.frame.hits_from_local(ray, local_hits, &self.material)
+    }
+}
+
+/// Donut shape, a circle of radius `minor_radius` swept around a circle of radius
+/// `major_radius`.
+pub struct Torus {
+    frame: Frame,
+    major_radius: f64,
+    minor_radius: f64,
+    material: Material,
+}
+
+impl Torus {
+    pub fn new(center: Vec3, axis: Vec3, major_radius: f64, minor_radius: f64, material: Material) -> Self {
+        Self { frame: Frame::new(center, axis), major_radius, minor_radius, material }
+    }
+
+    /// Hits along the whole line of a ray in local space, in order.
+    fn local_crossings(&self, local_ray: Ray) -> Vec<SurfaceHit> {
+        let dir_len = local_ray.dir().norm();
+        let d = local_ray.dir() / dir_len;
+        let (big_r, small_r) = (self.major_radius, self.minor_radius);
+
+        // The quartic loses precision far away, so start from the bounding sphere
+        let outer = big_r + small_r;
+        let half_b = local_ray.origin().dot(d);
+        let c = local_ray.origin().norm_sq() - outer * outer;
+        if half_b * half_b - c < 0.0 {
+            return Vec::new();
+        }
+        let start = -half_b - (half_b * half_b - c).sqrt();
+        let o = local_ray.origin() + start * d;
+
+        // (x² + y² + z² + R² - r²)² = 4R²(x² + y²) along the ray
+        let n = o.dot(d);
+        let q = o.norm_sq() + big_r * big_r - small_r * small_r;
+        let r2 = 4.0 * big_r * big_r;
+        let roots = solve_quartic(
+            1.0,
+            4.0 * n,
+            4.0 * n * n + 2.0 * q - r2 * (d.x() * d.x() + d.y() * d.y()),
+            4.0 * n * q - 2.0 * r2 * (o.x() * d.x() + o.y() * d.y()),
+            q * q - r2 * (o.x() * o.x() + o.y() * o.y()),
+        );
+
+        roots
+            .into_iter()
+            .map(|distance| {
+                let t = (start + distance) / dir_len;
+                let local = local_ray.at(t);
+                // Away from the circle running through the middle of the tube
+                let ring_distance = (local.x() * local.x() + local.y() * local.y()).sqrt();
+                let ring_point = if ring_distance > 0.0 {
+                    (big_r / ring_distance) * Vec3::new(local.x(), local.y(), 0.0)
+                } else {
+                    Vec3::new(big_r, 0.0, 0.0)
+                };
+                let normal = (local - ring_point).normalize();
+                let u = (local.y().atan2(local.x()) + PI) / (2.0 * PI);
+                let v = (local.z().atan2(ring_distance - big_r) + PI) / (2.0 * PI);
+                (t, normal, (u, v))
+            })
+            .collect()
+    }
+}
+
+impl Hittable for Torus {
+    fn hit(&self, ray: Ray, t_range: Range<f64>) -> Option<HitResult<'_>> {
+        self.crossings(ray).into_iter().find(|hit| t_range.contains(&hit.t()))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let outer = self.major_radius + self.minor_radius;
+        let r = self.minor_radius;
+        self.frame.bounding_box(Aabb::new(Vec3::new(-outer, -outer, -r), Vec3::new(outer, outer, r)))
+    }
+}
+
+impl Solid for Torus {
+    fn crossings(&self, ray: Ray) -> Vec<HitResult<'_>> {
+        let local_hits = self.local_crossings(self.frame.ray_to_local(ray));
+        self.frame.hits_from_local(ray, local_hits, &self.material)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material() -> Material {
+        Material::Diffuse { color: Vec3::new(0.5, 0.5, 0.5) }
+    }
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!((actual - expected).norm() < 1e-6, "{:?} != {:?}", actual, expected);
+    }
+
+    // Ray from z = 5 towards the origin
+    fn ray_down_z(x: f64, y: f64) -> Ray {
+        Ray::new(Vec3::new(x, y, 5.0), Vec3::new(0.0, 0.0, -1.0))
+    }
+
+    #[test]
+    fn oriented_box() {
+        // Rotated 45 degrees around z, so the corner points along x
+        let shape = OrientedBox::new(Vec3::zero(), Vec3::new(2.0, 2.0, 2.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0), material());
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        let hit = shape.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - (5.0 - 2f64.sqrt())).abs() < 1e-6);
+        assert!(hit.front_face());
+
+        // From the inside
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0));
+        let hit = shape.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 1.0).abs() < 1e-6);
+        assert!(!hit.front_face());
+        assert_near(hit.normal(), Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn quad() {
+        let shape = Quad::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 4.0, 0.0), material());
+        let hit = shape.hit(ray_down_z(0.0, 0.0), 0.0..f64::INFINITY).unwrap();
+        assert_eq!(hit.t(), 5.0);
+        assert_near(hit.normal(), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(hit.uv(), (0.5, 0.25));
+        assert!(shape.hit(ray_down_z(1.5, 0.0), 0.0..f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn disk() {
+        let shape = Disk::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 1.0, material());
+        let hit = shape.hit(ray_down_z(0.5, 0.5), 0.0..f64::INFINITY).unwrap();
+        assert_eq!(hit.t(), 4.0);
+        assert!(!hit.front_face());
+        assert!(shape.hit(ray_down_z(0.8, 0.8), 0.0..f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn cylinder() {
+        let shape = Cylinder::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0), 1.0, material());
+        // Top cap
+        let hit = shape.hit(ray_down_z(0.5, 0.0), 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 4.0).abs() < 1e-9);
+        assert_near(hit.normal(), Vec3::new(0.0, 0.0, 1.0));
+        // Side
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 0.5), Vec3::new(-1.0, 0.0, 0.0));
+        let hit = shape.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 4.0).abs() < 1e-9);
+        assert_near(hit.normal(), Vec3::new(1.0, 0.0, 0.0));
+        // Misses above the top
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 1.5), Vec3::new(-1.0, 0.0, 0.0));
+        assert!(shape.hit(ray, 0.0..f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn cone() {
+        let shape = Cone::new(Vec3::zero(), Vec3::new(0.0, 0.0, 2.0), 1.0, material());
+        // Halfway up the side the radius is 0.5
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 1.0), Vec3::new(-1.0, 0.0, 0.0));
+        let hit = shape.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 4.5).abs() < 1e-9);
+        assert_near(hit.normal(), Vec3::new(2.0, 0.0, 1.0).normalize());
+        // Base from below
+        let ray = Ray::new(Vec3::new(0.2, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = shape.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 5.0).abs() < 1e-9);
+        assert_near(hit.normal(), Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn torus() {
+        let shape = Torus::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 2.0, 0.5, material());
+        // Straight through the hole
+        assert!(shape.hit(ray_down_z(0.0, 0.0), 0.0..f64::INFINITY).is_none());
+        // Down onto the tube
+        let hit = shape.hit(ray_down_z(2.0, 0.0), 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 4.5).abs() < 1e-6);
+        assert_near(hit.normal(), Vec3::new(0.0, 0.0, 1.0));
+        // Along the x axis, through the tube twice
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        let hit = shape.hit(ray, 0.0..f64::INFINITY).unwrap();
+        assert!((hit.t() - 1.25).abs() < 1e-6);
+        let hit = shape.hit(ray, 1.3..f64::INFINITY).unwrap();
+        assert!((hit.t() - 1.75).abs() < 1e-6);
+        assert!(!hit.front_face());
+    }
+
+    #[test]
+    fn sphere_sampling() {
+        let shape = Sphere::new(Vec3::new(0.0, 0.0, 3.0), 1.0, material());
+        for _ in 0..100 {
+            let point = shape.sample_point(Vec3::zero()).unwrap();
+            assert!(((point - Vec3::new(0.0, 0.0, 3.0)).norm() - 1.0).abs() < 1e-6);
+            // Only the side facing the origin
+            assert!(point.z() <= 3.0 - 1.0 / 3.0 + 1e-6);
+        }
+        // Uniform over the cone, which has cos = √8 / 3 at its edge
+        let expected = 1.0 / (2.0 * PI * (1.0 - 8f64.sqrt() / 3.0));
+        assert!((shape.pdf(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0)) - expected).abs() < 1e-6);
+        assert_eq!(shape.pdf(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0)), 0.0);
+        let count = 200000;
+        let sum: f64 = (0..count).map(|_| shape.pdf(Vec3::zero(), Vec3::random_unit())).sum();
+        assert!((4.0 * PI * sum / count as f64 - 1.0).abs() < 0.05);
+
+        // From inside, points are picked by area
+        let inside = Vec3::new(0.0, 0.0, 2.5);
+        let expected = 1.5 * 1.5 / (4.0 * PI);
+        assert!((shape.pdf(inside, Vec3::new(0.0, 0.0, 1.0)) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn triangle_uvs() {
+        let shape = Triangle::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material());
+        let hit = shape.hit(ray_down_z(0.25, 0.5), 0.0..f64::INFINITY).unwrap();
+        assert!((hit.uv().0 - 0.25).abs() < 1e-9 && (hit.uv().1 - 0.5).abs() < 1e-9);
+        // Mapped onto the top half of a texture, upside down
+        let shape = shape.with_uvs([(1.0, 1.0), (0.0, 1.0), (1.0, 0.5)]);
+        let hit = shape.hit(ray_down_z(0.25, 0.5), 0.0..f64::INFINITY).unwrap();
+        assert!((hit.uv().0 - 0.75).abs() < 1e-9 && (hit.uv().1 - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn quad_sampling() {
+        let shape = Quad::new(Vec3::new(-1.0, -1.0, 1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0), material());
+        let point = shape.sample_point(Vec3::zero()).unwrap();
+        assert!((point.z() - 1.0).abs() < 1e-6 && point.x().abs() <= 1.0 && point.y().abs() <= 1.0);
+        // Straight on, a small patch of area A is seen as a solid angle of A
+        assert!((shape.pdf(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0)) - 0.25).abs() < 1e-6);
+        // Integrating over all directions gives one
+        let count = 200000;
+        let sum: f64 = (0..count).map(|_| shape.pdf(Vec3::zero(), Vec3::random_unit())).sum();
+        assert!((4.0 * PI * sum / count as f64 - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn spawned_rays_leave_surface() {
+        // Far from the origin and tiny, where a fixed epsilon breaks down
+        for (center, radius) in [(Vec3::new(1e4, -2e4, 3e4), 50.0), (Vec3::new(0.3, 0.2, 0.1), 1e-3)] {
+            let shape = Sphere::new(center, radius, material());
+            for _ in 0..1000 {
+                let origin = center + 3.0 * radius * Vec3::random_unit();
+                let hit = shape.hit(Ray::new(origin, center - origin), 0.0..f64::INFINITY).unwrap();
+
+                // Bouncing off can't hit a sphere again
+                let bounce = Vec3::random_on_hemisphere(hit.normal());
+                assert!(shape.hit(hit.spawn_ray(bounce), 0.0..f64::INFINITY).is_none());
+
+                // Going inside only hits the far side, a chord of length 2r cos away
+                let inside = hit.spawn_ray(-bounce);
+                let far_side = shape.hit(inside, 0.0..f64::INFINITY).unwrap();
+                let chord = 2.0 * radius * bounce.dot(hit.normal());
+                assert!((far_side.hit_point() - inside.origin()).norm() > 0.5 * chord);
+            }
+        }
+    }
+
+    #[test]
+    fn spawned_rays_leave_axis_planes() {
+        // Points on the floor are exact, so there is no error to offset by
+        let floor = Quad::new(Vec3::new(-1.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 2.0), Vec3::new(2.0, 0.0, 0.0), material());
+        for _ in 0..1000 {
+            let target = Vec3::new(Vec3::random().x(), 0.0, Vec3::random().z());
+            let hit = floor.hit(Ray::new(Vec3::new(0.0, 1.0, 0.0), target - Vec3::new(0.0, 1.0, 0.0)), 0.0..f64::INFINITY).unwrap();
+            let bounce = Vec3::random_on_hemisphere(hit.normal());
+            assert!(floor.hit(hit.spawn_ray(bounce), 0.0..f64::INFINITY).is_none());
+        }
+    }
+
+    #[test]
+    fn triangles_are_watertight() {
+        // Two triangles sharing the edge from (0, 0, 0) to (1, 1, 0)
+        let a = Triangle::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0), material());
+        let b = Triangle::new(Vec3::zero(), Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material());
+        for i in 0..10000 {
+            let on_edge = (i as f64 + 0.5) / 10000.0;
+            let target = Vec3::new(on_edge, on_edge, 0.0);
+            let origin = Vec3::new(0.5, 0.5, 1.0) + Vec3::random();
+            let ray = Ray::new(origin, target - origin);
+            assert!(a.hit(ray, 0.0..f64::INFINITY).is_some() || b.hit(ray, 0.0..f64::INFINITY).is_some());
+        }
+    }
+}
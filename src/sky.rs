@@ -0,0 +1,224 @@
+use std::f64::consts::PI;
+use crate::spectrum::{blackbody_rgb, xyz_to_rgb};
+use crate::util::random;
+use crate::vector::Vec3;
+
+// Half the angle the sun takes up in the sky, in degrees
+const SUN_ANGULAR_RADIUS: f64 = 0.2665;
+// Radiance per kcd/m² of sky luminance, which puts the light of a sun high up in
+// the sky at around π, so it lights a white surface about as bright as 1
+const SCALE: f64 = 1.0 / 30.0;
+// Irradiance of the sun before going through the atmosphere
+const SUN_IRRADIANCE: f64 = 4.0;
+
+/// Daylight sky with a sun, from the analytic model in "A Practical Analytic Model
+/// for Daylight" by Preetham, Shirley and Smits. Used as the background of a scene,
+/// lighting everything that can see it. Y is up.
+#[derive(Debug, Clone)]
+pub struct Sky {
+    sun_dir: Vec3,
+    // Coefficients of the Perez formula for Y, x and y
+    perez: [[f64; 5]; 3],
+    // Y, x and y of the sky straight up
+    zenith: [f64; 3],
+    sun_radiance: Vec3,
+    sun_cos_max: f64,
+    // What can be seen below the horizon
+    ground_radiance: Vec3,
+    intensity: f64,
+}
+
+impl Sky {
+    /// Sky with the sun `elevation` degrees above the horizon, and `azimuth` degrees
+    /// around from the +x axis towards +z. The haze goes from a `turbidity` of 2 for
+    /// a very clear sky to 10 for a hazy one. The ground below the horizon reflects
+    /// `ground_albedo` of the light of the sun and sky. The model only covers daylight,
+    /// once the sun has set it's gone but the sky stays lit.
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, ground_albedo: Vec3) -> Self {
+        let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+        let sun_dir = Vec3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin());
+        let t = turbidity;
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        // Angle of the sun from straight up
+        let theta = PI / 2.0 - elevation.max(0.0);
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let (t2, theta2, theta3) = (t * t, theta * theta, theta * theta * theta);
+        let zenith = [
+            ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0),
+            t2 * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+                + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+                + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886),
+            t2 * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+                + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+                + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688),
+        ];
+
+        let sun_cos_max = SUN_ANGULAR_RADIUS.to_radians().cos();
+        let sun_radiance = if elevation > 0.0 {
+            let white = blackbody_rgb(5800.0);
+            let transmittance = sun_transmittance(theta, turbidity);
+            SUN_IRRADIANCE / (2.0 * PI * (1.0 - sun_cos_max)) * transmittance * (white / white.luminance())
+        } else {
+            Vec3::zero()
+        };
+
+        let mut sky = Self {
+            sun_dir,
+            perez,
+            zenith,
+            sun_radiance,
+            sun_cos_max,
+            ground_radiance: Vec3::zero(),
+            intensity: 1.0,
+        };
+        // Light falling on the ground, evened out over every direction
+        sky.ground_radiance = ground_albedo * sky.ground_irradiance() / PI;
+        sky
+    }
+
+    /// Make the whole sky brighter or darker.
+    pub fn with_intensity(self, intensity: f64) -> Self {
+        Self { intensity, ..self }
+    }
+
+    /// Normalized direction towards the sun.
+    pub fn sun_dir(&self) -> Vec3 {
+        self.sun_dir
+    }
+
+    /// Light coming from the direction `dir`, including the sun.
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let dir = dir.normalize();
+        if dir.y() < 0.0 {
+            return self.intensity * self.ground_radiance;
+        }
+        let mut radiance = self.sky_radiance(dir);
+        if dir.dot(self.sun_dir) >= self.sun_cos_max {
+            radiance = radiance + self.sun_radiance;
+        }
+        self.intensity * radiance
+    }
+
+    /// Pick a direction towards the sun, uniformly over its disk. None once it has
+    /// set.
+    pub fn sample_sun(&self) -> Option<Vec3> {
+        if self.sun_radiance.is_near_zero() {
+            return None;
+        }
+        let cos = 1.0 - random::<f64>() * (1.0 - self.sun_cos_max);
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * random::<f64>();
+        let (u, v) = self.sun_dir.orthonormal_basis();
+        Some((sin * phi.cos()) * u + (sin * phi.sin()) * v + cos * self.sun_dir)
+    }
+
+    /// Density per solid angle of `sample_sun` picking `dir`.
+    pub fn sun_pdf(&self, dir: Vec3) -> f64 {
+        if self.sun_radiance.is_near_zero() || dir.normalize().dot(self.sun_dir) < self.sun_cos_max {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - self.sun_cos_max))
+    }
+
+    /// The sky without the sun, which the Perez formula gives relative to straight up.
+    fn sky_radiance(&self, dir: Vec3) -> Vec3 {
+        let cos_theta = dir.y().max(1e-3);
+        let gamma = dir.dot(self.sun_dir).clamp(-1.0, 1.0).acos();
+        let theta_sun = self.sun_dir.y().clamp(-1.0, 1.0).acos().min(PI / 2.0);
+        let [big_y, x, y] = [0, 1, 2].map(|i| {
+            let perez = |cos_theta: f64, gamma: f64| {
+                let [a, b, c, d, e] = self.perez[i];
+                (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+            };
+            self.zenith[i] * perez(cos_theta, gamma) / perez(1.0, theta_sun)
+        });
+        if big_y <= 0.0 || y <= 0.0 {
+            return Vec3::zero();
+        }
+        let xyz = Vec3::new(x / y * big_y, big_y, (1.0 - x - y) / y * big_y);
+        let rgb = xyz_to_rgb(SCALE * xyz);
+        Vec3::new(rgb.x().max(0.0), rgb.y().max(0.0), rgb.z().max(0.0))
+    }
+
+    /// Light from the sun and sky falling on the ground.
+    fn ground_irradiance(&self) -> Vec3 {
+        let (steps_theta, steps_phi) = (32, 64);
+        let (d_theta, d_phi) = (PI / 2.0 / steps_theta as f64, 2.0 * PI / steps_phi as f64);
+        let mut total = (2.0 * PI * (1.0 - self.sun_cos_max) * self.sun_dir.y().max(0.0)) * self.sun_radiance;
+        for i in 0..steps_theta {
+            let theta = (i as f64 + 0.5) * d_theta;
+            for j in 0..steps_phi {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let dir = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                total = total + (theta.cos() * theta.sin() * d_theta * d_phi) * self.sky_radiance(dir);
+            }
+        }
+        total
+    }
+}
+
+/// How much of the sunlight gets through the atmosphere when the sun is `theta` from
+/// straight up, for red, green and blue. Air scatters blue away (Rayleigh) and haze
+/// scatters everything a bit (Ångström).
+fn sun_transmittance(theta: f64, turbidity: f64) -> Vec3 {
+    // How much more air the light goes through than straight down, by Kasten
+    let degrees = theta.to_degrees();
+    let air_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - degrees).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let [r, g, b] = [0.68, 0.55, 0.44f64].map(|micrometers| {
+        let rayleigh = 0.008735 * micrometers.powf(-4.08);
+        let aerosol = beta * micrometers.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+    });
+    Vec3::new(r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sky(elevation: f64) -> Sky {
+        Sky::new(elevation, 30.0, 3.0, Vec3::new(0.3, 0.3, 0.3))
+    }
+
+    #[test]
+    fn blue_sky_and_bright_sun() {
+        let sky = sky(45.0);
+        let zenith = sky.radiance(Vec3::new(0.0, 1.0, 0.0));
+        assert!(zenith.z() > zenith.x() && zenith.luminance() > 0.0);
+        assert!(sky.radiance(sky.sun_dir()).luminance() > 1000.0 * zenith.luminance());
+        // Redder at sunset, as the light goes through more air
+        let sunset = Sky::new(2.0, 30.0, 3.0, Vec3::zero());
+        let (noon, evening) = (sky.radiance(sky.sun_dir()), sunset.radiance(sunset.sun_dir()));
+        assert!(evening.x() / evening.z() > noon.x() / noon.z());
+        assert!(evening.luminance() < noon.luminance());
+    }
+
+    #[test]
+    fn ground_reflects_sun_and_sky() {
+        let white = Sky::new(60.0, 30.0, 3.0, Vec3::new(1.0, 1.0, 1.0));
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let expected = white.ground_irradiance() / PI;
+        assert!((white.radiance(down) - expected).norm() < 1e-9);
+        // Mostly lit by the sun, which comes out around 1 for a white surface
+        assert!(white.radiance(down).luminance() > 0.5 && white.radiance(down).luminance() < 2.0);
+        assert!(sky(-10.0).sample_sun().is_none());
+    }
+
+    #[test]
+    fn sun_sampling() {
+        let sky = sky(20.0);
+        let pdf = 1.0 / (2.0 * PI * (1.0 - sky.sun_cos_max));
+        for _ in 0..100 {
+            let dir = sky.sample_sun().unwrap();
+            assert!(dir.dot(sky.sun_dir()) >= sky.sun_cos_max - 1e-6);
+        }
+        assert_eq!(sky.sun_pdf(sky.sun_dir()), pdf);
+        assert_eq!(sky.sun_pdf(Vec3::new(0.0, 1.0, 0.0)), 0.0);
+    }
+}
@@ -0,0 +1,287 @@
+use std::ops::{Add, Mul};
+use std::sync::OnceLock;
+use crate::util::random;
+use crate::vector::Vec3;
+
+// Visible range in nanometers
+pub const WAVELENGTH_MIN: f64 = 380.0;
+pub const WAVELENGTH_MAX: f64 = 720.0;
+
+// Wavelengths traced together per path
+pub const SPECTRUM_SAMPLES: usize = 4;
+
+/// The wavelengths carried by a path. The first one is the hero wavelength and the
+/// others are evenly spaced after it, wrapping around the visible range.
+#[derive(Debug, Copy, Clone)]
+pub struct Wavelengths {
+    lambda: [f64; SPECTRUM_SAMPLES],
+    pdf: [f64; SPECTRUM_SAMPLES],
+}
+
+impl Wavelengths {
+    pub fn sample() -> Self {
+        Self::from_hero(WAVELENGTH_MIN + random::<f64>() * (WAVELENGTH_MAX - WAVELENGTH_MIN))
+    }
+
+    pub fn from_hero(hero: f64) -> Self {
+        let range = WAVELENGTH_MAX - WAVELENGTH_MIN;
+        let mut lambda = [0.0; SPECTRUM_SAMPLES];
+        for (i, wavelength) in lambda.iter_mut().enumerate() {
+            let offset = i as f64 * range / SPECTRUM_SAMPLES as f64;
+            *wavelength = WAVELENGTH_MIN + (hero - WAVELENGTH_MIN + offset) % range;
+        }
+        Self { lambda, pdf: [1.0 / range; SPECTRUM_SAMPLES] }
+    }
+
+    pub fn hero(&self) -> f64 { self.lambda[0] }
+    pub fn get(&self, i: usize) -> f64 { self.lambda[i] }
+
+    /// Drop every wavelength but the hero, for when a path can no longer be shared,
+    /// like after refracting through dispersive glass.
+    pub fn terminate_secondary(&mut self) {
+        if self.pdf[1] == 0.0 {
+            return;
+        }
+        self.pdf[0] /= SPECTRUM_SAMPLES as f64;
+        for pdf in self.pdf.iter_mut().skip(1) {
+            *pdf = 0.0;
+        }
+    }
+
+    /// Convert radiance sampled at these wavelengths to linear RGB.
+    pub fn to_rgb(self, spectrum: SampledSpectrum) -> Vec3 {
+        let mut xyz = Vec3::zero();
+        for i in 0..SPECTRUM_SAMPLES {
+            if self.pdf[i] == 0.0 {
+                continue;
+            }
+            let (x, y, z) = cie_xyz(self.lambda[i]);
+            xyz = xyz + (spectrum.0[i] / self.pdf[i]) * Vec3::new(x, y, z);
+        }
+        xyz = xyz / (SPECTRUM_SAMPLES as f64 * CIE_Y_INTEGRAL);
+        xyz_to_rgb(xyz) * white_balance()
+    }
+}
+
+/// Values of a spectrum at the wavelengths of a path.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SampledSpectrum(pub [f64; SPECTRUM_SAMPLES]);
+
+impl SampledSpectrum {
+    pub fn zero() -> Self { Self([0.0; SPECTRUM_SAMPLES]) }
+    pub fn one() -> Self { Self([1.0; SPECTRUM_SAMPLES]) }
+
+    /// Smooth spectrum that has the given linear RGB color. Reflectances in [0, 1]
+    /// stay in [0, 1], and white becomes a constant spectrum.
+    pub fn from_rgb(rgb: Vec3, wavelengths: &Wavelengths) -> Self {
+        let mut values = [0.0; SPECTRUM_SAMPLES];
+        for (i, value) in values.iter_mut().enumerate() {
+            let (r, g, b) = rgb_basis(wavelengths.get(i));
+            *value = rgb.x() * r + rgb.y() * g + rgb.z() * b;
+        }
+        Self(values)
+    }
+
+    /// The largest value at any of the wavelengths.
+    pub fn max_value(&self) -> f64 {
+        self.0.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        let mut values = self.0;
+        for (value, other) in values.iter_mut().zip(rhs.0) {
+            *value += other;
+        }
+        Self(values)
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut values = self.0;
+        for (value, other) in values.iter_mut().zip(rhs.0) {
+            *value *= other;
+        }
+        Self(values)
+    }
+}
+
+impl Mul<SampledSpectrum> for f64 {
+    type Output = SampledSpectrum;
+
+    fn mul(self, rhs: SampledSpectrum) -> Self::Output {
+        let mut values = rhs.0;
+        for value in values.iter_mut() {
+            *value *= self;
+        }
+        SampledSpectrum(values)
+    }
+}
+
+/// Red, green and blue basis spectra. They sum to one at every wavelength.
+fn rgb_basis(wavelength: f64) -> (f64, f64, f64) {
+    let blue = 1.0 - smoothstep(475.0, 515.0, wavelength);
+    let red = smoothstep(575.0, 605.0, wavelength);
+    let green = 1.0 - blue - red;
+    (red, green, blue)
+}
+
+fn smoothstep(from: f64, to: f64, x: f64) -> f64 {
+    let t = ((x - from) / (to - from)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Integral of the CIE y matching function over the visible range
+const CIE_Y_INTEGRAL: f64 = 106.856895;
+
+/// CIE 1931 color matching functions using the multi-lobe fit from Wyman, Sloan and
+/// Shirley, "Simple Analytic Approximations to the CIE XYZ Color Matching Functions".
+pub fn cie_xyz(wavelength: f64) -> (f64, f64, f64) {
+    fn g(x: f64, mu: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+        let t = (x - mu) / if x < mu { sigma_low } else { sigma_high };
+        (-0.5 * t * t).exp()
+    }
+    let x = 1.056 * g(wavelength, 599.8, 37.9, 31.0)
+        + 0.362 * g(wavelength, 442.0, 16.0, 26.7)
+        - 0.065 * g(wavelength, 501.1, 20.4, 26.2);
+    let y = 0.821 * g(wavelength, 568.8, 46.9, 40.5)
+        + 0.286 * g(wavelength, 530.9, 16.3, 31.1);
+    let z = 1.217 * g(wavelength, 437.0, 11.8, 36.0)
+        + 0.681 * g(wavelength, 459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+/// CIE XYZ to linear sRGB.
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    let (x, y, z) = (xyz.x(), xyz.y(), xyz.z());
+    Vec3::new(
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    )
+}
+
+/// Radiance of a blackbody at a wavelength in nanometers and a temperature in Kelvin,
+/// from Planck's law.
+pub fn blackbody(wavelength: f64, temperature: f64) -> f64 {
+    if temperature <= 0.0 {
+        return 0.0;
+    }
+    const C: f64 = 299792458.0;
+    const H: f64 = 6.62606957e-34;
+    const KB: f64 = 1.3806488e-23;
+    let l = wavelength * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * KB * temperature)).exp() - 1.0))
+}
+
+// Temperature step of the precomputed blackbody colors
+const BLACKBODY_STEP: f64 = 50.0;
+const BLACKBODY_ENTRIES: usize = 400;
+
+/// Linear RGB color of a blackbody, scaled so that one at 6500 K has a luminance of 1.
+/// Glowing embers are dim and red, while hotter things quickly get brighter and bluer.
+pub fn blackbody_rgb(temperature: f64) -> Vec3 {
+    static TABLE: OnceLock<Vec<Vec3>> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let integrate = |temperature: f64| {
+            let steps = 100;
+            let step = (WAVELENGTH_MAX - WAVELENGTH_MIN) / steps as f64;
+            let mut xyz = Vec3::zero();
+            for i in 0..steps {
+                let wavelength = WAVELENGTH_MIN + (i as f64 + 0.5) * step;
+                let (x, y, z) = cie_xyz(wavelength);
+                xyz = xyz + (step * blackbody(wavelength, temperature)) * Vec3::new(x, y, z);
+            }
+            xyz
+        };
+        let reference = integrate(6500.0).y();
+        (0..BLACKBODY_ENTRIES)
+            .map(|i| {
+                // Deep reds are outside of sRGB
+                let rgb = xyz_to_rgb(integrate(i as f64 * BLACKBODY_STEP) / reference) * white_balance();
+                Vec3::new(rgb.x().max(0.0), rgb.y().max(0.0), rgb.z().max(0.0))
+            })
+            .collect()
+    });
+
+    let index = (temperature.max(0.0) / BLACKBODY_STEP).min((BLACKBODY_ENTRIES - 1) as f64);
+    let low = index.floor() as usize;
+    let high = (low + 1).min(BLACKBODY_ENTRIES - 1);
+    let fraction = index - low as f64;
+    (1.0 - fraction) * table[low] + fraction * table[high]
+}
+
+/// Scale per channel so that a constant spectrum comes out as white instead of the
+/// slightly pink equal energy white point.
+fn white_balance() -> Vec3 {
+    static WHITE_BALANCE: OnceLock<Vec3> = OnceLock::new();
+    *WHITE_BALANCE.get_or_init(|| {
+        let steps = 1000;
+        let step = (WAVELENGTH_MAX - WAVELENGTH_MIN) / steps as f64;
+        let mut xyz = Vec3::zero();
+        for i in 0..steps {
+            let (x, y, z) = cie_xyz(WAVELENGTH_MIN + (i as f64 + 0.5) * step);
+            xyz = xyz + step * Vec3::new(x, y, z);
+        }
+        let white = xyz_to_rgb(xyz / CIE_Y_INTEGRAL);
+        Vec3::new(1.0 / white.x(), 1.0 / white.y(), 1.0 / white.z())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stratified_rgb(rgb: Vec3) -> Vec3 {
+        // Average over many evenly spaced hero wavelengths
+        let steps = 500;
+        let mut sum = Vec3::zero();
+        for i in 0..steps {
+            let hero = WAVELENGTH_MIN + (i as f64 + 0.5) / steps as f64 * (WAVELENGTH_MAX - WAVELENGTH_MIN) / SPECTRUM_SAMPLES as f64;
+            let wavelengths = Wavelengths::from_hero(hero);
+            sum = sum + wavelengths.to_rgb(SampledSpectrum::from_rgb(rgb, &wavelengths));
+        }
+        sum / steps as f64
+    }
+
+    #[test]
+    fn white_stays_white() {
+        let rgb = stratified_rgb(Vec3::new(1.0, 1.0, 1.0));
+        assert!((rgb.x() - 1.0).abs() < 0.01, "{:?}", rgb);
+        assert!((rgb.y() - 1.0).abs() < 0.01, "{:?}", rgb);
+        assert!((rgb.z() - 1.0).abs() < 0.01, "{:?}", rgb);
+    }
+
+    #[test]
+    fn primaries_keep_their_hue() {
+        let red = stratified_rgb(Vec3::new(1.0, 0.0, 0.0));
+        assert!(red.x() > red.y() && red.x() > red.z());
+        let green = stratified_rgb(Vec3::new(0.0, 1.0, 0.0));
+        assert!(green.y() > green.x() && green.y() > green.z());
+        let blue = stratified_rgb(Vec3::new(0.0, 0.0, 1.0));
+        assert!(blue.z() > blue.x() && blue.z() > blue.y());
+    }
+
+    #[test]
+    fn blackbody_gets_bluer_when_hotter() {
+        let ember = blackbody_rgb(1500.0);
+        let sun = blackbody_rgb(5800.0);
+        assert!(ember.x() > ember.z());
+        assert!(sun.z() / sun.x() > ember.z() / ember.x());
+        assert!(sun.y() > ember.y());
+    }
+
+    #[test]
+    fn secondary_wavelengths_wrap_around() {
+        let wavelengths = Wavelengths::from_hero(700.0);
+        for i in 0..SPECTRUM_SAMPLES {
+            assert!((WAVELENGTH_MIN..WAVELENGTH_MAX).contains(&wavelengths.get(i)));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use crate::bdpt::LightSources;
+use crate::camera::Camera;
+use crate::ray::Ray;
+use crate::renderer::{background, emitted, next_bounce, surface_direct_light};
+use crate::scene::Scene;
+use crate::shapes::HitResult;
+use crate::vector::Vec3;
+
+// How fast the radius shrinks, from the stochastic progressive photon mapping paper
+const ALPHA: f64 = 2.0 / 3.0;
+
+/// Where the camera sees a surface that isn't a mirror or glass in one pass, which
+/// photons landing nearby light up.
+struct VisiblePoint<'a> {
+    hit: HitResult<'a>,
+    dir_in: Vec3,
+    // Of the camera ray on its way here
+    beta: Vec3,
+}
+
+/// What is known about a pixel over all passes so far.
+struct Pixel<'a> {
+    radius: f64,
+    // Photons counted so far, shrinking along with the radius
+    photons: f64,
+    // Flux of the photons within the radius, times how much of it reaches the camera
+    tau: Vec3,
+    // Light straight from the lights, found like the path tracer does
+    direct: Vec3,
+    visible: Option<VisiblePoint<'a>>,
+    // Of the photons landing in this pass
+    phi: Vec3,
+    count: u32,
+}
+
+/// Stochastic progressive photon mapping. Every pass traces a ray from the camera
+/// through each pixel to the first surface that isn't a mirror or glass, then sends
+/// out photons from the lights and collects the ones landing close to those points.
+/// The radius they are collected in shrinks every pass, so it converges.
+pub(crate) struct PhotonMapper<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    max_depth: u32,
+    lights: LightSources<'a>,
+    photons: usize,
+    radius: f64,
+}
+
+impl<'a> PhotonMapper<'a> {
+    pub(crate) fn new(scene: &'a Scene, camera: &'a Camera, max_depth: u32, photons: usize, radius: f64) -> Self {
+        Self { scene, camera, max_depth, lights: LightSources::new(scene), photons, radius }
+    }
+
+    /// Color of each pixel after `passes` passes, row by row.
+    pub(crate) fn render(&self, passes: u32, progress: bool) -> Vec<Vec3> {
+        let (width, height) = (self.camera.width(), self.camera.height());
+        let mut pixels: Vec<Pixel> = (0..width * height)
+            .map(|_| Pixel { radius: self.radius, photons: 0.0, tau: Vec3::zero(), direct: Vec3::zero(), visible: None, phi: Vec3::zero(), count: 0 })
+            .collect();
+
+        for pass in 0..passes {
+            if progress {
+                println!("{} / {}", pass, passes);
+            }
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                let (direct, visible) = self.camera_path(self.camera.ray_rand(x, y));
+                pixel.direct = pixel.direct + direct;
+                pixel.visible = visible;
+            }
+
+            let grid = Grid::new(&pixels);
+            for _ in 0..self.photons {
+                self.trace_photon(&grid, &mut pixels);
+            }
+
+            for pixel in &mut pixels {
+                if pixel.count > 0 {
+                    let photons = pixel.photons + ALPHA * pixel.count as f64;
+                    let radius = pixel.radius * (photons / (pixel.photons + pixel.count as f64)).sqrt();
+                    let beta = pixel.visible.as_ref().map_or(Vec3::zero(), |visible| visible.beta);
+                    let scale = (radius * radius) / (pixel.radius * pixel.radius);
+                    pixel.tau = scale * (pixel.tau + beta * pixel.phi);
+                    pixel.photons = photons;
+                    pixel.radius = radius;
+                }
+                pixel.phi = Vec3::zero();
+                pixel.count = 0;
+            }
+        }
+
+        let passes = passes.max(1) as f64;
+        pixels
+            .iter()
+            .map(|pixel| pixel.direct / passes + pixel.tau / (passes * PI * pixel.radius * pixel.radius))
+            .collect()
+    }
+
+    /// Light reaching the camera along `ray` straight from the lights, and where the
+    /// ray first lands on something photons can be collected on. Mirrors and glass
+    /// on the way there can't have lights sampled through them, so lights seen in
+    /// them count fully.
+    fn camera_path(&self, mut ray: Ray) -> (Vec3, Option<VisiblePoint<'a>>) {
+        let mut color = Vec3::zero();
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+        for _ in 0..self.max_depth {
+            let hit = match self.scene.hit_surface(ray) {
+                Some(hit) => hit,
+                None => return (color + beta * background(self.scene, ray, None), None),
+            };
+            color = color + beta * emitted(self.scene, ray, &hit, None);
+            let material = hit.material();
+            let scatter = match material.scatter(ray, &hit) {
+                Some(scatter) => scatter,
+                None => return (color, None),
+            };
+            if !material.is_specular() {
+                // Light found by scattering once more, weighed against sampling the lights
+                let mut direct = surface_direct_light(self.scene, ray, &hit, None);
+                let bounce = next_bounce(ray, &hit, &scatter, None);
+                direct = direct + scatter.attenuation * match self.scene.hit_surface(scatter.ray) {
+                    Some(light) => emitted(self.scene, scatter.ray, &light, bounce),
+                    None => background(self.scene, scatter.ray, bounce),
+                };
+                let visible = VisiblePoint { hit, dir_in: ray.dir().normalize(), beta };
+                return (color + beta * direct, Some(visible));
+            }
+            beta = beta * scatter.attenuation;
+            ray = scatter.ray;
+        }
+        (color, None)
+    }
+
+    /// Send a photon out from a light, leaving some of its flux at the pixels it
+    /// lands close to after its first bounce. Light reaching surfaces without
+    /// bouncing was found by the camera paths already.
+    fn trace_photon(&self, grid: &Grid, pixels: &mut [Pixel]) {
+        let (mut ray, flux) = match self.lights.emit() {
+            Some(emitted) => emitted,
+            None => return,
+        };
+        let mut beta = (1.0 / self.photons as f64) * flux;
+        for depth in 0..self.max_depth {
+            let hit = match self.scene.hit_surface(ray) {
+                Some(hit) => hit,
+                None => return,
+            };
+            let material = hit.material();
+            let dir = ray.dir().normalize();
+            if depth > 0 && !material.is_specular() {
+                let point = hit.hit_point();
+                for &i in grid.near(point) {
+                    let pixel = &mut pixels[i];
+                    let visible = pixel.visible.as_ref().unwrap();
+                    if (visible.hit.hit_point() - point).norm_sq() > pixel.radius * pixel.radius {
+                        continue;
+                    }
+                    let normal = visible.hit.normal();
+                    let f = visible.hit.material().eval(visible.dir_in, normal, -dir);
+                    pixel.phi = pixel.phi + f * beta;
+                    pixel.count += 1;
+                }
+            }
+
+            let scatter = match material.scatter(ray, &hit) {
+                Some(scatter) => scatter,
+                None => return,
+            };
+            beta = beta * scatter.attenuation;
+            if beta.is_near_zero() {
+                return;
+            }
+            ray = scatter.ray;
+        }
+    }
+}
+
+/// The pixels whose visible points are within their radius of each cell of a grid,
+/// with cells as big as the largest radius.
+struct Grid {
+    size: f64,
+    cells: HashMap<[i64; 3], Vec<usize>>,
+}
+
+impl Grid {
+    fn new(pixels: &[Pixel]) -> Self {
+        let size = pixels.iter().filter(|pixel| pixel.visible.is_some()).map(|pixel| pixel.radius).fold(0.0, f64::max);
+        let mut grid = Self { size, cells: HashMap::new() };
+        if size <= 0.0 {
+            return grid;
+        }
+        for (i, pixel) in pixels.iter().enumerate() {
+            if let Some(visible) = &pixel.visible {
+                let point = visible.hit.hit_point();
+                let reach = Vec3::new(pixel.radius, pixel.radius, pixel.radius);
+                let (low, high) = (grid.cell(point - reach), grid.cell(point + reach));
+                for x in low[0]..=high[0] {
+                    for y in low[1]..=high[1] {
+                        for z in low[2]..=high[2] {
+                            grid.cells.entry([x, y, z]).or_default().push(i);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell(&self, point: Vec3) -> [i64; 3] {
+        [0, 1, 2].map(|axis| (point.axis(axis) / self.size).floor() as i64)
+    }
+
+    /// Pixels that might have their visible point within their radius of `point`.
+    fn near(&self, point: Vec3) -> &[usize] {
+        if self.size <= 0.0 {
+            return &[];
+        }
+        self.cells.get(&self.cell(point)).map_or(&[], |pixels| pixels.as_slice())
+    }
+}
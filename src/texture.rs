@@ -0,0 +1,123 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use image::{ImageResult, RgbImage};
+use crate::vector::Vec3;
+
+/// Colors that change across a surface, looked up by the uv coordinates of a hit.
+#[derive(Clone, Debug)]
+pub enum Texture {
+    Constant(Vec3),
+    // Squares of `scale` in uv space
+    Checker {
+        even: Vec3,
+        odd: Vec3,
+        scale: f64,
+    },
+    Image(Arc<ImageTexture>),
+}
+
+impl Texture {
+    pub fn value(&self, uv: (f64, f64)) -> Vec3 {
+        match self {
+            Texture::Constant(color) => *color,
+            Texture::Checker { even, odd, scale } => {
+                let (u, v) = ((uv.0 / scale).floor() as i64, (uv.1 / scale).floor() as i64);
+                if (u + v).rem_euclid(2) == 0 { *even } else { *odd }
+            }
+            Texture::Image(image) => image.lookup(uv),
+        }
+    }
+
+    /// The color averaged over the whole uv square.
+    pub fn average(&self) -> Vec3 {
+        match self {
+            Texture::Constant(color) => *color,
+            Texture::Checker { even, odd, .. } => 0.5 * (*even + *odd),
+            Texture::Image(image) => image.average,
+        }
+    }
+}
+
+impl From<Vec3> for Texture {
+    fn from(color: Vec3) -> Self {
+        Texture::Constant(color)
+    }
+}
+
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    // Linear colors, row by row from the top
+    pixels: Vec<Vec3>,
+    average: Vec3,
+}
+
+impl Debug for ImageTexture {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageTexture")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("average", &self.average)
+            .finish()
+    }
+}
+
+impl ImageTexture {
+    pub fn new(image: &RgbImage) -> Self {
+        // Images are stored gamma corrected the same way renders are
+        let pixels: Vec<Vec3> = image.pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0.map(|channel| channel as f64 / 255.0);
+                Vec3::new(r * r, g * g, b * b)
+            })
+            .collect();
+        let sum = pixels.iter().fold(Vec3::zero(), |sum, &pixel| sum + pixel);
+        let average = sum / pixels.len().max(1) as f64;
+        Self { width: image.width(), height: image.height(), pixels, average }
+    }
+
+    pub fn load(file_path: &str) -> ImageResult<Self> {
+        Ok(Self::new(&image::open(file_path)?.to_rgb8()))
+    }
+
+    /// Nearest pixel, with v going up from the bottom of the image. Repeats outside of
+    /// the uv square.
+    fn lookup(&self, (u, v): (f64, f64)) -> Vec3 {
+        if self.pixels.is_empty() {
+            return Vec3::zero();
+        }
+        let x = ((u.rem_euclid(1.0) * self.width as f64) as u32).min(self.width - 1);
+        let y = (((1.0 - v.rem_euclid(1.0)) * self.height as f64) as u32).min(self.height - 1);
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!((actual - expected).norm() < 1e-6, "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn checker() {
+        let texture = Texture::Checker { even: Vec3::zero(), odd: Vec3::new(1.0, 1.0, 1.0), scale: 0.25 };
+        assert_near(texture.value((0.1, 0.1)), Vec3::zero());
+        assert_near(texture.value((0.3, 0.1)), Vec3::new(1.0, 1.0, 1.0));
+        assert_near(texture.value((-0.1, 0.1)), Vec3::new(1.0, 1.0, 1.0));
+        assert_near(texture.average(), Vec3::new(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn image_is_linear_and_upright() {
+        // White on top, grey below
+        let image = RgbImage::from_fn(1, 2, |_, y| if y == 0 { Rgb([255; 3]) } else { Rgb([127; 3]) });
+        let texture = ImageTexture::new(&image);
+        assert_near(texture.lookup((0.5, 0.9)), Vec3::new(1.0, 1.0, 1.0));
+        let dark = (127.0 / 255.0) * (127.0 / 255.0);
+        assert!((texture.lookup((0.5, 0.1)).x() - dark).abs() < 1e-6);
+        assert!((texture.average.x() - 0.5 * (1.0 + dark)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::mlt::PrimarySample;
+use crate::vector::Vec3;
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+    // Takes over from the generator while Metropolis light transport runs
+    static PRIMARY: RefCell<Option<PrimarySample>> = const { RefCell::new(None) };
+}
+
+/// Random value like `rand::random`, but from a generator that can be seeded.
+pub fn random<T>() -> T where Standard: Distribution<T> {
+    if let Some(value) = PRIMARY.with(|primary| primary.borrow_mut().as_mut().map(|primary| primary.gen())) {
+        return value;
+    }
+    RNG.with(|rng| rng.borrow_mut().gen())
+}
+
+/// Make `random` give the values of `primary` on the current thread, or go back to
+/// the generator for None. Gives back the one used before.
+pub(crate) fn set_primary(primary: Option<PrimarySample>) -> Option<PrimarySample> {
+    PRIMARY.with(|current| current.replace(primary))
+}
+
+/// Run `f` on the primary sample `random` is using, if there is one.
+pub(crate) fn with_primary<R>(f: impl FnOnce(&mut PrimarySample) -> R) -> Option<R> {
+    PRIMARY.with(|primary| primary.borrow_mut().as_mut().map(f))
+}
+
+/// Make the random values of the current thread the same every run, like for
+/// benchmarks and tests that compare images.
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+/// Picks indices with a chance proportional to their weights in constant time, using
+/// Vose's alias method.
+#[derive(Debug, Clone)]
+pub struct AliasTable {
+    // Chance of keeping a column instead of going to its alias
+    keep: Vec<f64>,
+    alias: Vec<usize>,
+    probabilities: Vec<f64>,
+}
+
+impl AliasTable {
+    /// None if no weight is above zero.
+    pub fn new(weights: &[f64]) -> Option<Self> {
+        let total: f64 = weights.iter().map(|weight| weight.max(0.0)).sum();
+        if !(total > 0.0 && total.is_finite()) {
+            return None;
+        }
+        let n = weights.len();
+        let probabilities: Vec<f64> = weights.iter().map(|weight| weight.max(0.0) / total).collect();
+        // Columns of height n p, cut down to one by topping up the short ones from tall ones
+        let mut keep: Vec<f64> = probabilities.iter().map(|p| p * n as f64).collect();
+        let mut alias: Vec<usize> = (0..n).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| keep[i] < 1.0);
+        while let (Some(&short), Some(&tall)) = (small.last(), large.last()) {
+            small.pop();
+            alias[short] = tall;
+            keep[tall] -= 1.0 - keep[short];
+            if keep[tall] < 1.0 {
+                large.pop();
+                small.push(tall);
+            }
+        }
+        // Whatever is left is one up to rounding errors
+        for i in small.into_iter().chain(large) {
+            keep[i] = 1.0;
+        }
+        Some(Self { keep, alias, probabilities })
+    }
+
+    pub fn sample(&self) -> usize {
+        let column = ((random::<f64>() * self.keep.len() as f64) as usize).min(self.keep.len() - 1);
+        if random::<f64>() < self.keep[column] { column } else { self.alias[column] }
+    }
+
+    /// Chance of `sample` giving `index`.
+    pub fn probability(&self, index: usize) -> f64 {
+        self.probabilities[index]
+    }
+}
+
+/// Bound on the relative error after n rounding operations. Based on the precision
+/// of `Vec3`, which limits points however they were calculated.
+pub const fn gamma(n: u32) -> f64 {
+    let epsilon = Vec3::EPSILON * 0.5;
+    n as f64 * epsilon / (1.0 - n as f64 * epsilon)
+}
+
+/// Real roots of a x² + b x + c = 0, smallest first.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return Vec::new();
+        }
+        return vec![-c / b];
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+    // Avoid cancellation by never subtracting numbers of similar size
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (mut t0, mut t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    if t0 > t1 {
+        std::mem::swap(&mut t0, &mut t1);
+    }
+    vec![t0, t1]
+}
+
+/// Real roots of x³ + a x² + b x + c = 0.
+fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // Substitute x = y - a / 3 to get y³ + p y + q = 0
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let shift = -a / 3.0;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+    if discriminant > 0.0 {
+        // One real root, Cardano's formula
+        let sqrt = discriminant.sqrt();
+        vec![(-q / 2.0 + sqrt).cbrt() + (-q / 2.0 - sqrt).cbrt() + shift]
+    } else if p == 0.0 {
+        vec![shift]
+    } else {
+        // Three real roots, trigonometric method
+        let r = 2.0 * (-p / 3.0).sqrt();
+        let phi = (3.0 * q / (p * r)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3)
+            .map(|k| r * (phi - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos() + shift)
+            .collect()
+    }
+}
+
+/// Real roots of a x⁴ + b x³ + c x² + d x + e = 0, smallest first, using Ferrari's
+/// method followed by a few Newton iterations to clean up rounding errors.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return Vec::new();
+    }
+    // Depressed quartic y⁴ + p y² + q y + r = 0 with x = y - b / 4a
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    let shift = -b / 4.0;
+    let p = c - 3.0 * b * b / 8.0;
+    let q = d - b * c / 2.0 + b * b * b / 8.0;
+    let r = e - b * d / 4.0 + b * b * c / 16.0 - 3.0 * b * b * b * b / 256.0;
+
+    let mut roots = Vec::with_capacity(4);
+    if q.abs() < 1e-12 {
+        // Biquadratic
+        for z in solve_quadratic(1.0, p, r) {
+            if z >= 0.0 {
+                let y = z.sqrt();
+                roots.push(y + shift);
+                roots.push(-y + shift);
+            }
+        }
+    } else {
+        // Resolvent cubic, any root m > 0 splits the quartic into two quadratics
+        let m = solve_cubic(p, p * p / 4.0 - r, -q * q / 8.0)
+            .into_iter()
+            .fold(f64::NEG_INFINITY, f64::max);
+        if m <= 0.0 {
+            return Vec::new();
+        }
+        let sqrt_2m = (2.0 * m).sqrt();
+        let quadratics = [
+            (sqrt_2m, p / 2.0 + m - q / (2.0 * sqrt_2m)),
+            (-sqrt_2m, p / 2.0 + m + q / (2.0 * sqrt_2m)),
+        ];
+        for (linear, constant) in quadratics {
+            for y in solve_quadratic(1.0, linear, constant) {
+                roots.push(y + shift);
+            }
+        }
+    }
+
+    for root in roots.iter_mut() {
+        for _ in 0..2 {
+            let x = *root;
+            let value = (((x + b) * x + c) * x + d) * x + e;
+            let derivative = ((4.0 * x + 3.0 * b) * x + 2.0 * c) * x + d;
+            if derivative != 0.0 {
+                *root = x - value / derivative;
+            }
+        }
+    }
+    roots.sort_by(f64::total_cmp);
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_roots(actual: Vec<f64>, expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn seeded_random_repeats() {
+        seed(7);
+        let first: Vec<f64> = (0..10).map(|_| random()).collect();
+        seed(7);
+        let second: Vec<f64> = (0..10).map(|_| random()).collect();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn alias_table_follows_weights() {
+        seed(1);
+        let weights = [1.0, 0.0, 5.0, 2.0, 0.5];
+        let table = AliasTable::new(&weights).unwrap();
+        let mut counts = [0; 5];
+        for _ in 0..100_000 {
+            counts[table.sample()] += 1;
+        }
+        for (i, count) in counts.into_iter().enumerate() {
+            let expected = weights[i] / 8.5;
+            assert!((table.probability(i) - expected).abs() < 1e-12);
+            assert!((count as f64 / 100_000.0 - expected).abs() < 0.005, "{}: {}", i, count);
+        }
+        assert!(AliasTable::new(&[0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn quadratic() {
+        assert_roots(solve_quadratic(1.0, -3.0, 2.0), &[1.0, 2.0]);
+        assert_roots(solve_quadratic(1.0, 0.0, 1.0), &[]);
+    }
+
+    #[test]
+    fn quartic_four_roots() {
+        // (x - 1)(x - 2)(x + 3)(x - 5)
+        assert_roots(solve_quartic(1.0, -5.0, -7.0, 41.0, -30.0), &[-3.0, 1.0, 2.0, 5.0]);
+    }
+
+    #[test]
+    fn quartic_two_roots() {
+        // (x² + 1)(x - 2)(x - 4)
+        assert_roots(solve_quartic(1.0, -6.0, 9.0, -6.0, 8.0), &[2.0, 4.0]);
+    }
+
+    #[test]
+    fn quartic_biquadratic() {
+        // (x² - 1)(x² - 4)
+        assert_roots(solve_quartic(1.0, 0.0, -5.0, 0.0, 4.0), &[-2.0, -1.0, 1.0, 2.0]);
+    }
+}
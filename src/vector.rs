@@ -1,28 +1,68 @@
-#![feature(stdsimd)]
+use std::fmt::Debug;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use crate::util::random;
 
-use std::arch::asm;
-use std::arch::x86_64::{__m128, __m256d, _mm256_cmp_pd, _mm256_set_pd, _mm256_store_pd, _mm_add_ps, _mm_cmpeq_ps, _mm_dp_ps, _mm_extract_epi64, _mm_extract_ps, _mm_mul_ps, _mm_set_ps, _mm_sub_ps};
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, Neg, Range, Sub};
-use rand::random;
+// How the components are stored is picked with cargo features. The backends that
+// aren't used are still built for tests, so they all pass the same checks.
+#[cfg(any(test, not(feature = "f32")))]
+mod scalar64;
+#[cfg(any(test, all(feature = "f32", not(all(feature = "sse", target_arch = "x86_64")))))]
+mod scalar32;
+#[cfg(all(any(test, feature = "sse"), target_arch = "x86_64"))]
+mod sse;
+
+#[cfg(not(feature = "f32"))]
+type Storage = scalar64::Scalar64;
+#[cfg(all(feature = "f32", not(all(feature = "sse", target_arch = "x86_64"))))]
+type Storage = scalar32::Scalar32;
+#[cfg(all(feature = "sse", target_arch = "x86_64"))]
+type Storage = sse::Sse;
+
+/// The three components of a vector, and the operations worth specializing.
+trait Lanes: Copy + Debug {
+    /// Machine epsilon of the stored components.
+    const EPSILON: f64;
+
+    fn new(x: f64, y: f64, z: f64) -> Self;
+    fn x(&self) -> f64;
+    fn y(&self) -> f64;
+    fn z(&self) -> f64;
+    fn add(self, other: Self) -> Self;
+    fn sub(self, other: Self) -> Self;
+    fn mul(self, other: Self) -> Self;
+    fn dot(self, other: Self) -> f64;
+    /// Closest value above `value` that can be stored.
+    fn next_up(value: f64) -> f64;
+    /// Closest value below `value` that can be stored.
+    fn next_down(value: f64) -> f64;
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct Vec3 {
-    data: __m128
+    data: Storage
 }
 
 impl Vec3 {
+    /// Machine epsilon of the components, which limits how precise a point can be
+    /// however it was calculated.
+    pub const EPSILON: f64 = Storage::EPSILON;
+
     pub fn new(x: f64, y: f64, z: f64) -> Self {
-        unsafe {
-            Self {
-                data: _mm_set_ps(x as f32, y as f32, z as f32, 0.0)
-            }
-        }
+        Self { data: Storage::new(x, y, z) }
     }
     pub fn zero() -> Self { Self::new(0.0, 0.0, 0.0) }
     pub fn random() -> Self {
         let (rx, ry, rz): (f64, f64, f64) = random();
         Self::new(rx * 2.0 - 1.0, ry * 2.0 - 1.0, rz * 2.0 - 1.0)
     }
+    /// Uniformly distributed on the unit sphere.
+    pub fn random_unit() -> Self {
+        let (ru, rv): (f64, f64) = random();
+        let z = 1.0 - 2.0 * ru;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * rv;
+        Self::new(r * phi.cos(), r * phi.sin(), z)
+    }
     pub fn random_on_hemisphere(normal: Vec3) -> Vec3 {
         let vec = Vec3::random().normalize();
         if vec.dot(normal) > 0.0 {
@@ -32,22 +72,20 @@ impl Vec3 {
         }
     }
 
-    pub fn x(&self) -> f64 {
-        let result: [f32; 4] = unsafe { std::mem::transmute(self.data) };
-        result[3] as f64
-    }
-    pub fn y(&self) -> f64 {
-        let result: [f32; 4] = unsafe { std::mem::transmute(self.data) };
-        result[2] as f64
-    }
-    pub fn z(&self) -> f64 {
-        let result: [f32; 4] = unsafe { std::mem::transmute(self.data) };
-        result[1] as f64
+    pub fn x(&self) -> f64 { self.data.x() }
+    pub fn y(&self) -> f64 { self.data.y() }
+    pub fn z(&self) -> f64 { self.data.z() }
+    pub fn axis(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x(),
+            1 => self.y(),
+            _ => self.z(),
+        }
     }
 
     pub fn is_near_zero(&self) -> bool {
         let epsilon = 1e-8;
-        return self.x().abs() < epsilon && self.y().abs() < epsilon && self.z().abs() < epsilon;
+        self.x().abs() < epsilon && self.y().abs() < epsilon && self.z().abs() < epsilon
     }
 
     pub fn norm_sq(&self) -> f64 {
@@ -57,10 +95,7 @@ impl Vec3 {
         self.norm_sq().sqrt()
     }
     pub fn dot(self, other: Self) -> f64 {
-        let res = unsafe { _mm_dp_ps::<0xFF>(self.data, other.data) };
-        let unpacked: [f32; 4] = unsafe { std::mem::transmute(res) };
-        // println!("unpacked = {:?}", unpacked);
-        unpacked[0] as f64
+        self.data.dot(other.data)
     }
     pub fn cross(self, other: Self) -> Self {
         Self::new(
@@ -73,7 +108,7 @@ impl Vec3 {
         (1.0 / self.norm()) * self
     }
     pub fn reflect(&self, normal: Vec3) -> Vec3 {
-        return *self - 2.0 * self.dot(normal) * normal;
+        *self - 2.0 * self.dot(normal) * normal
     }
     pub fn refract(&self, normal: Vec3, refractive_index: f64) -> Vec3 {
         // self and normal have to be unit vectors
@@ -82,7 +117,56 @@ impl Vec3 {
         let perp = refractive_index * (*self + cos * normal);
         let parallel = -(1.0 - perp.norm_sq()).abs().sqrt() * normal;
         // And add
-        return perp + parallel;
+        perp + parallel
+    }
+    pub fn orthonormal_basis(self) -> (Vec3, Vec3) {
+        // self has to be a unit vector
+        // Duff et al. "Building an Orthonormal Basis, Revisited"
+        let (x, y, z) = (self.x(), self.y(), self.z());
+        let sign = 1f64.copysign(z);
+        let a = -1.0 / (sign + z);
+        let b = x * y * a;
+        (
+            Vec3::new(1.0 + sign * x * x * a, sign * b, -sign * x),
+            Vec3::new(b, sign + y * y * a, -y),
+        )
+    }
+    /// Absolute value of each component.
+    pub fn abs(self) -> Self {
+        Self::new(self.x().abs(), self.y().abs(), self.z().abs())
+    }
+
+    /// The largest of x, y and z.
+    pub fn max_component(self) -> f64 {
+        self.x().max(self.y()).max(self.z())
+    }
+
+    /// How bright a linear RGB color looks.
+    pub fn luminance(self) -> f64 {
+        0.2126 * self.x() + 0.7152 * self.y() + 0.0722 * self.z()
+    }
+
+    pub fn powf(self, exp: f64) -> Self {
+        Self::new(self.x().powf(exp), self.y().powf(exp), self.z().powf(exp))
+    }
+
+    /// Move each component to the next value that can be stored in the direction of
+    /// the matching component of `direction`.
+    pub fn round_away(self, direction: Vec3) -> Self {
+        let round = |value: f64, direction: f64| {
+            if direction > 0.0 {
+                Storage::next_up(value)
+            } else if direction < 0.0 {
+                Storage::next_down(value)
+            } else {
+                value
+            }
+        };
+        Self::new(
+            round(self.x(), direction.x()),
+            round(self.y(), direction.y()),
+            round(self.z(), direction.z()),
+        )
     }
 }
 
@@ -97,8 +181,7 @@ impl Add for Vec3 {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let res = unsafe { _mm_add_ps(self.data, rhs.data) };
-        Vec3 { data: res }
+        Vec3 { data: self.data.add(rhs.data) }
     }
 }
 
@@ -115,8 +198,7 @@ impl Sub for Vec3 {
     type Output = Self;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        let res = unsafe { _mm_sub_ps(self.data, rhs.data) };
-        Vec3 { data: res }
+        Vec3 { data: self.data.sub(rhs.data) }
     }
 }
 
@@ -160,8 +242,7 @@ impl Mul<Vec3> for Vec3 {
     type Output = Vec3;
 
     fn mul(self, rhs: Vec3) -> Self::Output {
-        let res = unsafe { _mm_mul_ps(self.data, rhs.data) };
-        Vec3 { data: res }
+        Vec3 { data: self.data.mul(rhs.data) }
     }
 }
 
@@ -213,8 +294,59 @@ mod tests {
         assert!((Vec3::new(4.0, 8.0, 9.0).normalize().norm() - 1.0).abs() < 0.0001);
     }
 
+    #[test]
+    fn orthonormal_basis() {
+        let n = Vec3::new(1.0, -2.0, 0.5).normalize();
+        let (u, v) = n.orthonormal_basis();
+        assert!(u.dot(n).abs() < 1e-6 && v.dot(n).abs() < 1e-6 && u.dot(v).abs() < 1e-6);
+        assert!((u.norm() - 1.0).abs() < 1e-6 && (v.norm() - 1.0).abs() < 1e-6);
+    }
+
     #[test]
     fn multiply_vector_components() {
         assert_eq!(Vec3::new(1.0, 2.0, 3.0) * Vec3::new(4.0, 5.0, 6.0), Vec3::new(4.0, 10.0, 18.0));
     }
-}
\ No newline at end of file
+
+    fn check_lanes<L: Lanes>() {
+        let a = L::new(1.0, 2.0, 3.0);
+        let b = L::new(4.0, 5.0, 6.0);
+        let components = |lanes: L| (lanes.x(), lanes.y(), lanes.z());
+        assert_eq!(components(a), (1.0, 2.0, 3.0));
+        assert_eq!(components(a.add(b)), (5.0, 7.0, 9.0));
+        assert_eq!(components(a.sub(b)), (-3.0, -3.0, -3.0));
+        assert_eq!(components(a.mul(b)), (4.0, 10.0, 18.0));
+        assert_eq!(a.dot(b), 32.0);
+
+        let up = L::next_up(1.0);
+        let down = L::next_down(1.0);
+        assert!(up > 1.0 && up - 1.0 <= L::EPSILON);
+        assert!(down < 1.0 && 1.0 - down <= L::EPSILON);
+        // The neighbours can be stored exactly
+        assert_eq!(L::new(up, down, 0.0).x(), up);
+        assert_eq!(L::new(up, down, 0.0).y(), down);
+    }
+
+    #[test]
+    fn scalar64_backend() {
+        check_lanes::<scalar64::Scalar64>();
+    }
+
+    #[test]
+    fn scalar32_backend() {
+        check_lanes::<scalar32::Scalar32>();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sse_backend() {
+        check_lanes::<sse::Sse>();
+    }
+
+    #[test]
+    fn round_away() {
+        let vec = Vec3::new(1.0, -1.0, 2.0);
+        let rounded = vec.round_away(Vec3::new(1.0, 1.0, 0.0));
+        assert!(rounded.x() > 1.0 && rounded.y() > -1.0);
+        assert_eq!(rounded.z(), 2.0);
+    }
+}
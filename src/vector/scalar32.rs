@@ -0,0 +1,41 @@
+use super::Lanes;
+
+/// Plain f32 components, half the memory of f64.
+#[derive(Debug, Copy, Clone)]
+pub struct Scalar32([f32; 3]);
+
+impl Lanes for Scalar32 {
+    const EPSILON: f64 = f32::EPSILON as f64;
+
+    fn new(x: f64, y: f64, z: f64) -> Self {
+        Self([x as f32, y as f32, z as f32])
+    }
+
+    fn x(&self) -> f64 { self.0[0] as f64 }
+    fn y(&self) -> f64 { self.0[1] as f64 }
+    fn z(&self) -> f64 { self.0[2] as f64 }
+
+    fn add(self, other: Self) -> Self {
+        Self([self.0[0] + other.0[0], self.0[1] + other.0[1], self.0[2] + other.0[2]])
+    }
+
+    fn sub(self, other: Self) -> Self {
+        Self([self.0[0] - other.0[0], self.0[1] - other.0[1], self.0[2] - other.0[2]])
+    }
+
+    fn mul(self, other: Self) -> Self {
+        Self([self.0[0] * other.0[0], self.0[1] * other.0[1], self.0[2] * other.0[2]])
+    }
+
+    fn dot(self, other: Self) -> f64 {
+        (self.0[0] * other.0[0] + self.0[1] * other.0[1] + self.0[2] * other.0[2]) as f64
+    }
+
+    fn next_up(value: f64) -> f64 {
+        (value as f32).next_up() as f64
+    }
+
+    fn next_down(value: f64) -> f64 {
+        (value as f32).next_down() as f64
+    }
+}
@@ -0,0 +1,41 @@
+use super::Lanes;
+
+/// Plain f64 components, the most precise and the default.
+#[derive(Debug, Copy, Clone)]
+pub struct Scalar64([f64; 3]);
+
+impl Lanes for Scalar64 {
+    const EPSILON: f64 = f64::EPSILON;
+
+    fn new(x: f64, y: f64, z: f64) -> Self {
+        Self([x, y, z])
+    }
+
+    fn x(&self) -> f64 { self.0[0] }
+    fn y(&self) -> f64 { self.0[1] }
+    fn z(&self) -> f64 { self.0[2] }
+
+    fn add(self, other: Self) -> Self {
+        Self([self.0[0] + other.0[0], self.0[1] + other.0[1], self.0[2] + other.0[2]])
+    }
+
+    fn sub(self, other: Self) -> Self {
+        Self([self.0[0] - other.0[0], self.0[1] - other.0[1], self.0[2] - other.0[2]])
+    }
+
+    fn mul(self, other: Self) -> Self {
+        Self([self.0[0] * other.0[0], self.0[1] * other.0[1], self.0[2] * other.0[2]])
+    }
+
+    fn dot(self, other: Self) -> f64 {
+        self.0[0] * other.0[0] + self.0[1] * other.0[1] + self.0[2] * other.0[2]
+    }
+
+    fn next_up(value: f64) -> f64 {
+        value.next_up()
+    }
+
+    fn next_down(value: f64) -> f64 {
+        value.next_down()
+    }
+}
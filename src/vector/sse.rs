@@ -0,0 +1,59 @@
+use std::arch::x86_64::{__m128, _mm_add_ps, _mm_mul_ps, _mm_set_ps, _mm_sub_ps};
+use super::Lanes;
+
+/// f32 components in an SSE register. x is in the highest lane and the lowest is
+/// always zero.
+#[derive(Debug, Copy, Clone)]
+pub struct Sse(__m128);
+
+impl Sse {
+    fn lanes(&self) -> [f32; 4] {
+        unsafe { std::mem::transmute(self.0) }
+    }
+}
+
+impl Lanes for Sse {
+    const EPSILON: f64 = f32::EPSILON as f64;
+
+    fn new(x: f64, y: f64, z: f64) -> Self {
+        unsafe { Self(_mm_set_ps(x as f32, y as f32, z as f32, 0.0)) }
+    }
+
+    fn x(&self) -> f64 { self.lanes()[3] as f64 }
+    fn y(&self) -> f64 { self.lanes()[2] as f64 }
+    fn z(&self) -> f64 { self.lanes()[1] as f64 }
+
+    fn add(self, other: Self) -> Self {
+        unsafe { Self(_mm_add_ps(self.0, other.0)) }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        unsafe { Self(_mm_sub_ps(self.0, other.0)) }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        unsafe { Self(_mm_mul_ps(self.0, other.0)) }
+    }
+
+    // The dot product instruction needs SSE4.1, which not every x86_64 CPU has
+    #[cfg(target_feature = "sse4.1")]
+    fn dot(self, other: Self) -> f64 {
+        use std::arch::x86_64::_mm_dp_ps;
+        let result = Self(unsafe { _mm_dp_ps::<0xFF>(self.0, other.0) });
+        result.lanes()[0] as f64
+    }
+
+    #[cfg(not(target_feature = "sse4.1"))]
+    fn dot(self, other: Self) -> f64 {
+        let product = self.mul(other).lanes();
+        (product[3] + product[2] + product[1]) as f64
+    }
+
+    fn next_up(value: f64) -> f64 {
+        (value as f32).next_up() as f64
+    }
+
+    fn next_down(value: f64) -> f64 {
+        (value as f32).next_down() as f64
+    }
+}
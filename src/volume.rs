@@ -0,0 +1,293 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use crate::aabb::Aabb;
+use crate::vector::Vec3;
+
+/// Values throughout a box shaped region, like the density of smoke or the
+/// temperature of fire. Zero outside of the box.
+#[derive(Debug)]
+pub enum ScalarField {
+    Grid(VoxelGrid),
+    Noise(NoiseField),
+}
+
+impl ScalarField {
+    pub fn value(&self, point: Vec3) -> f64 {
+        match self {
+            ScalarField::Grid(grid) => grid.lookup(point),
+            ScalarField::Noise(noise) => noise.value(point),
+        }
+    }
+
+    pub fn max(&self) -> f64 {
+        match self {
+            ScalarField::Grid(grid) => grid.max,
+            ScalarField::Noise(_) => 1.0,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            ScalarField::Grid(grid) => grid.bounds,
+            ScalarField::Noise(noise) => noise.bounds,
+        }
+    }
+}
+
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    // x varies fastest, then y, then z
+    values: Vec<f32>,
+    bounds: Aabb,
+    max: f64,
+}
+
+impl Debug for VoxelGrid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VoxelGrid")
+            .field("resolution", &self.resolution)
+            .field("bounds", &self.bounds)
+            .field("max", &self.max)
+            .finish()
+    }
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: [usize; 3], values: Vec<f32>, bounds: Aabb) -> Self {
+        assert_eq!(values.len(), resolution[0] * resolution[1] * resolution[2], "Wrong number of voxels");
+        let max = values.iter().copied().fold(0.0f32, f32::max) as f64;
+        Self { resolution, values, bounds, max }
+    }
+
+    /// Load a headerless file of little endian 32 bit floats.
+    pub fn load_raw(file_path: &str, resolution: [usize; 3], bounds: Aabb) -> Result<Self> {
+        let bytes = fs::read(file_path)?;
+        let count = resolution.iter().try_fold(1usize, |count, &size| count.checked_mul(size));
+        let count = match count {
+            Some(count) if count.checked_mul(4) == Some(bytes.len()) => count,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "File size does not match resolution")),
+        };
+        Ok(Self::new(resolution, read_f32s(&bytes, count), bounds))
+    }
+
+    /// Load a Mitsuba grid volume (.vol) file. Only the first channel is used.
+    pub fn load_vol(file_path: &str) -> Result<Self> {
+        Self::parse_vol(&fs::read(file_path)?)
+    }
+
+    fn parse_vol(bytes: &[u8]) -> Result<Self> {
+        let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+        if bytes.len() < 48 || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+            return Err(invalid("Not a version 3 .vol file"));
+        }
+        let int = |offset: usize| i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        // Sizes have to be positive, or they'd wrap around to huge ones
+        let size = |offset: usize| usize::try_from(int(offset)).ok().filter(|&size| size > 0);
+        let encoding = int(4);
+        let (resolution, channels) = match (size(8), size(12), size(16), size(20)) {
+            (Some(x), Some(y), Some(z), Some(channels)) => ([x, y, z], channels),
+            _ => return Err(invalid("Resolution and channels have to be above zero")),
+        };
+        let bbox = read_f32s(&bytes[24..48], 6);
+        let bounds = Aabb::new(
+            Vec3::new(bbox[0] as f64, bbox[1] as f64, bbox[2] as f64),
+            Vec3::new(bbox[3] as f64, bbox[4] as f64, bbox[5] as f64),
+        );
+
+        let count = resolution.iter().try_fold(1usize, |count, &size| count.checked_mul(size));
+        let values_count = count.and_then(|count| count.checked_mul(channels)).ok_or_else(|| invalid("Too many voxels"))?;
+        let data = &bytes[48..];
+        let values: Vec<f32> = match encoding {
+            // float32
+            1 => {
+                if values_count.checked_mul(4).is_none_or(|size| data.len() < size) {
+                    return Err(invalid("Not enough voxels"));
+                }
+                read_f32s(data, values_count).into_iter().step_by(channels).collect()
+            }
+            // uint8
+            3 => {
+                if data.len() < values_count {
+                    return Err(invalid("Not enough voxels"));
+                }
+                data.iter().take(values_count).step_by(channels).map(|&value| value as f32 / 255.0).collect()
+            }
+            _ => return Err(invalid("Unsupported .vol encoding")),
+        };
+        Ok(Self::new(resolution, values, bounds))
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.values[(z * self.resolution[1] + y) * self.resolution[0] + x] as f64
+    }
+
+    /// Trilinearly interpolated value at a point in the world.
+    pub fn lookup(&self, point: Vec3) -> f64 {
+        // A grid with no voxels along some axis
+        if self.values.is_empty() {
+            return 0.0;
+        }
+        let relative = self.bounds.relative(point);
+        let mut low = [0; 3];
+        let mut high = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let coordinate = relative.axis(axis);
+            if !(0.0..=1.0).contains(&coordinate) {
+                return 0.0;
+            }
+            // Values are at the centers of the voxels
+            let max = (self.resolution[axis] - 1) as f64;
+            let voxel = (coordinate * self.resolution[axis] as f64 - 0.5).clamp(0.0, max);
+            low[axis] = voxel.floor() as usize;
+            high[axis] = (low[axis] + 1).min(self.resolution[axis] - 1);
+            fraction[axis] = voxel - low[axis] as f64;
+        }
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let [fx, fy, fz] = fraction;
+        let x00 = lerp(self.voxel(low[0], low[1], low[2]), self.voxel(high[0], low[1], low[2]), fx);
+        let x10 = lerp(self.voxel(low[0], high[1], low[2]), self.voxel(high[0], high[1], low[2]), fx);
+        let x01 = lerp(self.voxel(low[0], low[1], high[2]), self.voxel(high[0], low[1], high[2]), fx);
+        let x11 = lerp(self.voxel(low[0], high[1], high[2]), self.voxel(high[0], high[1], high[2]), fx);
+        lerp(lerp(x00, x10, fy), lerp(x01, x11, fy), fz)
+    }
+}
+
+fn read_f32s(bytes: &[u8], count: usize) -> Vec<f32> {
+    bytes.chunks_exact(4).take(count).map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap())).collect()
+}
+
+/// Fractal value noise shaped into a puffy ball, for clouds and explosions.
+#[derive(Debug)]
+pub struct NoiseField {
+    bounds: Aabb,
+    frequency: f64,
+    octaves: u32,
+    seed: u32,
+}
+
+impl NoiseField {
+    pub fn new(bounds: Aabb, frequency: f64, octaves: u32, seed: u32) -> Self {
+        Self { bounds, frequency, octaves, seed }
+    }
+
+    fn value(&self, point: Vec3) -> f64 {
+        let relative = self.bounds.relative(point);
+        let centered = 2.0 * relative - Vec3::new(1.0, 1.0, 1.0);
+        if centered.x().abs() > 1.0 || centered.y().abs() > 1.0 || centered.z().abs() > 1.0 {
+            return 0.0;
+        }
+
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total_amplitude = 0.0;
+        let mut frequency = self.frequency;
+        for octave in 0..self.octaves {
+            sum += amplitude * value_noise(frequency * point, self.seed.wrapping_add(octave));
+            total_amplitude += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        let fbm = if total_amplitude > 0.0 { sum / total_amplitude } else { 0.0 };
+
+        // Fade out towards the edges of the box
+        (1.0 - centered.norm_sq() + fbm).clamp(0.0, 1.0)
+    }
+}
+
+/// Smoothly interpolated random values between -1 and 1 at integer coordinates.
+fn value_noise(point: Vec3, seed: u32) -> f64 {
+    let cell = [point.x().floor(), point.y().floor(), point.z().floor()];
+    let smooth = |t: f64| t * t * (3.0 - 2.0 * t);
+    let tx = smooth(point.x() - cell[0]);
+    let ty = smooth(point.y() - cell[1]);
+    let tz = smooth(point.z() - cell[2]);
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        lattice_value(cell[0] as i64 + dx, cell[1] as i64 + dy, cell[2] as i64 + dz, seed)
+    };
+
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), tx);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), tx);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), tx);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), tx);
+    lerp(lerp(x00, x10, ty), lerp(x01, x11, ty), tz)
+}
+
+fn lattice_value(x: i64, y: i64, z: i64, seed: u32) -> f64 {
+    let mut hash = (x as u64).wrapping_mul(0x9E3779B185EBCA87)
+        ^ (y as u64).wrapping_mul(0xC2B2AE3D27D4EB4F)
+        ^ (z as u64).wrapping_mul(0x165667B19E3779F9)
+        ^ (seed as u64).wrapping_mul(0x27D4EB2F165667C5);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xFF51AFD7ED558CCD);
+    hash ^= hash >> 33;
+    (hash >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vol_file(encoding: i32, resolution: [i32; 3], data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(3);
+        for int in [encoding, resolution[0], resolution[1], resolution[2], 1] {
+            bytes.extend_from_slice(&int.to_le_bytes());
+        }
+        for float in [0.0f32, 0.0, 0.0, 2.0, 2.0, 2.0] {
+            bytes.extend_from_slice(&float.to_le_bytes());
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn parse_vol_float() {
+        let data: Vec<u8> = (0..8).flat_map(|i| (i as f32).to_le_bytes()).collect();
+        let grid = VoxelGrid::parse_vol(&vol_file(1, [2, 2, 2], &data)).unwrap();
+        assert_eq!(grid.max, 7.0);
+        assert_eq!(grid.bounds.max(), Vec3::new(2.0, 2.0, 2.0));
+        // Voxel centers are at 0.5 and 1.5
+        assert_eq!(grid.lookup(Vec3::new(0.5, 0.5, 0.5)), 0.0);
+        assert_eq!(grid.lookup(Vec3::new(1.5, 0.5, 0.5)), 1.0);
+        assert_eq!(grid.lookup(Vec3::new(0.5, 1.5, 0.5)), 2.0);
+        assert_eq!(grid.lookup(Vec3::new(0.5, 0.5, 1.5)), 4.0);
+        assert_eq!(grid.lookup(Vec3::new(1.0, 1.0, 1.0)), 3.5);
+        assert_eq!(grid.lookup(Vec3::new(3.0, 1.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn parse_vol_rejects_truncated() {
+        assert!(VoxelGrid::parse_vol(&vol_file(1, [2, 2, 2], &[0; 16])).is_err());
+        assert!(VoxelGrid::parse_vol(b"VOL").is_err());
+    }
+
+    #[test]
+    fn parse_vol_rejects_bad_sizes() {
+        let data = [0; 32];
+        assert!(VoxelGrid::parse_vol(&vol_file(1, [2, 0, 2], &data)).is_err());
+        assert!(VoxelGrid::parse_vol(&vol_file(1, [2, -2, 2], &data)).is_err());
+        // Big enough to overflow when multiplied together
+        assert!(VoxelGrid::parse_vol(&vol_file(1, [i32::MAX, i32::MAX, i32::MAX], &data)).is_err());
+        let mut no_channels = vol_file(1, [2, 2, 2], &data);
+        no_channels[20..24].copy_from_slice(&0i32.to_le_bytes());
+        assert!(VoxelGrid::parse_vol(&no_channels).is_err());
+
+        let empty = VoxelGrid::new([0, 2, 2], Vec::new(), Aabb::new(Vec3::zero(), Vec3::new(2.0, 2.0, 2.0)));
+        assert_eq!(empty.lookup(Vec3::new(1.0, 1.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let bounds = Aabb::new(Vec3::zero(), Vec3::new(1.0, 1.0, 1.0));
+        let noise = NoiseField::new(bounds, 4.0, 4, 1);
+        for i in 0..1000 {
+            let point = Vec3::new((i % 10) as f64 / 10.0, (i / 10 % 10) as f64 / 10.0, (i / 100) as f64 / 10.0);
+            let value = noise.value(point);
+            assert!((0.0..=1.0).contains(&value));
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Renders small standard scenes and compares them against reference images in
+// `tests/reference`, to catch changes that make images look different.
+//
+// After a change that is meant to change how things look, render new references
+// with `UPDATE_REFERENCES=1 cargo test --release reference`.
+
+use std::path::PathBuf;
+use image::{Rgb, RgbImage};
+use alvinw_raytracer::scenes::{self, Setup, FURNACE_LIGHT};
+use alvinw_raytracer::Renderer;
+
+const SEED: u64 = 1;
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 32;
+// Tests render with few samples so they are quick in debug builds, references with
+// lots so the noise in them doesn't count against the test
+const TEST_SAMPLES: u32 = 128;
+const REFERENCE_SAMPLES: u32 = 4096;
+
+/// How different two images are, after blurring away some of the noise.
+struct Difference {
+    /// Root mean square of the difference in linear color.
+    rmse: f64,
+    /// Average perceptual difference in L*a*b*, 0 to about 100.
+    perceptual: f64,
+    /// Perceptual difference of each pixel.
+    pixels: Vec<f64>,
+    /// Relative difference in average luminance. Noise mostly cancels out here, so
+    /// this catches images that are a little too bright or dark all over.
+    brightness: f64,
+}
+
+fn render(setup: Setup, samples_per_pixel: u32) -> RgbImage {
+    let (scene, camera) = setup(WIDTH, HEIGHT);
+    Renderer::new().samples_per_pixel(samples_per_pixel).seed(SEED).render(&scene, &camera)
+}
+
+/// Undo the gamma correction of `renderer::to_rgb`.
+fn linear(pixel: &Rgb<u8>) -> [f64; 3] {
+    pixel.0.map(|value| (value as f64 / 255.0).powi(2))
+}
+
+/// Gaussian blur with a standard deviation of one pixel, roughly what the eye does
+/// to fine detail like noise.
+fn blur(pixels: &[[f64; 3]], width: usize, height: usize) -> Vec<[f64; 3]> {
+    let kernel: Vec<f64> = (-2..=2).map(|i: i32| (-0.5 * (i * i) as f64).exp()).collect();
+    let total: f64 = kernel.iter().sum();
+    let pass = |pixels: &[[f64; 3]], at: &dyn Fn(usize, i32) -> usize| -> Vec<[f64; 3]> {
+        (0..pixels.len())
+            .map(|index| {
+                let mut sum = [0.0; 3];
+                for (weight, offset) in kernel.iter().zip(-2..=2) {
+                    let pixel = pixels[at(index, offset)];
+                    for channel in 0..3 {
+                        sum[channel] += weight * pixel[channel] / total;
+                    }
+                }
+                sum
+            })
+            .collect()
+    };
+    // Clamped at the edges
+    let clamp = |value: i32, size: usize| value.clamp(0, size as i32 - 1) as usize;
+    let horizontal = pass(pixels, &|index, offset| {
+        let (x, y) = (index % width, index / width);
+        y * width + clamp(x as i32 + offset, width)
+    });
+    pass(&horizontal, &|index, offset| {
+        let (x, y) = (index % width, index / width);
+        clamp(y as i32 + offset, height) * width + x
+    })
+}
+
+fn luminance([r, g, b]: [f64; 3]) -> f64 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Linear sRGB to CIE L*a*b* with a D65 white point.
+fn lab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.9505;
+    let y = luminance([r, g, b]);
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.0890;
+    let f = |t: f64| if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Compares blurred images in a way inspired by FLIP: the HyAB distance in L*a*b*,
+/// which is better than plain distance at the big differences renders fail with.
+fn difference(actual: &RgbImage, expected: &RgbImage) -> Difference {
+    assert_eq!(actual.dimensions(), expected.dimensions());
+    let (width, height) = (actual.width() as usize, actual.height() as usize);
+    let blurred = |image: &RgbImage| blur(&image.pixels().map(linear).collect::<Vec<_>>(), width, height);
+    let (actual, expected) = (blurred(actual), blurred(expected));
+
+    let squares: f64 = actual.iter().zip(&expected)
+        .flat_map(|(a, b)| (0..3).map(move |channel| (a[channel] - b[channel]).powi(2)))
+        .sum();
+    let pixels: Vec<f64> = actual.iter().zip(&expected)
+        .map(|(&a, &b)| {
+            let (a, b) = (lab(a), lab(b));
+            (a[0] - b[0]).abs() + ((a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+        })
+        .collect();
+    let (actual_luminance, expected_luminance): (f64, f64) = (actual.iter().copied().map(luminance).sum(), expected.iter().copied().map(luminance).sum());
+    Difference {
+        rmse: (squares / (actual.len() * 3) as f64).sqrt(),
+        perceptual: pixels.iter().sum::<f64>() / pixels.len() as f64,
+        pixels,
+        brightness: (actual_luminance - expected_luminance).abs() / expected_luminance.max(f64::MIN_POSITIVE),
+    }
+}
+
+/// Black where the images match, through red to yellow where they are far apart.
+fn heatmap(difference: &Difference) -> RgbImage {
+    RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let value = (difference.pixels[(y * WIDTH + x) as usize] / 20.0).min(1.0);
+        let red = (2.0 * value).min(1.0);
+        let green = (2.0 * value - 1.0).max(0.0);
+        Rgb([(red * 255.0) as u8, (green * 255.0) as u8, 0])
+    })
+}
+
+/// How far an image can be from its reference. Set a bit above the noise of a
+/// render with `TEST_SAMPLES`.
+struct Tolerance {
+    rmse: f64,
+    perceptual: f64,
+    brightness: f64,
+}
+
+fn compare(name: &str, actual: &RgbImage, expected: &RgbImage, tolerance: &Tolerance) {
+    let difference = difference(actual, expected);
+    if difference.rmse <= tolerance.rmse && difference.perceptual <= tolerance.perceptual && difference.brightness <= tolerance.brightness {
+        return;
+    }
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/reference-diffs");
+    std::fs::create_dir_all(&dir).unwrap();
+    actual.save(dir.join(format!("{}-actual.png", name))).unwrap();
+    expected.save(dir.join(format!("{}-expected.png", name))).unwrap();
+    heatmap(&difference).save(dir.join(format!("{}-diff.png", name))).unwrap();
+    panic!(
+        "{} differs from its reference: rmse {:.4} (max {}), perceptual {:.3} (max {}), brightness {:.3} (max {}). Images are in {}",
+        name, difference.rmse, tolerance.rmse, difference.perceptual, tolerance.perceptual, difference.brightness, tolerance.brightness, dir.display(),
+    );
+}
+
+/// Render the scene and compare it against its reference, or replace the reference
+/// when `UPDATE_REFERENCES` is set.
+fn check(name: &str, setup: Setup, tolerance: &Tolerance) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/reference").join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_REFERENCES").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        render(setup, REFERENCE_SAMPLES).save(&path).unwrap();
+        return;
+    }
+    let expected = image::open(&path)
+        .unwrap_or_else(|error| panic!("can't open {}: {}", path.display(), error))
+        .to_rgb8();
+    compare(name, &render(setup, TEST_SAMPLES), &expected, tolerance);
+}
+
+#[test]
+fn reference_cornell_box() {
+    // Noisy, the light is small and only found by chance
+    check("cornell", scenes::cornell_box, &Tolerance { rmse: 0.025, perceptual: 6.5, brightness: 0.06 });
+}
+
+#[test]
+fn reference_glass_sphere() {
+    check("glass", scenes::glass_sphere, &Tolerance { rmse: 0.02, perceptual: 4.0, brightness: 0.04 });
+}
+
+#[test]
+fn reference_furnace() {
+    let tolerance = Tolerance { rmse: 0.005, perceptual: 0.5, brightness: 0.01 };
+    check("furnace", scenes::furnace, &tolerance);
+    // Should match the light exactly, whatever the reference says
+    let value = (FURNACE_LIGHT.sqrt() * 255.0) as u8;
+    let uniform = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([value; 3]));
+    compare("furnace-uniform", &render(scenes::furnace, TEST_SAMPLES), &uniform, &tolerance);
+}
+
+#[test]
+fn identical_images_match() {
+    let image = render(scenes::cornell_box, 4);
+    let same = difference(&image, &image);
+    assert_eq!((same.rmse, same.perceptual), (0.0, 0.0));
+    // Red compared with green is about as different as it gets
+    let red = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([255, 0, 0]));
+    let green = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([0, 255, 0]));
+    assert!(difference(&red, &green).perceptual > 100.0);
+}